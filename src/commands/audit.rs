@@ -0,0 +1,116 @@
+use crate::db::operations::get_installed_packages;
+use crate::repository::advisories::{AdvisoryFeed, load_advisories};
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+// Match the installed packages against the cached advisories of the
+// configured repositories. Fails when any installed package is affected, so
+// the command can be used in scripts.
+pub fn audit(conn: &Connection) -> Result<()> {
+    let feed = load_advisories().context("Failed to load cached advisories")?;
+    if feed.advisories.is_empty() {
+        println!("No advisories cached. Run 'lpkg repo refresh' first.");
+        return Ok(());
+    }
+    report(conn, &feed)
+}
+
+// Print the advisories affecting each installed package. Packages that
+// cannot be checked, because an advisory or the installed version does not
+// parse, are reported and fail the audit as well.
+fn report(conn: &Connection, feed: &AdvisoryFeed) -> Result<()> {
+    let mut affected = 0;
+    let mut unchecked = 0;
+    for (_, name, version) in get_installed_packages(conn)? {
+        let mut advisories = match feed.affecting(&name, &version) {
+            Ok(advisories) => advisories,
+            Err(e) => {
+                eprintln!("Warning: cannot check {} {}: {:#}", name, version, e);
+                unchecked += 1;
+                continue;
+            }
+        };
+        advisories.sort_by_key(|a| std::cmp::Reverse(a.severity));
+        if advisories.is_empty() {
+            continue;
+        }
+        affected += 1;
+        println!("{} {}:", name, version);
+        for advisory in advisories {
+            println!(
+                "  {} ({}): {}{}",
+                advisory.id,
+                advisory.severity,
+                match &advisory.fixed_version {
+                    Some(fixed) => format!("fixed in {}", fixed),
+                    None => "no fixed version yet".to_string(),
+                },
+                advisory
+                    .summary
+                    .as_ref()
+                    .map(|s| format!(" - {}", s))
+                    .unwrap_or_default()
+            );
+            if let Some(url) = &advisory.url {
+                println!("    {}", url);
+            }
+        }
+    }
+
+    if affected > 0 {
+        return Err(anyhow::anyhow!(
+            "{} installed package(s) are affected by security advisories. Run 'lpkg upgrade --security' to apply the fixes.",
+            affected
+        ));
+    }
+    if unchecked > 0 {
+        return Err(anyhow::anyhow!(
+            "{} installed package(s) could not be checked against the security advisories.",
+            unchecked
+        ));
+    }
+    println!("No installed package is affected by a known advisory.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::connection::open_in_memory;
+
+    fn feed(json: &str) -> AdvisoryFeed {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_report_fails_on_affected_and_unchecked_packages() {
+        let conn = open_in_memory();
+        conn.execute(
+            "INSERT INTO packages (name, version) VALUES ('openthing', '1.0.0')",
+            [],
+        )
+        .unwrap();
+
+        let fixed = r#"{"advisories": {"openthing": [
+            {"id": "LPKG-10", "affected": "<1.0.0", "severity": "high"}
+        ]}}"#;
+        assert!(report(&conn, &feed(fixed)).is_ok());
+
+        let affected = r#"{"advisories": {"openthing": [
+            {"id": "LPKG-10", "affected": "<1.0.1", "severity": "high"}
+        ]}}"#;
+        let error = report(&conn, &feed(affected)).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("affected by security advisories")
+        );
+
+        // An advisory that cannot be read must not pass the package
+        let malformed = r#"{"advisories": {"openthing": [
+            {"id": "LPKG-11", "affected": "all of them", "severity": "high"}
+        ]}}"#;
+        let error = report(&conn, &feed(malformed)).unwrap_err();
+        assert!(error.to_string().contains("could not be checked"));
+    }
+}
@@ -0,0 +1,383 @@
+use crate::commands::sign::load_key_pair;
+use crate::config::{Config, KEYS_DIR, RepositoryConfig};
+use crate::package::archive::extract_archive;
+use crate::repository::http::HttpClient;
+use crate::repository::mirrors::Mirrors;
+use crate::repository::resolve::{self, Features, Pins, Source};
+use crate::repository::{
+    INDEX_FORMAT, PackageEntry, PackageIndex, RepositoryIndex, cache, search_package,
+};
+use crate::utils::checksum::{calculate_sha256, verify_checksum};
+use crate::utils::format::format_size;
+use anyhow::{Context, Result};
+use ring::signature::{ED25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+// A bundle is a tar.zst archive laid out like a small repository:
+//   index.json       index of the bundled packages, with relative URLs
+//   index.json.sig   Ed25519 signature of index.json
+//   keys/<id>.pub    public key the index was signed with
+//   packages/*.lpkg  the packages themselves
+pub fn create(packages: &[String], output: &str, sign_key: &str) -> Result<()> {
+    let key_pair = load_key_pair(sign_key)?;
+    let sources = resolve::load_sources()?;
+    if sources.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No repositories with a cached index. Add one with 'lpkg repo add'."
+        ));
+    }
+    create_from(&sources, packages, output, &key_pair)
+}
+
+// Bundle the packages and their dependencies from the given repositories
+fn create_from(
+    sources: &[Source],
+    packages: &[String],
+    output: &str,
+    key_pair: &Ed25519KeyPair,
+) -> Result<()> {
+    // Everything goes into the bundle: the target machine may have nothing installed
+    let resolved = resolve::resolve(
+        sources,
+        packages,
+        &Pins::new(),
+        &Features::new(),
+        &|_, _| Ok(false),
+    )?;
+    println!("Bundling {} package(s):", resolved.len());
+    for entry in &resolved {
+        println!(
+            "  {} {} (from {})",
+            entry.package.name, entry.package.version, entry.source.repo.name
+        );
+    }
+
+    let staging = tempfile::tempdir().context("Failed to create temporary directory")?;
+    let packages_dir = staging.path().join("packages");
+    fs::create_dir_all(&packages_dir)?;
+
+    let config = Config::load()?;
+    let mut clients: HashMap<&str, (HttpClient, Mirrors)> = HashMap::new();
+    let mut bundled = HashMap::new();
+    for entry in &resolved {
+        let repo = &entry.source.repo;
+        if !clients.contains_key(repo.name.as_str()) {
+            let client = HttpClient::for_repository(&config, repo)?;
+            let mirrors = Mirrors::for_repository(repo, Some(&entry.source.index), &client);
+            clients.insert(&repo.name, (client, mirrors));
+        }
+        let (client, mirrors) = &clients[repo.name.as_str()];
+        let cached = cache::fetch_package(entry.package, mirrors, client)?;
+
+        let file_name = format!("{}-{}.lpkg", entry.package.name, entry.package.version);
+        let dest = packages_dir.join(&file_name);
+        fs::copy(&cached, &dest).context(format!(
+            "Failed to copy {} into the bundle",
+            cached.display()
+        ))?;
+        let sha256 = calculate_sha256(dest.to_str().unwrap_or_default())?;
+        let size = fs::metadata(&dest)?.len();
+
+        bundled.insert(
+            entry.package.name.clone(),
+            PackageEntry::Single(Box::new(PackageIndex {
+                url: format!("packages/{}", file_name),
+                signature_url: None,
+                sha256: Some(sha256),
+                size: Some(size),
+                ..entry.package.clone()
+            })),
+        );
+    }
+
+    let index = RepositoryIndex {
+        format: Some(INDEX_FORMAT),
+        packages: bundled,
+        mirrors: None,
+    };
+    let index_json =
+        serde_json::to_vec_pretty(&index).context("Failed to serialize bundle index")?;
+    fs::write(staging.path().join("index.json"), &index_json)?;
+    fs::write(
+        staging.path().join("index.json.sig"),
+        key_pair.sign(&index_json).as_ref(),
+    )?;
+    let public_key = key_pair.public_key().as_ref();
+    let keys_dir = staging.path().join("keys");
+    fs::create_dir_all(&keys_dir)?;
+    fs::write(
+        keys_dir.join(format!("{}.pub", key_id(public_key))),
+        public_key,
+    )?;
+
+    write_bundle(staging.path(), output)?;
+    println!(
+        "Bundle written to {} ({}), signed with key {}",
+        output,
+        format_size(fs::metadata(output)?.len()),
+        key_id(public_key)
+    );
+    Ok(())
+}
+
+fn write_bundle(staging: &Path, output: &str) -> Result<()> {
+    let file = File::create(output).context(format!("Failed to create bundle {}", output))?;
+    let mut encoder = ZstdEncoder::new(file, 19).context("Failed to create zstd encoder")?;
+    {
+        let mut builder = tar::Builder::new(&mut encoder);
+        builder
+            .append_dir_all(".", staging)
+            .context("Failed to add files to bundle")?;
+        builder
+            .finish()
+            .context("Failed to finish bundle archive")?;
+    }
+    encoder
+        .finish()
+        .context("Failed to finish zstd compression")?;
+    Ok(())
+}
+
+// Install every package in a bundle, in dependency order, without any network
+// access. The index signature must verify against a trusted key and every
+// package must match the size and checksum recorded in the index.
+pub fn install(conn: &mut Connection, bundle: &str, trusted_key: Option<&str>) -> Result<()> {
+    println!("Installing from bundle: {}", bundle);
+    let temp_dir = tempfile::tempdir().context("Failed to create temporary directory")?;
+    let root = temp_dir.path();
+    extract_archive(bundle, root.to_str().unwrap_or_default())
+        .context(format!("Failed to extract bundle {}", bundle))?;
+
+    let index_json = fs::read(root.join("index.json")).context("Bundle has no index.json")?;
+    let signature = fs::read(root.join("index.json.sig")).context("Bundle index is not signed")?;
+    let key = verify_index(&index_json, &signature, root, trusted_key)?;
+    println!("Bundle index signature verified with key {}", key);
+
+    let index: RepositoryIndex =
+        serde_json::from_slice(&index_json).context("Failed to parse bundle index")?;
+    index.check().context("Invalid bundle index")?;
+    for entry in index.packages.values() {
+        for package in entry.versions() {
+            verify_package(root, package)?;
+        }
+    }
+
+    let mut names: Vec<String> = index.packages.keys().cloned().collect();
+    names.sort();
+    let sources = vec![Source {
+        repo: RepositoryConfig {
+            name: "bundle".to_string(),
+            url: format!("file://{}/index.json", root.display()),
+            mirrors: Vec::new(),
+            mirror_selection: Default::default(),
+            priority: 0,
+            proxy: None,
+            ca_bundles: Vec::new(),
+            auth: None,
+        },
+        index,
+    }];
+
+    let resolved = {
+        let conn = &*conn;
+        let index = &sources[0].index;
+        let pins = crate::commands::hold::load_pins(conn)?;
+        resolve::resolve(
+            &sources,
+            &names,
+            &pins,
+            &Features::new(),
+            &|name, constraint| match constraint {
+                Some(constraint) => {
+                    crate::db::operations::is_package_installed(conn, name, Some(constraint))
+                }
+                None => match search_package(index, name) {
+                    Some(package) => crate::db::operations::is_package_installed(
+                        conn,
+                        name,
+                        Some(&format!("={}", package.version)),
+                    ),
+                    None => Ok(false),
+                },
+            },
+        )?
+    };
+
+    if resolved.is_empty() {
+        println!("All packages in the bundle are already installed.");
+        return Ok(());
+    }
+    for entry in &resolved {
+        let package = entry.package;
+        // Keep the package in the cache so the database record stays valid
+        // after the temporary directory is removed
+        let sha256 = package.sha256.as_deref().unwrap_or_default();
+        let cached =
+            crate::utils::root::path(cache::cache_path(&package.name, &package.version, sha256)?);
+        if let Some(parent) = cached.parent() {
+            fs::create_dir_all(parent).context(format!(
+                "Failed to create cache directory {}",
+                parent.display()
+            ))?;
+        }
+        fs::copy(root.join(&package.url), &cached)
+            .context(format!("Failed to cache {}", cached.display()))?;
+        crate::commands::install::install(conn, cached.to_str().unwrap_or_default(), &[]).context(
+            format!("Failed to install {} {}", package.name, package.version),
+        )?;
+    }
+    println!("Installed {} package(s) from bundle.", resolved.len());
+    Ok(())
+}
+
+// Check the index signature against the trusted keys and return the id of the
+// key that verified it.
+fn verify_index(
+    index_json: &[u8],
+    signature: &[u8],
+    root: &Path,
+    trusted_key: Option<&str>,
+) -> Result<String> {
+    let mut trusted = Vec::new();
+    if let Some(path) = trusted_key {
+        trusted.push(PathBuf::from(path));
+    }
+    if let Ok(entries) = fs::read_dir(KEYS_DIR) {
+        trusted.extend(
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("pub")),
+        );
+    }
+
+    for path in &trusted {
+        let key =
+            fs::read(path).context(format!("Failed to read public key {}", path.display()))?;
+        if key.len() != 32 {
+            eprintln!(
+                "Warning: ignoring {}: Ed25519 public keys must be 32 bytes.",
+                path.display()
+            );
+            continue;
+        }
+        if UnparsedPublicKey::new(&ED25519, &key)
+            .verify(index_json, signature)
+            .is_ok()
+        {
+            return Ok(key_id(&key));
+        }
+    }
+
+    let bundled: Vec<String> = fs::read_dir(root.join("keys"))
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| fs::read(e.path()).ok())
+                .map(|key| key_id(&key))
+                .collect()
+        })
+        .unwrap_or_default();
+    Err(anyhow::anyhow!(
+        "Bundle index is not signed by a trusted key (bundle key: {}). Copy the public key to {} or pass it with --key.",
+        if bundled.is_empty() {
+            "none".to_string()
+        } else {
+            bundled.join(", ")
+        },
+        KEYS_DIR
+    ))
+}
+
+fn verify_package(root: &Path, package: &PackageIndex) -> Result<()> {
+    let path = root.join(&package.url);
+    let expected = package.sha256.as_deref().context(format!(
+        "Bundle index has no checksum for {} {}",
+        package.name, package.version
+    ))?;
+    let inside = Path::new(&package.url)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if !inside || !path.is_file() {
+        return Err(anyhow::anyhow!(
+            "Bundle is missing {} for {} {}",
+            package.url,
+            package.name,
+            package.version
+        ));
+    }
+    let size = fs::metadata(&path)?.len();
+    if package.size.is_some_and(|expected| expected != size)
+        || !verify_checksum(path.to_str().unwrap_or_default(), expected)?
+    {
+        return Err(anyhow::anyhow!(
+            "Checksum mismatch for {} {} in bundle",
+            package.name,
+            package.version
+        ));
+    }
+    Ok(())
+}
+
+// Short identifier of a public key: the first 16 hex digits of its SHA-256
+fn key_id(public_key: &[u8]) -> String {
+    hex::encode(Sha256::digest(public_key))[..16].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::testing::{pack, source};
+    use crate::db::connection::open_in_memory;
+    use crate::db::operations::get_package_data;
+    use crate::utils::root;
+
+    #[test]
+    fn test_bundle_round_trip() {
+        root::set_for_tests();
+        let repo = tempfile::tempdir().unwrap();
+        pack(repo.path(), "bundled-lib", "1.0.0", "", &[("data", "lib")]);
+        let depends = "\n[dependencies]\nbundled-lib = \">=1\"\n";
+        pack(
+            repo.path(),
+            "bundled-app",
+            "1.0.0",
+            depends,
+            &[("data", "app")],
+        );
+        let sources = vec![source(repo.path())];
+
+        let out = tempfile::tempdir().unwrap();
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let bundle = out.path().join("app.bundle");
+        let bundle = bundle.to_str().unwrap();
+        create_from(&sources, &["bundled-app".to_string()], bundle, &key_pair).unwrap();
+        // The target machine has no access to the repository
+        drop(repo);
+
+        let mut conn = open_in_memory();
+        let other_key = out.path().join("other.pub");
+        let other = Ed25519KeyPair::from_seed_unchecked(&[8; 32]).unwrap();
+        fs::write(&other_key, other.public_key().as_ref()).unwrap();
+        let error = install(&mut conn, bundle, other_key.to_str()).unwrap_err();
+        assert!(
+            error.to_string().contains("not signed by a trusted key"),
+            "{:#}",
+            error
+        );
+        assert!(get_package_data(&conn, "bundled-lib").unwrap().is_none());
+
+        let key = out.path().join("bundle.pub");
+        fs::write(&key, key_pair.public_key().as_ref()).unwrap();
+        install(&mut conn, bundle, key.to_str()).unwrap();
+        for name in ["bundled-lib", "bundled-app"] {
+            let (_, version, _) = get_package_data(&conn, name).unwrap().unwrap();
+            assert_eq!(version, "1.0.0");
+        }
+    }
+}
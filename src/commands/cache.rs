@@ -0,0 +1,69 @@
+use crate::db::operations::get_retained_package_files;
+use crate::repository::cache::{self, CACHE_DIR};
+use crate::utils::format::format_size;
+use crate::utils::root;
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+pub fn list() -> Result<()> {
+    let packages = cache::list_cached().context("Failed to read package cache")?;
+
+    if packages.is_empty() {
+        println!(
+            "Package cache at {} is empty.",
+            root::path(CACHE_DIR).display()
+        );
+        return Ok(());
+    }
+
+    let mut total = 0;
+    for package in &packages {
+        println!(
+            "{} {} {} ({})",
+            package.name,
+            package.version,
+            &package.sha256[..package.sha256.len().min(12)],
+            format_size(package.size)
+        );
+        total += package.size;
+    }
+    println!(
+        "{} cached package(s), {} total in {}",
+        packages.len(),
+        format_size(total),
+        root::path(CACHE_DIR).display()
+    );
+    Ok(())
+}
+
+pub fn clean(conn: &Connection) -> Result<()> {
+    let (count, freed) = cache::clean(&retained(conn)?).context("Failed to clean package cache")?;
+    println!(
+        "Removed {} cached package(s), freed {}.",
+        count,
+        format_size(freed)
+    );
+    Ok(())
+}
+
+pub fn prune(conn: &Connection, keep: usize) -> Result<()> {
+    let (count, freed) =
+        cache::prune(keep, &retained(conn)?).context("Failed to prune package cache")?;
+    println!(
+        "Pruned {} cached package(s) keeping the newest {} version(s) of each, freed {}.",
+        count,
+        keep,
+        format_size(freed)
+    );
+    Ok(())
+}
+
+// Packages of installed versions and of versions kept for rollback
+fn retained(conn: &Connection) -> Result<HashSet<PathBuf>> {
+    Ok(get_retained_package_files(conn)?
+        .into_iter()
+        .map(root::path)
+        .collect())
+}
@@ -0,0 +1,347 @@
+use crate::commands::install::{
+    abort_upgrade, check_pin, commit_upgrade, install_staged, prepare_upgrade, stage,
+};
+use crate::config::Config;
+use crate::db::operations::{
+    GenerationPackage, add_generation, get_generation_packages, get_generations,
+    get_installed_packages, get_package_data, set_package_lpkg_path,
+};
+use crate::repository::cache::{self, CACHE_DIR};
+use crate::utils::checksum::{calculate_sha256, verify_checksum};
+use crate::utils::root;
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::Path;
+
+// Record the installed packages as a new generation, unless they are the same
+// as in the latest one. Package files that are not in the cache yet are
+// copied there so the generation can be restored offline.
+pub fn snapshot(conn: &mut Connection, command: &str) -> Result<()> {
+    let packages = installed_packages(conn)?;
+    if let Some((latest, ..)) = get_generations(conn)?.last()
+        && get_generation_packages(conn, *latest)? == packages
+    {
+        return Ok(());
+    }
+    let keep = Config::load()?.generations.keep;
+    let generation = add_generation(conn, command, &packages, keep)?;
+    println!("Recorded generation {}.", generation);
+    Ok(())
+}
+
+pub fn list(conn: &Connection) -> Result<()> {
+    let generations = get_generations(conn).context("Failed to list generations")?;
+    if generations.is_empty() {
+        println!("No generations recorded yet.");
+        return Ok(());
+    }
+    // The current generation is the newest one matching the installed versions
+    let installed: Vec<(String, String)> = get_installed_packages(conn)?
+        .into_iter()
+        .map(|(_, name, version)| (name, version))
+        .collect();
+    let mut current = None;
+    for (id, ..) in generations.iter().rev() {
+        let versions: Vec<(String, String)> = get_generation_packages(conn, *id)?
+            .into_iter()
+            .map(|p| (p.name, p.version))
+            .collect();
+        if versions == installed {
+            current = Some(*id);
+            break;
+        }
+    }
+    for (id, created_at, command, count) in &generations {
+        println!(
+            "{} {:>4}  {}  {} package(s)  {}",
+            if current == Some(*id) { "*" } else { " " },
+            id,
+            created_at,
+            count,
+            command
+        );
+    }
+    Ok(())
+}
+
+// Converge the installed packages to those of an earlier generation
+pub fn switch(conn: &mut Connection, generation: i64) -> Result<()> {
+    if !get_generations(conn)?
+        .iter()
+        .any(|(id, ..)| *id == generation)
+    {
+        return Err(anyhow::anyhow!("Generation {} does not exist.", generation));
+    }
+    let target = get_generation_packages(conn, generation)?;
+    println!("Switching to generation {}:", generation);
+    if converge(conn, &target)? {
+        println!("Switched to generation {}.", generation);
+    } else {
+        println!("The system already matches generation {}.", generation);
+    }
+    Ok(())
+}
+
+// Install, remove and change packages so exactly the `target` packages are
+// installed, using only cached package files. Version changes are prepared
+// before anything is switched, so a package that fails to unpack leaves the
+// system as it was. A package at the same version but built from a different
+// package file is reinstalled. Returns false when nothing had to change.
+pub fn converge(conn: &mut Connection, target: &[GenerationPackage]) -> Result<bool> {
+    let checksums: HashMap<String, Option<String>> = recorded_packages(conn)?
+        .into_iter()
+        .map(|p| (p.name, p.sha256))
+        .collect();
+    let installed: HashMap<String, (i64, String)> = get_installed_packages(conn)?
+        .into_iter()
+        .map(|(id, name, version)| (name, (id, version)))
+        .collect();
+
+    let mut changes = Vec::new();
+    let mut new_installs = Vec::new();
+    for package in target {
+        match installed.get(&package.name) {
+            Some((_, version))
+                if *version == package.version
+                    && (package.sha256.is_none()
+                        || checksums.get(&package.name) == Some(&package.sha256)) => {}
+            Some((id, version)) => changes.push((package, *id, version.clone())),
+            None => new_installs.push(package),
+        }
+    }
+    let mut removals: Vec<&String> = installed
+        .keys()
+        .filter(|name| !target.iter().any(|p| &p.name == *name))
+        .collect();
+    removals.sort();
+
+    if changes.is_empty() && new_installs.is_empty() && removals.is_empty() {
+        return Ok(false);
+    }
+    for (package, _, version) in &changes {
+        if *version == package.version {
+            println!("  {} {} (reinstall)", package.name, version);
+        } else {
+            println!("  {} {} -> {}", package.name, version, package.version);
+        }
+    }
+    for package in &new_installs {
+        println!("  install {} {}", package.name, package.version);
+    }
+    for name in &removals {
+        println!("  remove {} {}", name, installed[*name].1);
+    }
+
+    // Everything needed has to be in the cache before anything changes
+    for package in changes
+        .iter()
+        .map(|(p, ..)| *p)
+        .chain(new_installs.iter().copied())
+    {
+        cached_file(package)?;
+        check_pin(conn, &package.name, &package.version)?;
+    }
+    let planned: HashMap<String, String> = target
+        .iter()
+        .map(|p| (p.name.clone(), p.version.clone()))
+        .collect();
+
+    let mut prepared = Vec::new();
+    for (package, package_id, _) in &changes {
+        let result = stage(&cached_file(package)?)
+            .and_then(|staged| prepare_upgrade(conn, *package_id, staged, &planned));
+        match result {
+            Ok(upgrade) => prepared.push(upgrade),
+            Err(e) => {
+                for upgrade in prepared {
+                    abort_upgrade(upgrade);
+                }
+                return Err(e.context(format!(
+                    "Failed to prepare {} {}, the system was not changed",
+                    package.name, package.version
+                )));
+            }
+        }
+    }
+    for upgrade in prepared {
+        let name = upgrade.name().to_string();
+        commit_upgrade(conn, upgrade).context(format!("Failed to switch {}", name))?;
+    }
+    for package in &new_installs {
+        let staged = stage(&cached_file(package)?)?;
+        install_staged(conn, &staged, &planned).context(format!(
+            "Failed to install {} {}",
+            package.name, package.version
+        ))?;
+    }
+    for name in removals {
+        crate::commands::remove::remove(conn, name)?;
+    }
+    Ok(true)
+}
+
+// The installed packages with the cached copy of each package file. Packages
+// installed from a file outside the cache are copied into it first.
+pub fn installed_packages(conn: &Connection) -> Result<Vec<GenerationPackage>> {
+    let mut packages = Vec::new();
+    for (id, name, version) in get_installed_packages(conn)? {
+        let lpkg_path = get_package_data(conn, &name)?
+            .map(|(_, _, path)| path)
+            .filter(|path| root::path(path).is_file());
+        let cached = match lpkg_path {
+            // Cached packages are named after their checksum
+            Some(path) if path.starts_with(CACHE_DIR) => Some(Path::new(&path).to_path_buf()),
+            Some(path) => {
+                let file = root::path(&path);
+                let cached = cache::store(file.to_str().unwrap_or_default(), &name, &version)?;
+                set_package_lpkg_path(conn, id, cached.to_str().unwrap_or_default())?;
+                Some(cached)
+            }
+            None => None,
+        };
+        packages.push(GenerationPackage {
+            sha256: cached
+                .as_ref()
+                .and_then(|p| p.file_stem())
+                .map(|s| s.to_string_lossy().to_string()),
+            lpkg_path: cached.map(|p| p.to_string_lossy().to_string()),
+            name,
+            version,
+        });
+    }
+    Ok(packages)
+}
+
+// The installed packages as recorded, without copying anything into the
+// cache. Package files outside the cache are checksummed where they are.
+pub fn recorded_packages(conn: &Connection) -> Result<Vec<GenerationPackage>> {
+    let mut packages = Vec::new();
+    for (_, name, version) in get_installed_packages(conn)? {
+        let lpkg_path = get_package_data(conn, &name)?
+            .map(|(_, _, path)| path)
+            .filter(|path| root::path(path).is_file());
+        let sha256 = match &lpkg_path {
+            Some(path) if path.starts_with(CACHE_DIR) => Path::new(path)
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string()),
+            Some(path) => Some(calculate_sha256(&root::path(path).to_string_lossy())?),
+            None => None,
+        };
+        packages.push(GenerationPackage {
+            name,
+            version,
+            sha256,
+            lpkg_path,
+        });
+    }
+    Ok(packages)
+}
+
+// The verified cached package file of a generation package, on the running
+// system
+fn cached_file(package: &GenerationPackage) -> Result<String> {
+    let missing = || {
+        anyhow::anyhow!(
+            "{} {} is not in the package cache and cannot be restored offline",
+            package.name,
+            package.version
+        )
+    };
+    let (Some(path), Some(sha256)) = (&package.lpkg_path, &package.sha256) else {
+        return Err(missing());
+    };
+    let file = root::path(path).to_string_lossy().into_owned();
+    if !Path::new(&file).is_file() {
+        return Err(missing());
+    }
+    if !verify_checksum(&file, sha256)? {
+        return Err(anyhow::anyhow!(
+            "The cached package for {} {} is corrupt ({}).",
+            package.name,
+            package.version,
+            path
+        ));
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::install::{install, install_dir};
+    use crate::commands::remove::remove;
+    use crate::commands::testing::{pack, source};
+    use crate::commands::upgrade;
+    use crate::db::connection::open_in_memory;
+    use crate::repository::resolve::Features;
+    use std::fs;
+
+    fn versions(conn: &Connection) -> Vec<(String, String)> {
+        get_installed_packages(conn)
+            .unwrap()
+            .into_iter()
+            .map(|(_, name, version)| (name, version))
+            .filter(|(name, _)| name.starts_with("era-"))
+            .collect()
+    }
+
+    #[test]
+    fn test_switch_restores_versions_constrained_by_each_other() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let local = tempfile::tempdir().unwrap();
+        let depends = |on: &str| format!("\n[dependencies]\nera-lib = \"{}\"\n", on);
+        for (name, meta) in [
+            ("era-lib", String::new()),
+            ("era-app", depends("<2")),
+            ("era-old", String::new()),
+        ] {
+            let file = pack(local.path(), name, "1.0.0", &meta, &[("data", "1")]);
+            install(&mut conn, &file, &[]).unwrap();
+        }
+        snapshot(&mut conn, "first").unwrap();
+        let (first, ..) = get_generations(&conn).unwrap()[0].clone();
+        let before = versions(&conn);
+
+        // Both packages move to 2.0.0 together, one package goes and another
+        // arrives
+        remove(&mut conn, "era-old").unwrap();
+        let repo = tempfile::tempdir().unwrap();
+        pack(repo.path(), "era-lib", "2.0.0", "", &[("data", "2")]);
+        pack(
+            repo.path(),
+            "era-app",
+            "2.0.0",
+            &depends(">=2"),
+            &[("data", "2")],
+        );
+        let sources = vec![source(repo.path())];
+        let requested = ["era-app".to_string(), "era-lib".to_string()];
+        upgrade::run(
+            &mut conn,
+            &sources,
+            &requested,
+            &[],
+            false,
+            &Features::new(),
+        )
+        .unwrap();
+        let file = pack(local.path(), "era-new", "1.0.0", "", &[("data", "1")]);
+        install(&mut conn, &file, &[]).unwrap();
+        snapshot(&mut conn, "second").unwrap();
+        drop(local);
+        drop(repo);
+
+        // era-app 1.0.0 needs era-lib below 2 and era-app 2.0.0 the opposite,
+        // so the switch only works if checked against the target generation
+        switch(&mut conn, first).unwrap();
+        assert_eq!(versions(&conn), before);
+        let data = root::path(install_dir("era-lib", "1.0.0")).join("data");
+        assert_eq!(fs::read_to_string(data).unwrap(), "1");
+        let data = root::path(install_dir("era-new", "1.0.0")).join("data");
+        assert!(!data.exists());
+        let target = get_generation_packages(&conn, first).unwrap();
+        assert!(!converge(&mut conn, &target).unwrap());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::commands::generations::{converge, installed_packages, recorded_packages};
+use crate::db::operations::{
+    GenerationPackage, TransactionChange, begin_transaction, finish_transaction, get_transaction,
+    get_transaction_changes, get_transactions,
+};
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+// A transaction in progress and the packages installed when it started
+pub struct Transaction {
+    id: i64,
+    before: Vec<GenerationPackage>,
+}
+
+// Start recording a package operation
+pub fn begin(conn: &Connection, command: &str) -> Result<Transaction> {
+    let user = current_user();
+    let before = recorded_packages(conn)?;
+    let id = begin_transaction(conn, command, &user)?;
+    Ok(Transaction { id, before })
+}
+
+// Record the result of an operation and the packages it added, removed or
+// changed, even when it failed part way. A package counts as changed when its
+// version or package file did.
+pub fn finish(conn: &mut Connection, transaction: Transaction, result: &Result<()>) -> Result<()> {
+    let after = recorded_packages(conn)?;
+    let mut packages: BTreeMap<&str, (Option<&GenerationPackage>, Option<&GenerationPackage>)> =
+        BTreeMap::new();
+    for package in &transaction.before {
+        packages.entry(&package.name).or_default().0 = Some(package);
+    }
+    for package in &after {
+        packages.entry(&package.name).or_default().1 = Some(package);
+    }
+    let changes: Vec<TransactionChange> = packages
+        .into_iter()
+        .filter(|(_, (old, new))| {
+            old.map(|p| (&p.version, &p.sha256)) != new.map(|p| (&p.version, &p.sha256))
+        })
+        .map(|(name, (old, new))| TransactionChange {
+            name: name.to_string(),
+            old_version: old.map(|p| p.version.clone()),
+            new_version: new.map(|p| p.version.clone()),
+            old_sha256: old.and_then(|p| p.sha256.clone()),
+            old_lpkg_path: old.and_then(|p| p.lpkg_path.clone()),
+        })
+        .collect();
+    let outcome = match result {
+        Ok(()) => "success".to_string(),
+        Err(e) => format!("failed: {:#}", e),
+    };
+    finish_transaction(conn, transaction.id, &outcome, &changes)
+}
+
+pub fn list(conn: &Connection) -> Result<()> {
+    let transactions = get_transactions(conn).context("Failed to list transactions")?;
+    if transactions.is_empty() {
+        println!("No transactions recorded yet.");
+        return Ok(());
+    }
+    for transaction in &transactions {
+        // Only the first line of a failure fits the overview
+        let result = transaction.result.lines().next().unwrap_or_default();
+        println!(
+            "{:>4}  {}  {}  {} change(s)  {}  [{}]",
+            transaction.id,
+            transaction.started_at,
+            transaction.user,
+            transaction.changes,
+            transaction.command,
+            result
+        );
+    }
+    Ok(())
+}
+
+pub fn show(conn: &Connection, transaction_id: i64) -> Result<()> {
+    let transaction = get_transaction(conn, transaction_id)?
+        .ok_or_else(|| anyhow::anyhow!("Transaction {} does not exist.", transaction_id))?;
+    println!("Transaction {}", transaction.id);
+    println!("Command: {}", transaction.command);
+    println!("User: {}", transaction.user);
+    println!("Started: {}", transaction.started_at);
+    println!(
+        "Finished: {}",
+        transaction.finished_at.as_deref().unwrap_or("-")
+    );
+    println!("Result: {}", transaction.result);
+
+    let changes = get_transaction_changes(conn, transaction_id)?;
+    if changes.is_empty() {
+        println!("No packages were changed.");
+    }
+    for change in &changes {
+        match (&change.old_version, &change.new_version) {
+            (None, Some(new)) => println!("  + {} {}", change.name, new),
+            (Some(old), None) => println!("  - {} {}", change.name, old),
+            (Some(old), Some(new)) if old == new => {
+                println!("  ~ {} {} (reinstalled)", change.name, old)
+            }
+            (Some(old), Some(new)) => println!("  ~ {} {} -> {}", change.name, old, new),
+            (None, None) => {}
+        }
+    }
+    Ok(())
+}
+
+// Reverse a transaction: remove the packages it added, reinstall the ones it
+// removed and put changed packages back to their old versions, from the
+// package cache. Only possible while the packages are still as the
+// transaction left them.
+pub fn undo(conn: &mut Connection, transaction_id: i64) -> Result<()> {
+    if get_transaction(conn, transaction_id)?.is_none() {
+        return Err(anyhow::anyhow!(
+            "Transaction {} does not exist.",
+            transaction_id
+        ));
+    }
+    let changes = get_transaction_changes(conn, transaction_id)?;
+    if changes.is_empty() {
+        println!(
+            "Transaction {} did not change any packages.",
+            transaction_id
+        );
+        return Ok(());
+    }
+
+    let mut target: BTreeMap<String, GenerationPackage> = installed_packages(conn)?
+        .into_iter()
+        .map(|p| (p.name.clone(), p))
+        .collect();
+    for change in &changes {
+        let current = target.get(&change.name).map(|p| p.version.as_str());
+        if current != change.new_version.as_deref() {
+            return Err(anyhow::anyhow!(
+                "Cannot undo transaction {}: {} is {}, but the transaction left {}",
+                transaction_id,
+                change.name,
+                describe(current),
+                describe(change.new_version.as_deref())
+            ));
+        }
+        // A reinstall can only be reversed with the package file it replaced
+        if change.old_version == change.new_version && change.old_sha256.is_none() {
+            return Err(anyhow::anyhow!(
+                "Cannot undo transaction {}: {} {} was reinstalled, but the package file it replaced is not known",
+                transaction_id,
+                change.name,
+                change.old_version.as_deref().unwrap_or_default()
+            ));
+        }
+        match &change.old_version {
+            Some(old_version) => {
+                target.insert(
+                    change.name.clone(),
+                    GenerationPackage {
+                        name: change.name.clone(),
+                        version: old_version.clone(),
+                        sha256: change.old_sha256.clone(),
+                        lpkg_path: change.old_lpkg_path.clone(),
+                    },
+                );
+            }
+            None => {
+                target.remove(&change.name);
+            }
+        }
+    }
+
+    println!("Undoing transaction {}:", transaction_id);
+    let target: Vec<GenerationPackage> = target.into_values().collect();
+    if converge(conn, &target)? {
+        println!("Transaction {} was undone.", transaction_id);
+    } else {
+        println!(
+            "The packages are already as they were before transaction {}.",
+            transaction_id
+        );
+    }
+    Ok(())
+}
+
+fn describe(version: Option<&str>) -> String {
+    match version {
+        Some(version) => format!("at version {}", version),
+        None => "not installed".to_string(),
+    }
+}
+
+// The user behind sudo is more useful than root. Without either variable
+// the name is looked up from the process owner's uid.
+fn current_user() -> String {
+    if let Ok(user) = env::var("SUDO_USER").or_else(|_| env::var("USER")) {
+        return user;
+    }
+    let Ok(uid) = fs::metadata("/proc/self").map(|m| m.uid()) else {
+        return "unknown".to_string();
+    };
+    fs::read_to_string("/etc/passwd")
+        .ok()
+        .and_then(|passwd| {
+            passwd.lines().find_map(|line| {
+                let fields: Vec<&str> = line.split(':').collect();
+                (fields.get(2) == Some(&uid.to_string().as_str())).then(|| fields[0].to_string())
+            })
+        })
+        .unwrap_or_else(|| format!("uid {}", uid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::install::install;
+    use crate::commands::remove::remove;
+    use crate::commands::testing::pack;
+    use crate::db::connection::open_in_memory;
+    use crate::db::operations::get_installed_packages;
+    use crate::utils::root;
+
+    fn versions(conn: &Connection) -> Vec<(String, String)> {
+        get_installed_packages(conn)
+            .unwrap()
+            .into_iter()
+            .map(|(_, name, version)| (name, version))
+            .filter(|(name, _)| name.starts_with("undo-"))
+            .collect()
+    }
+
+    #[test]
+    fn test_undo_reverses_every_change_of_a_transaction() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let local = tempfile::tempdir().unwrap();
+        for name in ["undo-lib", "undo-gone"] {
+            let file = pack(local.path(), name, "1.0.0", "", &[("data", "1")]);
+            install(&mut conn, &file, &[]).unwrap();
+        }
+        let before = versions(&conn);
+
+        // One transaction upgrades, adds and removes a package
+        let transaction = begin(&conn, "lpkg test").unwrap();
+        let id = transaction.id;
+        let file = pack(local.path(), "undo-lib", "2.0.0", "", &[("data", "2")]);
+        install(&mut conn, &file, &[]).unwrap();
+        let file = pack(local.path(), "undo-new", "1.0.0", "", &[("data", "1")]);
+        install(&mut conn, &file, &[]).unwrap();
+        remove(&mut conn, "undo-gone").unwrap();
+        finish(&mut conn, transaction, &Ok(())).unwrap();
+        drop(local);
+
+        let changes: Vec<(String, Option<String>, Option<String>)> =
+            get_transaction_changes(&conn, id)
+                .unwrap()
+                .into_iter()
+                .map(|c| (c.name, c.old_version, c.new_version))
+                .collect();
+        let version = |v: &str| Some(v.to_string());
+        assert_eq!(
+            changes,
+            [
+                ("undo-gone".to_string(), version("1.0.0"), None),
+                ("undo-lib".to_string(), version("1.0.0"), version("2.0.0")),
+                ("undo-new".to_string(), None, version("1.0.0")),
+            ]
+        );
+
+        undo(&mut conn, id).unwrap();
+        assert_eq!(versions(&conn), before);
+
+        // The packages are no longer as the transaction left them
+        let error = undo(&mut conn, id).unwrap_err();
+        assert!(error.to_string().contains("Cannot undo"), "{:#}", error);
+    }
+}
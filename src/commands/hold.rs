@@ -0,0 +1,162 @@
+use crate::db::operations::{get_package_data, get_pins, set_hold, set_pin};
+use crate::repository::resolve::{Pin, Pins};
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use semver::VersionReq;
+
+// Hold a package at its installed version, or list holds and pins when no
+// package is given
+pub fn hold(conn: &Connection, package_name: Option<&str>) -> Result<()> {
+    let Some(package_name) = package_name else {
+        return list(conn);
+    };
+    let (_, version, _) = get_package_data(conn, package_name)?
+        .ok_or_else(|| anyhow::anyhow!("Package '{}' is not installed.", package_name))?;
+    set_hold(conn, package_name, true)
+        .context(format!("Failed to hold package '{}'", package_name))?;
+    println!("Package '{}' is held at version {}.", package_name, version);
+    Ok(())
+}
+
+pub fn unhold(conn: &Connection, package_name: &str) -> Result<()> {
+    if !get_pins(conn)?
+        .iter()
+        .any(|(name, held, _)| name == package_name && *held)
+    {
+        println!("Package '{}' is not held.", package_name);
+        return Ok(());
+    }
+    set_hold(conn, package_name, false)
+        .context(format!("Failed to release hold on '{}'", package_name))?;
+    println!("Package '{}' is no longer held.", package_name);
+    Ok(())
+}
+
+// Pin a package to a version range such as ">=2.1, <2.3". The package does
+// not have to be installed yet.
+pub fn pin(conn: &Connection, package_name: &str, range: &str) -> Result<()> {
+    let req = VersionReq::parse(range).context(format!("Invalid version range '{}'", range))?;
+    set_pin(conn, package_name, Some(&req.to_string()))
+        .context(format!("Failed to pin package '{}'", package_name))?;
+    println!("Package '{}' is pinned to {}.", package_name, req);
+    Ok(())
+}
+
+pub fn unpin(conn: &Connection, package_name: &str) -> Result<()> {
+    if !get_pins(conn)?
+        .iter()
+        .any(|(name, _, pin)| name == package_name && pin.is_some())
+    {
+        println!("Package '{}' is not pinned.", package_name);
+        return Ok(());
+    }
+    set_pin(conn, package_name, None)
+        .context(format!("Failed to unpin package '{}'", package_name))?;
+    println!("Package '{}' is no longer pinned.", package_name);
+    Ok(())
+}
+
+fn list(conn: &Connection) -> Result<()> {
+    let pins = get_pins(conn).context("Failed to list holds and pins")?;
+    if pins.is_empty() {
+        println!("No packages are held or pinned.");
+        return Ok(());
+    }
+    for (name, held, pin) in pins {
+        let mut state = Vec::new();
+        if held {
+            match get_package_data(conn, &name)? {
+                Some((_, version, _)) => state.push(format!("held at {}", version)),
+                None => state.push("held (not installed)".to_string()),
+            }
+        }
+        if let Some(pin) = pin {
+            state.push(format!("pinned to {}", pin));
+        }
+        println!("{}: {}", name, state.join(", "));
+    }
+    Ok(())
+}
+
+// The holds and pins the resolver has to respect. A hold pins a package to
+// exactly its installed version and takes precedence over a version range;
+// holds on packages that are not installed have no effect.
+pub fn load_pins(conn: &Connection) -> Result<Pins> {
+    let mut pins = Pins::new();
+    for (name, held, range) in get_pins(conn)? {
+        let installed = get_package_data(conn, &name)?.map(|(_, version, _)| version);
+        let pin = match (held, installed, range) {
+            (true, Some(version), _) => Pin {
+                req: VersionReq::parse(&format!("={}", version)).context(format!(
+                    "Installed version '{}' of held package {} is not a valid semantic version",
+                    version, name
+                ))?,
+                reason: format!("held at {}", version),
+            },
+            (_, _, Some(range)) => Pin {
+                req: VersionReq::parse(&range)
+                    .context(format!("Invalid pin '{}' for {}", range, name))?,
+                reason: format!("pinned to {}", range),
+            },
+            _ => continue,
+        };
+        pins.insert(name, pin);
+    }
+    Ok(pins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::install::install;
+    use crate::commands::testing::{pack, source};
+    use crate::commands::upgrade;
+    use crate::db::connection::open_in_memory;
+    use crate::repository::resolve::{self, Features};
+    use crate::utils::root;
+
+    #[test]
+    fn test_holds_and_pins_block_install_and_upgrade() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let local = tempfile::tempdir().unwrap();
+        let file = pack(local.path(), "steady", "1.0.0", "", &[("data", "1")]);
+        install(&mut conn, &file, &[]).unwrap();
+
+        let repo = tempfile::tempdir().unwrap();
+        for version in ["1.1.0", "2.0.0"] {
+            pack(repo.path(), "steady", version, "", &[("data", version)]);
+        }
+        let sources = vec![source(repo.path())];
+        let installed = |conn: &Connection| get_package_data(conn, "steady").unwrap().unwrap().1;
+
+        // A held package is not upgraded, from a file or a repository
+        hold(&conn, Some("steady")).unwrap();
+        let newer = pack(local.path(), "steady", "1.1.0", "", &[("data", "1.1")]);
+        let error = install(&mut conn, &newer, &[]).unwrap_err();
+        assert!(error.to_string().contains("held at 1.0.0"), "{:#}", error);
+        let pins = load_pins(&conn).unwrap();
+        assert!(resolve::find_candidate(&sources, "steady", None, &pins).is_none());
+        assert_eq!(installed(&conn), "1.0.0");
+
+        // A pin allows upgrades within its range only
+        unhold(&conn, "steady").unwrap();
+        pin(&conn, "steady", ">=1.1, <2").unwrap();
+        let newest = pack(local.path(), "steady", "2.0.0", "", &[("data", "2")]);
+        let error = install(&mut conn, &newest, &[]).unwrap_err();
+        assert!(error.to_string().contains("pinned to"), "{:#}", error);
+        upgrade::run(
+            &mut conn,
+            &sources,
+            &["steady".to_string()],
+            &[],
+            false,
+            &Features::new(),
+        )
+        .unwrap();
+        assert_eq!(installed(&conn), "1.1.0");
+
+        unpin(&conn, "steady").unwrap();
+        assert!(load_pins(&conn).unwrap().is_empty());
+    }
+}
@@ -4,7 +4,7 @@ use rusqlite::Connection;
 use std::fs;
 use std::path::Path;
 use std::process::Command;
-use toml_edit::{Document, value};
+use toml_edit::{Array, ArrayOfTables, DocumentMut, InlineTable, Item, Table, value};
 use yaml_rust::YamlLoader;
 use crate::utils::file_ops::copy_dir_all;
 
@@ -69,7 +69,7 @@ pub fn prepare_flutter_project(_conn: &mut Connection, project_root: &Path, meta
     ))?;
 
     // 4. Generate meta.toml
-    let mut meta_doc = Document::new();
+    let mut meta_doc = DocumentMut::new();
     meta_doc["package"]["name"] = value(package_name);
     meta_doc["package"]["version"] = value(package_version);
     meta_doc["package"]["description"] = value(package_description);
@@ -87,9 +87,33 @@ pub fn prepare_flutter_project(_conn: &mut Connection, project_root: &Path, meta
 
     meta_doc["package"]["application_id"] = value(application_id.clone());
 
+    // The bundle finds its data and lib/ relative to the working directory
+    let mut bin = Table::new();
+    bin["name"] = value(package_name);
+    bin["path"] = value(package_name);
+    bin["working_dir"] = value(".");
+    let mut bins = ArrayOfTables::new();
+    bins.push(bin);
+    meta_doc["bin"] = Item::ArrayOfTables(bins);
+
+    // Declare the desktop entry created below and the icon, if any
+    let mut desktop = Table::new();
+    desktop["entries"] = value(Array::from_iter([format!(
+        "usr/share/applications/{}.desktop",
+        package_name
+    )]));
+
     // Handle icon
     let icon_path = project_root.join("assets/logo.png");
     if icon_path.exists() {
+        let mut icon = InlineTable::new();
+        icon.insert(
+            "path",
+            format!("usr/share/icons/hicolor/128x128/apps/{}.png", package_name).into(),
+        );
+        icon.insert("size", "128x128".into());
+        desktop["icons"] = value(Array::from_iter([icon]));
+
         fs::copy(
             &icon_path,
             usr_share_icons_dir.join(format!("{}.png", package_name)),
@@ -102,6 +126,7 @@ pub fn prepare_flutter_project(_conn: &mut Connection, project_root: &Path, meta
                 .display()
         ))?;
     }
+    meta_doc["desktop"] = Item::Table(desktop);
 
     fs::write(meta_path, meta_doc.to_string())
         .context("Failed to write auto-generated meta.toml for Flutter project")?;
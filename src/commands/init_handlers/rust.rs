@@ -3,7 +3,7 @@ use rusqlite::Connection;
 use std::fs;
 use std::path::Path;
 use std::process::Command;
-use toml_edit::{Document, value};
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, value};
 
 pub fn prepare_rust_project(_conn: &mut Connection, project_root: &Path, meta_path: &Path) -> Result<()> {
     println!("Detected Rust project. Preparing for lpkg packaging...");
@@ -12,7 +12,7 @@ pub fn prepare_rust_project(_conn: &mut Connection, project_root: &Path, meta_pa
     let cargo_toml_content =
         fs::read_to_string(&cargo_toml_path).context("Failed to read Cargo.toml")?;
     let cargo_toml_doc = cargo_toml_content
-        .parse::<Document>()
+        .parse::<DocumentMut>()
         .context("Failed to parse Cargo.toml")?;
 
     let package_name = cargo_toml_doc["package"]["name"]
@@ -61,12 +61,20 @@ pub fn prepare_rust_project(_conn: &mut Connection, project_root: &Path, meta_pa
     ))?;
 
     // Generate meta.toml
-    let mut meta_doc = Document::new();
+    let mut meta_doc = DocumentMut::new();
     meta_doc["package"]["name"] = value(package_name);
     meta_doc["package"]["version"] = value(package_version);
     meta_doc["package"]["description"] = value(package_description);
     meta_doc["package"]["license"] = value(package_license);
 
+    // The executable is linked onto the PATH as is
+    let mut bin = Table::new();
+    bin["name"] = value(package_name);
+    bin["path"] = value(format!("usr/bin/{}", package_name));
+    let mut bins = ArrayOfTables::new();
+    bins.push(bin);
+    meta_doc["bin"] = Item::ArrayOfTables(bins);
+
     fs::write(meta_path, meta_doc.to_string())
         .context("Failed to write auto-generated meta.toml for Rust project")?;
     println!("Auto-generated meta.toml for Rust project.");
@@ -1,15 +1,348 @@
-use crate::package::{archive::extract_archive, metadata::parse_metadata};
+use crate::commands::hold::load_pins;
+use crate::config::Config;
+use crate::db::operations::{
+    add_dependency, add_package, add_package_feature, add_package_file, add_previous_version,
+    add_provide, get_file_owner, get_package_data, get_package_features,
+    get_package_file_checksums, get_package_id, remove_package_file, update_package,
+};
+use crate::package::archive::extract_archive;
+use crate::package::desktop::{self, SharedFile};
+use crate::package::metadata::{BinEntry, MetaFile, parse_metadata, parse_provide};
+use crate::package::systemd;
+use crate::repository::{cache, compare_versions};
+use crate::utils::checksum::calculate_sha256;
 use crate::utils::file_ops::copy_file;
+use crate::utils::{platform, root};
 use anyhow::{Context, Result};
 use rusqlite::Connection;
+use semver::{Version, VersionReq};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use walkdir::WalkDir;
 
-pub fn install(conn: &mut Connection, file: &str) -> Result<()> {
+const PACKAGES_DIR: &str = "/usr/local/lpkg/packages";
+const BIN_DIR: &str = "/usr/local/bin";
+
+// Install either a local .lpkg file or, for "name=version", that exact
+// version from the configured repositories, with the given optional features
+pub fn install_package(
+    conn: &mut Connection,
+    package: &str,
+    allow_downgrade: bool,
+    features: &[String],
+) -> Result<()> {
+    if Path::new(package).exists() {
+        return install(conn, package, features);
+    }
+    match package.split_once('=') {
+        Some((name, version)) if !name.is_empty() && !version.is_empty() => {
+            crate::commands::upgrade::install_version(
+                conn,
+                name,
+                version,
+                allow_downgrade,
+                features,
+            )
+        }
+        _ => Err(anyhow::anyhow!(
+            "'{}' is neither a package file nor a name=version specification",
+            package
+        )),
+    }
+}
+
+pub fn install(conn: &mut Connection, file: &str, features: &[String]) -> Result<()> {
     println!("Installing package from: {}", file);
 
+    let mut staged = stage(file)?;
+    staged.select_features(features)?;
+    let metadata = &staged.meta_file.package;
+    println!(
+        "Installing package: {} version {}",
+        metadata.name, metadata.version
+    );
+
+    // Check if package is already installed. Selecting more features
+    // reinstalls the same version with them.
+    if let Some(package_id) = get_package_id(conn, &metadata.name, &metadata.version)? {
+        let selected = get_package_features(conn, package_id)?;
+        if features.iter().all(|f| selected.contains(f)) {
+            return Err(anyhow::anyhow!(
+                "Package '{}' version '{}' is already installed.",
+                metadata.name,
+                metadata.version
+            ));
+        }
+        println!(
+            "Reinstalling {} {} with feature(s) {}",
+            metadata.name,
+            metadata.version,
+            features.join(", ")
+        );
+        let prepared = prepare_upgrade(conn, package_id, staged, &HashMap::new())?;
+        return commit_upgrade(conn, prepared);
+    }
+
+    // Holds and pins apply to local files as well
+    check_pin(conn, &metadata.name, &metadata.version)?;
+
+    // Another installed version is upgraded in place
+    if let Some((package_id, installed_version, _)) = get_package_data(conn, &metadata.name)? {
+        if compare_versions(&metadata.version, &installed_version) != Ordering::Greater {
+            return Err(anyhow::anyhow!(
+                "Package '{}' version '{}' is installed, which is newer than '{}'. Use 'lpkg install {}={} --allow-downgrade' to downgrade.",
+                metadata.name,
+                installed_version,
+                metadata.version,
+                metadata.name,
+                metadata.version
+            ));
+        }
+        println!(
+            "Upgrading {} from version {}",
+            metadata.name, installed_version
+        );
+        let prepared = prepare_upgrade(conn, package_id, staged, &HashMap::new())?;
+        return commit_upgrade(conn, prepared);
+    }
+
+    install_staged(conn, &staged, &HashMap::new())
+}
+
+// Install a staged package that has no installed version. Dependencies may
+// also be satisfied by the `planned` versions (name -> version) of the same
+// operation.
+pub fn install_staged(
+    conn: &mut Connection,
+    staged: &Staged,
+    planned: &HashMap<String, String>,
+) -> Result<()> {
+    let metadata = &staged.meta_file.package;
+    check_dependencies(conn, staged, planned)?;
+    let bins = check_entry_points(conn, staged)?;
+    let shared = check_shared_files(conn, staged)?;
+
+    // Record the cached copy, which stays available for reinstalls and rollbacks
+    let cached = cache::store(&staged.file, &metadata.name, &metadata.version)?;
+
+    // Run pre-install script if specified
+    let scripts = metadata.scripts.as_ref();
+    staged.run_script(
+        scripts.and_then(|s| s.pre_install.as_ref()),
+        "pre-install",
+        &[],
+    )?;
+
+    // Copy files to the package's own directory, then the shared desktop
+    // files and units, then put its commands on the PATH. The package is
+    // only recorded once all of them are in place.
+    let install_dir = staged.install_dir();
+    let result = install_files(staged, &install_dir, &HashMap::new()).and_then(|mut files| {
+        files.extend(install_shared_files(&shared)?);
+        let entry_points = write_entry_points(&install_dir, &bins)?;
+        files.extend(link_entry_points(&entry_points)?);
+        Ok(files)
+    });
+    let installed_files = match result {
+        Ok(files) => files,
+        Err(e) => {
+            remove_partial_install(conn, &install_dir, &shared, &bins);
+            return Err(e);
+        }
+    };
+
+    let tx = conn.transaction()?;
+    let package_id = add_package(
+        &tx,
+        &metadata.name,
+        &metadata.version,
+        metadata.description.as_deref(),
+        metadata.license.as_deref(),
+        metadata.homepage.as_deref(),
+        metadata.repository.as_deref(),
+        metadata.authors.as_ref().map(|a| a.join(", ")).as_deref(),
+        cached.to_str(),
+    )
+    .context("Failed to add package to database")?;
+    record_dependencies(&tx, package_id, staged)?;
+    record_provides(&tx, package_id, &staged.meta_file)?;
+    for (path, checksum) in &installed_files {
+        add_package_file(&tx, package_id, path, checksum.as_deref())
+            .context(format!("Failed to record file {} in database", path))?;
+    }
+    tx.commit()
+        .context("Failed to commit installation to database")?;
+    remove_replaced(conn, &staged.meta_file, &installed_files)?;
+    let paths: Vec<&String> = installed_files.iter().map(|(path, _)| path).collect();
+    desktop::refresh_caches(&paths);
+    systemd::apply(&staged.meta_file, &[]);
+
+    // Run post-install script if specified after file installation
+    staged.run_script(
+        scripts.and_then(|s| s.post_install.as_ref()),
+        "post-install",
+        &[],
+    )?;
+    Ok(())
+}
+
+// Take back what a failed install put in place: its own directory, and the
+// shared files and commands no other package owns, which it created
+fn remove_partial_install(
+    conn: &Connection,
+    install_dir: &Path,
+    shared: &[SharedFile],
+    bins: &[BinEntry],
+) {
+    let created = shared.iter().map(|file| file.dest.clone()).chain(
+        bins.iter()
+            .map(|bin| PathBuf::from(BIN_DIR).join(&bin.name)),
+    );
+    for path in created {
+        let unowned = get_file_owner(conn, &path.to_string_lossy()).is_ok_and(|o| o.is_none());
+        if unowned && root::path(&path).symlink_metadata().is_ok() {
+            fs::remove_file(root::path(&path)).ok();
+        }
+    }
+    fs::remove_dir_all(root::path(install_dir)).ok();
+}
+
+// Refuse a version that a hold or pin on the package rules out
+pub fn check_pin(conn: &Connection, name: &str, version: &str) -> Result<()> {
+    if let Some(pin) = load_pins(conn)?.get(name)
+        && !Version::parse(version).is_ok_and(|version| pin.req.matches(&version))
+    {
+        return Err(anyhow::anyhow!(
+            "Cannot install {} {}: {} is {}. Use 'lpkg unhold' or 'lpkg unpin' first.",
+            name,
+            version,
+            name,
+            pin.reason
+        ));
+    }
+    Ok(())
+}
+
+// A package archive extracted to a temporary directory. The directory is
+// removed when this is dropped.
+pub struct Staged {
+    temp_dir: TempDir,
+    file: String,
+    meta_file: MetaFile,
+    features: Vec<String>, // Selected optional features
+}
+
+impl Staged {
+    // Select optional features of the package, whose dependencies are then
+    // required as well
+    pub fn select_features(&mut self, features: &[String]) -> Result<()> {
+        let available = self.meta_file.features();
+        for feature in features {
+            if !available.contains(&feature) {
+                let metadata = &self.meta_file.package;
+                return Err(unknown_feature(
+                    &metadata.name,
+                    &metadata.version,
+                    feature,
+                    &available,
+                ));
+            }
+            if !self.features.contains(feature) {
+                self.features.push(feature.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn files_dir(&self) -> PathBuf {
+        self.temp_dir.path().join("files")
+    }
+
+    // Versioned directory the package's files are installed to
+    fn install_dir(&self) -> PathBuf {
+        install_dir(
+            &self.meta_file.package.name,
+            &self.meta_file.package.version,
+        )
+    }
+
+    fn run_script(
+        &self,
+        script: Option<&String>,
+        label: &str,
+        envs: &[(&str, &str)],
+    ) -> Result<()> {
+        let Some(script) = script else {
+            return Ok(());
+        };
+        let script_path = self.temp_dir.path().join("scripts").join(script);
+        if !script_path.exists() {
+            return Ok(());
+        }
+        // Scripts act on the running system
+        if root::is_alternate() {
+            println!("Skipping {} script under --root: {}", label, script);
+            return Ok(());
+        }
+        println!("Running {} script: {}", label, script_path.display());
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(script_path.to_str().unwrap_or_default())
+            .envs(envs.iter().copied())
+            .output()
+            .context(format!(
+                "Failed to execute {} script {}",
+                label,
+                script_path.display()
+            ))?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "{} script failed with status: {}. Stderr: {}",
+                label,
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        println!("{} script completed successfully", label);
+        Ok(())
+    }
+}
+
+// The error for selecting a feature a package does not offer
+pub fn unknown_feature(
+    name: &str,
+    version: &str,
+    feature: &str,
+    available: &[&String],
+) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{} {} has no feature '{}'. Available features: {}",
+        name,
+        version,
+        feature,
+        if available.is_empty() {
+            "none".to_string()
+        } else {
+            available
+                .iter()
+                .map(|f| f.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    )
+}
+
+pub fn install_dir(name: &str, version: &str) -> PathBuf {
+    PathBuf::from(PACKAGES_DIR).join(format!("{}-{}", name, version))
+}
+
+// Extract a .lpkg archive and read its metadata
+pub fn stage(file: &str) -> Result<Staged> {
     let temp_base = env::var("TMPDIR").unwrap_or_else(|_| {
         let home = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
         format!("{}/lpkg_temp", home)
@@ -18,373 +351,901 @@ pub fn install(conn: &mut Connection, file: &str) -> Result<()> {
         "Failed to create temporary base directory: {}",
         temp_base
     ))?;
-    let temp_dir = format!("{}/lpkg_install_{}", temp_base, std::process::id());
-    let temp_path = Path::new(&temp_dir);
+    let temp_dir = tempfile::Builder::new()
+        .prefix("lpkg_install_")
+        .tempdir_in(&temp_base)
+        .context("Failed to create temporary directory")?;
 
     // Extract the .lpkg archive to a temporary directory
-    extract_archive(file, &temp_dir).context("Failed to extract .lpkg archive")?;
+    extract_archive(file, temp_dir.path().to_str().unwrap_or_default())
+        .context("Failed to extract .lpkg archive")?;
 
     // Read and parse meta.toml from the extracted archive
-    let meta_path = temp_path.join("meta.toml");
+    let meta_path = temp_dir.path().join("meta.toml");
     let meta_content = fs::read_to_string(&meta_path).context(format!(
         "Failed to read meta.toml from {}",
         meta_path.display()
     ))?;
     let meta_file = parse_metadata(&meta_content).context("Failed to parse package metadata")?;
-    let metadata = &meta_file.package;
-
-    let install_base_path = PathBuf::from("/usr/local/lpkg/packages")
-        .join(format!("{}-{}", metadata.name, metadata.version));
-
-    println!(
-        "DEBUG: Parsed meta_file.dependencies: {:?}",
-        meta_file.dependencies
-    );
-
-    println!(
-        "Installing package: {} version {}",
-        metadata.name, metadata.version
-    );
-
-    // Check dependencies
-    println!("DEBUG: Starting dependency check...");
-    if let Some(deps) = &meta_file.dependencies {
-        println!("DEBUG: Dependencies found: {:?}", deps);
-        for (dep_name, dep_version_constraint) in deps {
-            println!(
-                "DEBUG: Checking dependency: {} {}",
-                dep_name, dep_version_constraint
-            );
-            let is_installed = crate::db::operations::is_package_installed(
-                conn,
-                dep_name,
-                Some(dep_version_constraint),
-            )?;
-            println!(
-                "DEBUG: Is {} {} installed? {}",
-                dep_name, dep_version_constraint, is_installed
-            );
-            if !is_installed {
-                return Err(anyhow::anyhow!(
-                    "Dependency not met: {} {}",
-                    dep_name,
-                    dep_version_constraint
-                ));
-            }
-            println!(
-                "DEBUG: Dependency met: {} {}",
-                dep_name, dep_version_constraint
-            );
-        }
-        println!("DEBUG: All dependencies met.");
-    } else {
-        println!("DEBUG: No dependencies found.");
-    }
+    let package = &meta_file.package;
+    platform::check(
+        package.arch.as_deref(),
+        package.libc.as_deref(),
+        package.min_kernel.as_deref(),
+    )
+    .map_err(|reason| {
+        anyhow::anyhow!(
+            "Cannot install {} {}: {}.",
+            package.name,
+            package.version,
+            reason
+        )
+    })?;
 
-    // Check if package is already installed
-    println!("DEBUG: Checking if package is already installed...");
-    if crate::db::operations::is_package_installed(
-        conn,
-        &metadata.name,
-        Some(&metadata.version),
-    )? {
-        println!("DEBUG: Package already installed.");
+    if !temp_dir.path().join("files").is_dir() {
         return Err(anyhow::anyhow!(
-            "Package '{}' version '{}' is already installed.",
-            metadata.name,
-            metadata.version
+            "No 'files' directory found in extracted archive"
         ));
     }
-    println!("DEBUG: Package not installed, proceeding with installation.");
-
-    // Update package database first to get package_id for file recording
-    let package_id = crate::db::operations::add_package(
-        conn,
-        &metadata.name,
-        &metadata.version,
-        metadata.description.as_deref(),
-        metadata.license.as_deref(),
-        metadata.homepage.as_deref(),
-        metadata.repository.as_deref(),
-        metadata.authors.as_ref().map(|a| a.join(", ")).as_deref(),
-        Some(file),
-    )
-    .context("Failed to add package to database")?;
+    Ok(Staged {
+        temp_dir,
+        file: file.to_string(),
+        meta_file,
+        features: Vec::new(),
+    })
+}
 
-    // Add dependencies to database
-    if let Some(deps) = &meta_file.dependencies {
-        for (dep_name, dep_version_constraint) in deps {
-            crate::db::operations::add_dependency(
+// Check that every dependency is installed, or is about to be installed in
+// the same operation (`planned` maps names to the versions being installed).
+fn check_dependencies(
+    conn: &Connection,
+    staged: &Staged,
+    planned: &HashMap<String, String>,
+) -> Result<()> {
+    for (dep_name, dep_version_constraint) in staged.meta_file.dependencies_with(&staged.features) {
+        let planned_match = match planned.get(dep_name) {
+            Some(version) => {
+                let req = VersionReq::parse(dep_version_constraint).context(format!(
+                    "Failed to parse version constraint: {}",
+                    dep_version_constraint
+                ))?;
+                Version::parse(version).is_ok_and(|v| req.matches(&v))
+            }
+            None => false,
+        };
+        if !planned_match
+            && !crate::db::operations::is_package_installed(
                 conn,
-                package_id,
                 dep_name,
                 Some(dep_version_constraint),
-            )
-            .context(format!(
-                "Failed to add dependency {} {} to database",
+            )?
+        {
+            return Err(anyhow::anyhow!(
+                "Dependency not met: {} {}",
                 dep_name,
                 dep_version_constraint
-            ))?;
+            ));
         }
     }
+    Ok(())
+}
 
-    // Run pre-install script if specified
-    let scripts_dir = temp_path.join("scripts");
-    if scripts_dir.exists() && scripts_dir.is_dir() {
-        if let Some(scripts) = &metadata.scripts {
-            if let Some(pre_install) = &scripts.pre_install {
-                let script_path = scripts_dir.join(pre_install);
-                if script_path.exists() {
-                    println!("Running pre-install script: {}", script_path.display());
-                    let output = std::process::Command::new("sh")
-                        .arg("-c")
-                        .arg(script_path.to_str().unwrap_or_default())
-                        .output()
-                        .context(format!(
-                            "Failed to execute pre-install script {}",
-                            script_path.display()
-                        ))?;
-                    if !output.status.success() {
-                        return Err(anyhow::anyhow!(
-                            "Pre-install script failed with status: {}. Stderr: {}",
-                            output.status,
-                            String::from_utf8_lossy(&output.stderr)
-                        ));
-                    }
-                    println!("Pre-install script completed successfully");
-                }
+// Record the dependencies of the package with its selected features, and the
+// features themselves so upgrades keep them
+fn record_dependencies(conn: &Connection, package_id: i64, staged: &Staged) -> Result<()> {
+    for (dep_name, dep_version_constraint) in staged.meta_file.dependencies_with(&staged.features) {
+        add_dependency(conn, package_id, dep_name, Some(dep_version_constraint)).context(
+            format!(
+                "Failed to add dependency {} {} to database",
+                dep_name, dep_version_constraint
+            ),
+        )?;
+    }
+    for feature in &staged.features {
+        add_package_feature(conn, package_id, feature)
+            .context(format!("Failed to add feature {} to database", feature))?;
+    }
+    Ok(())
+}
+
+fn record_provides(conn: &Connection, package_id: i64, meta_file: &MetaFile) -> Result<()> {
+    let metadata = &meta_file.package;
+    for entry in metadata.provides.iter().flatten() {
+        let (name, version) = parse_provide(entry);
+        add_provide(conn, package_id, name, version, false)
+            .context(format!("Failed to add provide {} to database", entry))?;
+    }
+    for name in metadata.replaces.iter().flatten() {
+        add_provide(conn, package_id, name, None, true).context(format!(
+            "Failed to add replaced package {} to database",
+            name
+        ))?;
+    }
+    Ok(())
+}
+
+// Remove the installed packages a package replaces. This runs once the new
+// package is installed and recorded, so a failed install leaves the replaced
+// packages in place. Paths both packages ship (`files`, as recorded for the
+// new package) are handed over to it first, so they stay where they are.
+fn remove_replaced(
+    conn: &mut Connection,
+    meta_file: &MetaFile,
+    files: &[(String, Option<String>)],
+) -> Result<()> {
+    let metadata = &meta_file.package;
+    for replaced in metadata.replaces.iter().flatten() {
+        if *replaced == metadata.name {
+            continue;
+        }
+        if let Some((replaced_id, version, _)) = get_package_data(conn, replaced)? {
+            println!(
+                "{} {} replaces {} {}, removing it.",
+                metadata.name, metadata.version, replaced, version
+            );
+            for (path, _) in files {
+                remove_package_file(conn, replaced_id, path)?;
             }
+            crate::commands::remove::remove(conn, replaced)
+                .context(format!("Failed to remove replaced package '{}'", replaced))?;
         }
     }
+    Ok(())
+}
 
-    // Copy files to system locations from temp_path.join("files")
-    let files_dir = temp_path.join("files");
-    if files_dir.exists() && files_dir.is_dir() {
-        use walkdir::WalkDir;
-
-        let mut installed_files = Vec::new();
-        for entry in WalkDir::new(&files_dir).into_iter().filter_map(|e| e.ok()) {
-            let src_path = entry.path();
-            if src_path.is_file() {
-                // Calculate the relative path from files_dir
-                let rel_path = src_path.strip_prefix(&files_dir).context(format!(
-                    "Failed to strip prefix from path {}",
-                    src_path.display()
-                ))?;
+// Whether a path recorded for `owner` may be taken over by the package: it is
+// its own, or belongs to a package it replaces
+fn may_take_over(meta_file: &MetaFile, owner: &str) -> bool {
+    let metadata = &meta_file.package;
+    owner == metadata.name || metadata.replaces.iter().flatten().any(|r| r == owner)
+}
 
-                // Destination is in the permanent installation directory
-                let dest_path = install_base_path.join(rel_path);
+// Copy the package's files into `install_dir`. Files identical to one in
+// `reusable` (relative path -> (existing path, checksum)) are hard-linked
+// instead of copied. Returns the installed paths with their checksums.
+fn install_files(
+    staged: &Staged,
+    install_dir: &Path,
+    reusable: &HashMap<PathBuf, (String, String)>,
+) -> Result<Vec<(String, Option<String>)>> {
+    let files_dir = staged.files_dir();
+    let mut installed_files = Vec::new();
+    for entry in WalkDir::new(&files_dir).into_iter().filter_map(|e| e.ok()) {
+        let src_path = entry.path();
+        if !src_path.is_file() {
+            continue;
+        }
+        // Calculate the relative path from files_dir
+        let rel_path = src_path.strip_prefix(&files_dir).context(format!(
+            "Failed to strip prefix from path {}",
+            src_path.display()
+        ))?;
+        let dest_path = install_dir.join(rel_path);
+        let target_path = root::path(&dest_path);
+        let checksum = calculate_sha256(src_path.to_str().unwrap_or_default()).context(format!(
+            "Failed to calculate checksum for {}",
+            src_path.display()
+        ))?;
 
-                // Create parent directories if they don't exist
-                if let Some(parent) = dest_path.parent() {
-                    fs::create_dir_all(parent).context(format!(
-                        "Failed to create parent directory for {}",
-                        dest_path.display()
-                    ))?;
-                }
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent).context(format!(
+                "Failed to create parent directory for {}",
+                target_path.display()
+            ))?;
+        }
+        let unchanged = reusable
+            .get(rel_path)
+            .filter(|(_, old_checksum)| *old_checksum == checksum);
+        match unchanged {
+            Some((old_path, _)) if fs::hard_link(root::path(old_path), &target_path).is_ok() => {
+                println!("Keeping unchanged file: {}", target_path.display());
+            }
+            _ => {
                 println!(
                     "Installing file: {} -> {}",
                     src_path.display(),
-                    dest_path.display()
+                    target_path.display()
                 );
-                copy_file(
-                    src_path.to_str().unwrap_or_default(),
-                    dest_path.to_str().unwrap_or_default(),
-                )
-                .context(format!("Failed to install file {}", src_path.display()))?;
+                replace_file(src_path, &target_path)
+                    .context(format!("Failed to install file {}", src_path.display()))?;
+            }
+        }
+        installed_files.push((dest_path.to_string_lossy().to_string(), Some(checksum)));
+    }
+    Ok(installed_files)
+}
+
+// Copy a file to its destination through a temporary file in the same
+// directory, so the destination is replaced atomically
+fn replace_file(src: &Path, dest: &Path) -> Result<()> {
+    let file_name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = dest.with_file_name(format!(".{}.lpkg-new", file_name));
+    copy_file(
+        src.to_str().unwrap_or_default(),
+        temp_path.to_str().unwrap_or_default(),
+    )?;
+    fs::rename(&temp_path, dest)
+        .context(format!("Failed to move {} into place", dest.display()))?;
+    Ok(())
+}
 
-                installed_files.push(dest_path.to_string_lossy().to_string());
+// Check the desktop integration files and systemd units of a staged package
+// before anything is installed. Files of another package are never replaced.
+fn check_shared_files(conn: &Connection, staged: &Staged) -> Result<Vec<SharedFile>> {
+    let mut shared = desktop::shared_files(&staged.meta_file, &staged.files_dir())?;
+    shared.extend(systemd::unit_files(&staged.meta_file, &staged.files_dir())?);
+    for file in &shared {
+        match get_file_owner(conn, &file.dest.to_string_lossy())? {
+            Some(owner) if !may_take_over(&staged.meta_file, &owner) => {
+                return Err(anyhow::anyhow!(
+                    "{} is already installed by package '{}'",
+                    file.dest.display(),
+                    owner
+                ));
+            }
+            // Left alone like commands in BIN_DIR, see check_command_owner()
+            None if root::path(&file.dest).symlink_metadata().is_ok() => {
+                return Err(anyhow::anyhow!(
+                    "Cannot install {}: it already exists and was not installed by lpkg",
+                    file.dest.display()
+                ));
             }
+            _ => {}
         }
-        // Record installed_files in database with checksums
-        use crate::utils::checksum::calculate_sha256;
-        for file in &installed_files {
-            let src_path = files_dir.join(file);
-            let checksum = calculate_sha256(src_path.to_str().unwrap_or_default()).context(
-                format!("Failed to calculate checksum for {}", src_path.display()),
-            )?;
-            crate::db::operations::add_package_file(conn, package_id, file, Some(&checksum))
-                .context(format!("Failed to record file {} in database", file))?;
+    }
+    Ok(shared)
+}
+
+// Copy the desktop integration files and units to their shared locations
+fn install_shared_files(shared: &[SharedFile]) -> Result<Vec<(String, Option<String>)>> {
+    let mut installed_files = Vec::new();
+    for file in shared {
+        let dest = root::path(&file.dest);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)
+                .context(format!("Failed to create directory for {}", dest.display()))?;
         }
-    } else {
-        return Err(anyhow::anyhow!(
-            "No 'files' directory found in extracted archive"
-        ));
+        replace_file(&file.source, &dest).context(format!("Failed to copy {}", dest.display()))?;
+        println!("Copied {} to: {}", file.source.display(), dest.display());
+        installed_files.push((file.dest.to_string_lossy().to_string(), None));
     }
+    Ok(installed_files)
+}
 
-    // Copy .desktop file and icon to standard locations for desktop integration
-    let desktop_file_src = temp_path
-        .join("files/usr/share/applications")
-        .join(format!("{}.desktop", metadata.name));
-    let desktop_file_dest_dir = PathBuf::from("/usr/local/share/applications");
-    let desktop_file_dest = desktop_file_dest_dir.join(format!("{}.desktop", metadata.name));
+// A command of a package, linked into BIN_DIR
+pub struct EntryPoint {
+    name: String,
+    target: PathBuf,          // The executable, or the wrapper script that runs it
+    wrapper: Option<PathBuf>, // The wrapper script, if one is used
+}
 
-    if desktop_file_src.exists() {
-        fs::create_dir_all(&desktop_file_dest_dir).context(format!(
-            "Failed to create directory for desktop file: {}",
-            desktop_file_dest_dir.display()
-        ))?;
-        copy_file(
-            desktop_file_src.to_str().unwrap_or_default(),
-            desktop_file_dest.to_str().unwrap_or_default(),
-        )
-        .context(format!(
-            "Failed to copy desktop file to {}",
-            desktop_file_dest.display()
-        ))?;
-        crate::db::operations::add_package_file(
-            conn,
-            package_id,
-            desktop_file_dest.to_str().unwrap_or_default(),
-            None,
-        )
-        .context(format!(
-            "Failed to record desktop file {} in database",
-            desktop_file_dest.display()
-        ))?;
-        println!("Copied desktop file to: {}", desktop_file_dest.display());
+// The commands a package declares with [[bin]]. Packages without any get a
+// command named after the package, for usr/bin/<name> or, as Flutter
+// bundles are laid out, an executable <name> that runs in files/.
+fn bin_entries(staged: &Staged) -> Vec<BinEntry> {
+    if let Some(bin) = &staged.meta_file.bin {
+        return bin.clone();
     }
+    let name = &staged.meta_file.package.name;
+    let files_dir = staged.files_dir();
+    if is_executable(&files_dir.join("usr/bin").join(name)) {
+        vec![BinEntry {
+            name: name.clone(),
+            path: format!("usr/bin/{}", name),
+            working_dir: None,
+            env: None,
+            wrapper: None,
+        }]
+    } else if is_executable(&files_dir.join(name)) {
+        vec![BinEntry {
+            name: name.clone(),
+            path: name.clone(),
+            working_dir: Some(".".to_string()),
+            env: None,
+            wrapper: None,
+        }]
+    } else {
+        Vec::new()
+    }
+}
 
-    let icon_file_src = temp_path
-        .join("files/usr/share/icons/hicolor/128x128/apps")
-        .join(format!("{}.png", metadata.name));
-    let icon_file_dest_dir = PathBuf::from("/usr/local/share/icons/hicolor/128x128/apps");
-    let icon_file_dest = icon_file_dest_dir.join(format!("{}.png", metadata.name));
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
 
-    if icon_file_src.exists() {
-        fs::create_dir_all(&icon_file_dest_dir).context(format!(
-            "Failed to create directory for icon file: {}",
-            icon_file_dest_dir.display()
-        ))?;
-        copy_file(
-            icon_file_src.to_str().unwrap_or_default(),
-            icon_file_dest.to_str().unwrap_or_default(),
-        )
-        .context(format!(
-            "Failed to copy icon file to {}",
-            icon_file_dest.display()
-        ))?;
-        crate::db::operations::add_package_file(
-            conn,
-            package_id,
-            icon_file_dest.to_str().unwrap_or_default(),
-            None,
-        )
-        .context(format!(
-            "Failed to record icon file {} in database",
-            icon_file_dest.display()
-        ))?;
-        println!("Copied icon file to: {}", icon_file_dest.display());
-
-        // Update icon cache for GTK-based desktop environments
-        if Path::new("/usr/bin/gtk-update-icon-cache").exists() {
-            println!("Updating icon cache...");
-            let output = std::process::Command::new("gtk-update-icon-cache")
-                .arg("-q")
-                .arg("-t")
-                .arg("-f")
-                .arg("/usr/local/share/icons/hicolor")
-                .output()
-                .context("Failed to update icon cache")?;
-            if !output.status.success() {
-                eprintln!(
-                    "Warning: gtk-update-icon-cache failed: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
-            } else {
-                println!("Icon cache updated successfully.");
-            }
+// Check the commands of a staged package before anything is installed:
+// each must run an executable in the package and may not take over a
+// command that is not the package's own
+fn check_entry_points(conn: &Connection, staged: &Staged) -> Result<Vec<BinEntry>> {
+    let files_dir = staged.files_dir();
+    let bins = bin_entries(staged);
+    for (i, bin) in bins.iter().enumerate() {
+        bin.validate()
+            .map_err(|e| anyhow::anyhow!("Invalid [[bin]] entry: {}", e))?;
+        if bins[..i].iter().any(|other| other.name == bin.name) {
+            return Err(anyhow::anyhow!(
+                "Command '{}' is declared more than once",
+                bin.name
+            ));
+        }
+        if !is_executable(&files_dir.join(&bin.path)) {
+            return Err(anyhow::anyhow!(
+                "Command '{}' points to {}, which is not an executable file in the package",
+                bin.name,
+                bin.path
+            ));
         }
+        if let Some(dir) = &bin.working_dir
+            && !files_dir.join(dir).is_dir()
+        {
+            return Err(anyhow::anyhow!(
+                "Command '{}' runs in {}, which is not a directory in the package",
+                bin.name,
+                dir
+            ));
+        }
+        check_command_owner(conn, &staged.meta_file, &bin.name)?;
     }
+    Ok(bins)
+}
 
-    // Run post-install script if specified after file installation
-    let scripts_dir = temp_path.join("scripts");
-    if scripts_dir.exists() && scripts_dir.is_dir() {
-        if let Some(scripts) = &metadata.scripts {
-            if let Some(post_install) = &scripts.post_install {
-                let script_path = scripts_dir.join(post_install);
-                if script_path.exists() {
-                    println!("Running pre-install script: {}", script_path.display());
-                    let output = std::process::Command::new("sh")
-                        .arg("-c")
-                        .arg(script_path.to_str().unwrap_or_default())
-                        .output()
-                        .context(format!(
-                            "Failed to execute pre-install script {}",
-                            script_path.display()
-                        ))?;
-                    if !output.status.success() {
-                        return Err(anyhow::anyhow!(
-                            "Pre-install script failed with status: {}. Stderr: {}",
-                            output.status,
-                            String::from_utf8_lossy(&output.stderr)
-                        ));
-                    }
-                    println!("Pre-install script completed successfully");
-                }
-            }
-        }
+// Write the wrapper scripts the commands need into `install_dir`. Nothing
+// outside it is touched; link_entry_points() puts the commands on the PATH.
+fn write_entry_points(install_dir: &Path, bins: &[BinEntry]) -> Result<Vec<EntryPoint>> {
+    let mut entry_points = Vec::new();
+    for bin in bins {
+        let executable = install_dir.join(&bin.path);
+        let wrapper = if bin.uses_wrapper() {
+            Some(write_wrapper(install_dir, bin, &executable)?)
+        } else {
+            None
+        };
+        entry_points.push(EntryPoint {
+            name: bin.name.clone(),
+            target: wrapper.clone().unwrap_or(executable),
+            wrapper,
+        });
     }
+    Ok(entry_points)
+}
 
-    // Create wrapper script for the executable
-    let executable_name = metadata.name.clone();
-    let wrapper_script_name = format!("{}-wrapper.sh", executable_name);
-    let wrapper_script_path = install_base_path.join(&wrapper_script_name);
+// Refuse to take over a command that belongs to another package, other than
+// one being replaced, or was not installed by lpkg
+fn check_command_owner(conn: &Connection, meta_file: &MetaFile, command: &str) -> Result<()> {
+    let link = PathBuf::from(BIN_DIR).join(command);
+    if let Some(owner) = get_file_owner(conn, &link.to_string_lossy())? {
+        if may_take_over(meta_file, &owner) {
+            return Ok(());
+        }
+        return Err(anyhow::anyhow!(
+            "Command '{}' is already installed by package '{}'",
+            command,
+            owner
+        ));
+    }
+    let link_path = root::path(&link);
+    if link_path.symlink_metadata().is_err() {
+        return Ok(());
+    }
+    // Links made before commands were recorded point into the package's
+    // own directory
+    let own_link = fs::read_link(&link_path).is_ok_and(|target| {
+        target
+            .strip_prefix(PACKAGES_DIR)
+            .ok()
+            .and_then(|rest| rest.components().next())
+            .and_then(|dir| dir.as_os_str().to_str())
+            .is_some_and(|dir| {
+                let metadata = &meta_file.package;
+                std::iter::once(&metadata.name)
+                    .chain(metadata.replaces.iter().flatten())
+                    .filter_map(|owner| dir.strip_prefix(owner.as_str())?.strip_prefix('-'))
+                    .any(|version| Version::parse(version).is_ok())
+            })
+    });
+    if own_link {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "Cannot install command '{}': {} already exists and was not installed by lpkg",
+        command,
+        link_path.display()
+    ))
+}
 
-    let wrapper_content = format!(
-        "#!/bin/bash\n\n# Change to the application's installation directory\ncd \"{}\" || exit 1\n\n# Execute the Flutter application\nexec \"./{}\" \"$@\"",
-        install_base_path.display(),
-        executable_name
-    );
+// Write a script that runs `executable` in the requested directory with the
+// requested environment
+fn write_wrapper(install_dir: &Path, bin: &BinEntry, executable: &Path) -> Result<PathBuf> {
+    let wrapper_script_path = install_dir.join(format!("{}-wrapper.sh", bin.name));
+    let mut wrapper_content = String::from("#!/bin/sh\n");
+    if let Some(dir) = &bin.working_dir {
+        wrapper_content.push_str(&format!(
+            "cd {} || exit 1\n",
+            shell_quote(&install_dir.join(dir).to_string_lossy())
+        ));
+    }
+    let mut env: Vec<(&String, &String)> = bin.env.iter().flatten().collect();
+    env.sort();
+    for (key, value) in env {
+        wrapper_content.push_str(&format!("export {}={}\n", key, shell_quote(value)));
+    }
+    wrapper_content.push_str(&format!(
+        "exec {} \"$@\"\n",
+        shell_quote(&executable.to_string_lossy())
+    ));
 
-    fs::write(&wrapper_script_path, wrapper_content).context(format!(
+    // The script refers to paths of the managed system, wherever it is written
+    let script_file = root::path(&wrapper_script_path);
+    fs::write(&script_file, wrapper_content).context(format!(
         "Failed to write wrapper script to {}",
-        wrapper_script_path.display()
+        script_file.display()
     ))?;
-    fs::set_permissions(&wrapper_script_path, fs::Permissions::from_mode(0o755)).context(
-        format!(
-            "Failed to set permissions for wrapper script {}",
-            wrapper_script_path.display()
-        ),
-    )?;
-    println!("Created wrapper script: {}", wrapper_script_path.display());
+    fs::set_permissions(&script_file, fs::Permissions::from_mode(0o755)).context(format!(
+        "Failed to set permissions for wrapper script {}",
+        script_file.display()
+    ))?;
+    println!("Created wrapper script: {}", script_file.display());
+    Ok(wrapper_script_path)
+}
 
-    // Create symlink for the wrapper script in /usr/local/bin
-    let symlink_path = PathBuf::from("/usr/local/bin").join(&executable_name);
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
 
-    if symlink_path.exists() {
-        if symlink_path.is_symlink() {
-            fs::remove_file(&symlink_path).context(format!(
-                "Failed to remove existing symlink at {}",
-                symlink_path.display()
-            ))?;
-        } else if symlink_path.is_file() {
-            // If it's a regular file, remove it
-            fs::remove_file(&symlink_path).context(format!(
-                "Failed to remove existing file at {}",
-                symlink_path.display()
-            ))?;
-        } else if symlink_path.is_dir() {
-            // If it's a directory, we should not remove it automatically.
-            return Err(anyhow::anyhow!(
-                "Cannot create symlink: {} is an existing directory.",
-                symlink_path.display()
-            ));
+// Link the commands into BIN_DIR. Returns the links and wrapper scripts, to
+// be recorded as files of the package.
+fn link_entry_points(entry_points: &[EntryPoint]) -> Result<Vec<(String, Option<String>)>> {
+    let mut files = Vec::new();
+    for entry_point in entry_points {
+        link_command(&entry_point.target, &entry_point.name)?;
+        if let Some(wrapper) = &entry_point.wrapper {
+            files.push((wrapper.to_string_lossy().into_owned(), None));
         }
+        files.push((
+            PathBuf::from(BIN_DIR)
+                .join(&entry_point.name)
+                .to_string_lossy()
+                .into_owned(),
+            None,
+        ));
     }
-    std::os::unix::fs::symlink(&wrapper_script_path, &symlink_path).context(format!(
+    Ok(files)
+}
+
+// Point /usr/local/bin/<name> at the command's target. The link is created
+// under a temporary name and renamed over the old one, so the command never
+// disappears during an upgrade.
+fn link_command(target: &Path, executable_name: &str) -> Result<()> {
+    let symlink_path = root::path(BIN_DIR).join(executable_name);
+    if symlink_path.is_dir() && !symlink_path.is_symlink() {
+        // If it's a directory, we should not remove it automatically.
+        return Err(anyhow::anyhow!(
+            "Cannot create symlink: {} is an existing directory.",
+            symlink_path.display()
+        ));
+    }
+
+    // A fresh root may not have the directory yet
+    let bin_dir = root::path(BIN_DIR);
+    fs::create_dir_all(&bin_dir).context(format!("Failed to create {}", bin_dir.display()))?;
+    let temp_link = bin_dir.join(format!(".{}.lpkg-new", executable_name));
+    fs::remove_file(&temp_link).ok();
+    std::os::unix::fs::symlink(target, &temp_link).context(format!(
         "Failed to create symlink from {} to {}",
-        wrapper_script_path.display(),
+        target.display(),
+        temp_link.display()
+    ))?;
+    fs::rename(&temp_link, &symlink_path).context(format!(
+        "Failed to move symlink into place at {}",
         symlink_path.display()
     ))?;
     println!(
         "Created symlink: {} -> {}",
         symlink_path.display(),
-        wrapper_script_path.display()
+        target.display()
     );
+    Ok(())
+}
+
+// A new version of an installed package, written next to the old version
+// but not yet made current
+pub struct PreparedUpgrade {
+    staged: Staged,
+    package_id: i64,
+    old_version: String,
+    files: Vec<(String, Option<String>)>,
+    entry_points: Vec<EntryPoint>,
+    shared_files: Vec<SharedFile>,
+    set_aside: Option<PathBuf>, // Where a reinstalled version's old files were moved
+}
+
+impl PreparedUpgrade {
+    pub fn name(&self) -> &str {
+        &self.staged.meta_file.package.name
+    }
+}
+
+// First half of an in-place upgrade: run the pre-upgrade script and install
+// the new version's files into its own directory. Nothing the old version
+// uses is touched, so abort_upgrade() can undo this completely.
+pub fn prepare_upgrade(
+    conn: &Connection,
+    package_id: i64,
+    mut staged: Staged,
+    planned: &HashMap<String, String>,
+) -> Result<PreparedUpgrade> {
+    // Features selected for the installed version carry over to the new one,
+    // unless it no longer offers them
+    let available = staged.meta_file.features();
+    for feature in get_package_features(conn, package_id)? {
+        if staged.features.contains(&feature) {
+            continue;
+        }
+        if available.contains(&&feature) {
+            staged.features.push(feature);
+        } else {
+            println!(
+                "Note: {} {} no longer offers feature '{}', it is dropped.",
+                staged.meta_file.package.name, staged.meta_file.package.version, feature
+            );
+        }
+    }
+
+    let metadata = &staged.meta_file.package;
+    let (_, old_version, _) = get_package_data(conn, &metadata.name)?
+        .context(format!("Package '{}' is not installed", metadata.name))?;
+    check_dependencies(conn, &staged, planned)?;
+    let bins = check_entry_points(conn, &staged)?;
+    let shared_files = check_shared_files(conn, &staged)?;
+
+    let envs = [
+        ("LPKG_OLD_VERSION", old_version.as_str()),
+        ("LPKG_NEW_VERSION", metadata.version.as_str()),
+    ];
+    staged.run_script(
+        metadata
+            .scripts
+            .as_ref()
+            .and_then(|s| s.pre_upgrade.as_ref()),
+        "pre-upgrade",
+        &envs,
+    )?;
+
+    // A reinstall of the same version needs its directory, so the old files
+    // are moved aside until the upgrade is committed or aborted
+    let installed_dir = install_dir(&metadata.name, &old_version);
+    let new_dir = staged.install_dir();
+    let set_aside = (new_dir == installed_dir).then(|| set_aside_dir(&installed_dir));
+    if let Some(set_aside) = &set_aside
+        && root::path(set_aside).exists()
+    {
+        // Left over from an interrupted reinstall
+        fs::remove_dir_all(root::path(set_aside))
+            .context(format!("Failed to remove stale {}", set_aside.display()))?;
+    }
+    let old_dir = set_aside.clone().unwrap_or(installed_dir.clone());
+
+    // Unchanged files of the old version are hard-linked rather than copied
+    let reusable: HashMap<PathBuf, (String, String)> =
+        get_package_file_checksums(conn, package_id)?
+            .into_iter()
+            .filter_map(|(path, checksum)| {
+                let rel_path = Path::new(&path)
+                    .strip_prefix(&installed_dir)
+                    .ok()?
+                    .to_path_buf();
+                let old_path = old_dir.join(&rel_path).to_string_lossy().into_owned();
+                Some((rel_path, (old_path, checksum?)))
+            })
+            .collect();
+
+    if set_aside.is_some() {
+        fs::rename(root::path(&installed_dir), root::path(&old_dir))
+            .context(format!("Failed to move {} aside", installed_dir.display()))?;
+    } else if root::path(&new_dir).exists() {
+        // Left over from an interrupted attempt
+        fs::remove_dir_all(root::path(&new_dir))
+            .context(format!("Failed to remove stale {}", new_dir.display()))?;
+    }
+    let result = install_files(&staged, &new_dir, &reusable)
+        .and_then(|files| Ok((files, write_entry_points(&new_dir, &bins)?)));
+    let (files, entry_points) = match result {
+        Ok(installed) => installed,
+        Err(e) => {
+            fs::remove_dir_all(root::path(&new_dir)).ok();
+            if set_aside.is_some() {
+                fs::rename(root::path(&old_dir), root::path(&new_dir)).ok();
+            }
+            return Err(e);
+        }
+    };
+
+    Ok(PreparedUpgrade {
+        staged,
+        package_id,
+        old_version,
+        files,
+        entry_points,
+        shared_files,
+        set_aside,
+    })
+}
+
+fn set_aside_dir(dir: &Path) -> PathBuf {
+    let mut name = dir.as_os_str().to_owned();
+    name.push(".lpkg-old");
+    PathBuf::from(name)
+}
 
-    // Clean up temporary directory
-    fs::remove_dir_all(temp_path).context("Failed to clean up temporary directory")?;
+// Throw away a prepared upgrade, leaving the old version as it was
+pub fn abort_upgrade(prepared: PreparedUpgrade) {
+    let new_dir = root::path(prepared.staged.install_dir());
+    fs::remove_dir_all(&new_dir).ok();
+    if let Some(set_aside) = &prepared.set_aside {
+        fs::rename(root::path(set_aside), &new_dir).ok();
+    }
+}
+
+// Second half of an in-place upgrade: replace the shared files, switch the
+// command links, move the database record to the new version and remove
+// whatever the new version no longer ships.
+pub fn commit_upgrade(conn: &mut Connection, prepared: PreparedUpgrade) -> Result<()> {
+    let PreparedUpgrade {
+        staged,
+        package_id,
+        old_version,
+        mut files,
+        entry_points,
+        shared_files,
+        set_aside,
+    } = prepared;
+    let metadata = &staged.meta_file.package;
+    let old_files = get_package_file_checksums(conn, package_id)?;
+    let cached = cache::store(&staged.file, &metadata.name, &metadata.version)?;
+    // The replaced version is kept in the cache so it can be rolled back to
+    let keep_versions = Config::load()?.rollback.keep_versions;
+    let previous = match get_package_data(conn, &metadata.name)? {
+        Some((_, _, old_file)) if keep_versions > 0 && root::path(&old_file).is_file() => {
+            let old_file = root::path(&old_file);
+            Some(cache::store(
+                old_file.to_str().unwrap_or_default(),
+                &metadata.name,
+                &old_version,
+            )?)
+        }
+        _ => None,
+    };
+
+    files.extend(install_shared_files(&shared_files)?);
+    files.extend(link_entry_points(&entry_points)?);
 
+    // Keep a single record for the package, now describing the new version
+    let tx = conn.transaction()?;
+    update_package(
+        &tx,
+        package_id,
+        &metadata.version,
+        metadata.description.as_deref(),
+        metadata.license.as_deref(),
+        metadata.homepage.as_deref(),
+        metadata.repository.as_deref(),
+        metadata.authors.as_ref().map(|a| a.join(", ")).as_deref(),
+        cached.to_str(),
+    )?;
+    if let Some(previous) = &previous {
+        add_previous_version(
+            &tx,
+            &metadata.name,
+            &old_version,
+            previous.to_str().unwrap_or_default(),
+            keep_versions,
+        )?;
+    }
+    for (path, checksum) in &files {
+        add_package_file(&tx, package_id, path, checksum.as_deref())
+            .context(format!("Failed to record file {} in database", path))?;
+    }
+    record_dependencies(&tx, package_id, &staged)?;
+    record_provides(&tx, package_id, &staged.meta_file)?;
+    tx.commit()
+        .context("Failed to commit upgrade to database")?;
+    remove_replaced(conn, &staged.meta_file, &files)?;
+
+    // Remove files that disappeared in the new version, including commands and
+    // units it no longer declares, then what is left of the old version's
+    // directory
+    let kept: HashSet<&String> = files.iter().map(|(path, _)| path).collect();
+    let dropped: Vec<&String> = old_files
+        .iter()
+        .map(|(path, _)| path)
+        .filter(|path| !kept.contains(path))
+        .collect();
+    systemd::stop_units(&dropped);
+    for path in &dropped {
+        println!("Removing file: {}", path);
+        crate::utils::file_ops::remove_file(&root::path(path).to_string_lossy())?;
+    }
+    let old_dir = root::path(set_aside.unwrap_or(install_dir(&metadata.name, &old_version)));
+    if old_dir.is_dir() {
+        fs::remove_dir_all(&old_dir).context(format!("Failed to remove {}", old_dir.display()))?;
+    }
+    let paths: Vec<&String> = files
+        .iter()
+        .chain(&old_files)
+        .map(|(path, _)| path)
+        .collect();
+    desktop::refresh_caches(&paths);
+    let old_paths: Vec<&String> = old_files.iter().map(|(path, _)| path).collect();
+    systemd::apply(&staged.meta_file, &systemd::installed_units(&old_paths));
+
+    let envs = [
+        ("LPKG_OLD_VERSION", old_version.as_str()),
+        ("LPKG_NEW_VERSION", metadata.version.as_str()),
+    ];
+    staged.run_script(
+        metadata
+            .scripts
+            .as_ref()
+            .and_then(|s| s.post_upgrade.as_ref()),
+        "post-upgrade",
+        &envs,
+    )?;
+    match compare_versions(&metadata.version, &old_version) {
+        Ordering::Equal => println!("Reinstalled {} {}", metadata.name, metadata.version),
+        order => println!(
+            "{} {} from {} to {}",
+            if order == Ordering::Less {
+                "Downgraded"
+            } else {
+                "Upgraded"
+            },
+            metadata.name,
+            old_version,
+            metadata.version
+        ),
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::testing::pack;
+    use crate::db::connection::open_in_memory;
+    use crate::db::operations::get_package_files_by_id;
+    use std::os::unix::fs::MetadataExt;
+
+    fn command_target(name: &str) -> PathBuf {
+        fs::read_link(root::path(BIN_DIR).join(name)).unwrap()
+    }
+
+    #[test]
+    fn test_replacing_package_takes_over_only_once_installed() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let packages = tempfile::tempdir().unwrap();
+        let legacy = pack(
+            packages.path(),
+            "legacy",
+            "1.0.0",
+            "",
+            &[("usr/bin/legacy", "#!/bin/sh\n"), ("data", "legacy")],
+        );
+        install(&mut conn, &legacy, &[]).unwrap();
+        let legacy_data = root::path(install_dir("legacy", "1.0.0")).join("data");
+
+        // The successor ships the legacy command too, but one of its files
+        // cannot be put in place
+        let successor = pack(
+            packages.path(),
+            "successor",
+            "2.0.0",
+            "replaces = [\"legacy\"]\n\n[[bin]]\nname = \"legacy\"\npath = \"usr/bin/successor\"\n",
+            &[("usr/bin/successor", "#!/bin/sh\n"), ("data", "successor")],
+        );
+        let blocked = root::path(install_dir("successor", "2.0.0")).join("data");
+        fs::create_dir_all(blocked.join("in-the-way")).unwrap();
+        assert!(install(&mut conn, &successor, &[]).is_err());
+
+        // Nothing of the legacy package was removed
+        let (legacy_id, _, _) = get_package_data(&conn, "legacy").unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&legacy_data).unwrap(), "legacy");
+        assert!(command_target("legacy").starts_with(install_dir("legacy", "1.0.0")));
+        assert!(
+            get_package_files_by_id(&conn, legacy_id)
+                .unwrap()
+                .contains(&"/usr/local/bin/legacy".to_string())
+        );
+
+        // Nor was the successor recorded, or its files left behind
+        assert!(get_package_data(&conn, "successor").unwrap().is_none());
+        assert!(!root::path(install_dir("successor", "2.0.0")).exists());
+
+        // Once the successor installs, the command is handed over to it and
+        // the legacy package is removed
+        install(&mut conn, &successor, &[]).unwrap();
+        assert!(get_package_data(&conn, "legacy").unwrap().is_none());
+        assert!(
+            get_package_files_by_id(&conn, legacy_id)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(!legacy_data.exists());
+        assert!(command_target("legacy").starts_with(install_dir("successor", "2.0.0")));
+        assert_eq!(
+            get_file_owner(&conn, "/usr/local/bin/legacy").unwrap(),
+            Some("successor".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unmanaged_shared_file_is_not_overwritten() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let packages = tempfile::tempdir().unwrap();
+        let entry = root::path("/usr/local/share/applications/handmade.desktop");
+        fs::create_dir_all(entry.parent().unwrap()).unwrap();
+        fs::write(&entry, "written by the admin").unwrap();
+
+        let file = pack(
+            packages.path(),
+            "handmade",
+            "1.0.0",
+            "",
+            &[("usr/share/applications/handmade.desktop", "packaged")],
+        );
+        let error = install(&mut conn, &file, &[]).unwrap_err();
+        assert!(error.to_string().contains("not installed by lpkg"));
+        assert_eq!(fs::read_to_string(&entry).unwrap(), "written by the admin");
+        assert!(get_package_data(&conn, "handmade").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_upgrade_reuses_unchanged_files_and_removes_dropped_ones() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let packages = tempfile::tempdir().unwrap();
+        let old = pack(
+            packages.path(),
+            "keeper",
+            "1.0.0",
+            "",
+            &[("kept", "same"), ("dropped", "old only")],
+        );
+        let new = pack(
+            packages.path(),
+            "keeper",
+            "1.1.0",
+            "",
+            &[("kept", "same"), ("added", "new only")],
+        );
+        install(&mut conn, &old, &[]).unwrap();
+        let (package_id, _, _) = get_package_data(&conn, "keeper").unwrap().unwrap();
+        let old_dir = root::path(install_dir("keeper", "1.0.0"));
+        let new_dir = root::path(install_dir("keeper", "1.1.0"));
+
+        // The identical file is linked into the new version's directory
+        let prepared =
+            prepare_upgrade(&conn, package_id, stage(&new).unwrap(), &HashMap::new()).unwrap();
+        let inode = |path: PathBuf| fs::metadata(path).unwrap().ino();
+        assert_eq!(inode(old_dir.join("kept")), inode(new_dir.join("kept")));
+        commit_upgrade(&mut conn, prepared).unwrap();
+
+        assert!(!old_dir.exists());
+        assert_eq!(fs::read_to_string(new_dir.join("kept")).unwrap(), "same");
+        assert_eq!(
+            fs::read_to_string(new_dir.join("added")).unwrap(),
+            "new only"
+        );
+        assert!(!new_dir.join("dropped").exists());
+        let mut files = get_package_files_by_id(&conn, package_id).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "/usr/local/lpkg/packages/keeper-1.1.0/added",
+                "/usr/local/lpkg/packages/keeper-1.1.0/kept",
+            ]
+        );
+    }
+}
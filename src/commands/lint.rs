@@ -0,0 +1,32 @@
+use crate::package::lint::lint as lint_metadata;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+// Lint the meta.toml of a package source directory
+pub fn lint(directory: &str) -> Result<()> {
+    check(Path::new(directory))?;
+    println!("No problems found in meta.toml.");
+    Ok(())
+}
+
+// Print every problem in the directory's meta.toml and fail if there are any
+pub fn check(directory: &Path) -> Result<()> {
+    let meta_path = directory.join("meta.toml");
+    let content = fs::read_to_string(&meta_path).context(format!(
+        "Failed to read meta.toml from {}",
+        meta_path.display()
+    ))?;
+    let diagnostics = lint_metadata(&content, &directory.join("scripts"));
+    if diagnostics.is_empty() {
+        return Ok(());
+    }
+    for diagnostic in &diagnostics {
+        eprintln!("{}:{}", meta_path.display(), diagnostic);
+    }
+    Err(anyhow::anyhow!(
+        "Found {} problem(s) in {}",
+        diagnostics.len(),
+        meta_path.display()
+    ))
+}
@@ -1,6 +1,14 @@
+pub mod audit;
+pub mod bundle;
+pub mod cache;
+pub mod generations;
+pub mod history;
+pub mod hold;
 pub mod info;
 pub mod install;
+pub mod lint;
 pub mod list;
+pub mod outdated;
 pub mod pack;
 pub mod remove;
 pub mod repo;
@@ -8,6 +16,10 @@ pub mod sign;
 pub mod verify;
 pub mod rollback;
 pub mod update;
+pub mod upgrade;
 pub mod init;
 pub mod init_handlers;
 pub mod setup;
+pub mod serve;
+#[cfg(test)]
+pub mod testing;
@@ -0,0 +1,142 @@
+use crate::commands::hold::load_pins;
+use crate::db::operations::{get_dependents, get_installed_packages};
+use crate::repository::compare_versions;
+use crate::repository::resolve::{self, Pins, Source};
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use semver::VersionReq;
+use serde::Serialize;
+use std::cmp::Ordering;
+
+#[derive(Debug, Serialize)]
+struct Outdated {
+    name: String,
+    installed: String,
+    latest: String,
+    // Newest version holds, pins and installed dependents allow, if any
+    allowed: Option<String>,
+    repository: String,
+}
+
+// Compare the installed packages against the cached repository indexes and
+// report the ones with a newer version available. Nothing is refreshed.
+pub fn outdated(conn: &Connection, json: bool) -> Result<()> {
+    let sources = resolve::load_sources()?;
+    let report = find_outdated(conn, &sources)?;
+
+    if json {
+        let output =
+            serde_json::to_string_pretty(&report).context("Failed to serialize outdated report")?;
+        println!("{}", output);
+        return Ok(());
+    }
+    if report.is_empty() {
+        println!("All packages are up to date.");
+        return Ok(());
+    }
+    for package in &report {
+        println!(
+            "{} {} -> {} (allowed: {}) [{}]",
+            package.name,
+            package.installed,
+            package.latest,
+            package.allowed.as_deref().unwrap_or("none"),
+            package.repository
+        );
+    }
+    println!("{} package(s) have newer versions.", report.len());
+    Ok(())
+}
+
+// The installed packages with a newer version in the given repositories, by
+// name
+fn find_outdated(conn: &Connection, sources: &[Source]) -> Result<Vec<Outdated>> {
+    let pins = load_pins(conn)?;
+    let mut report = Vec::new();
+    for (_, name, installed) in get_installed_packages(conn)? {
+        let Some(latest) = resolve::find_candidate(sources, &name, None, &Pins::new()) else {
+            continue;
+        };
+        if compare_versions(&latest.package.version, &installed) != Ordering::Greater {
+            continue;
+        }
+        let allowed = allowed_version(conn, sources, &name, &pins)?
+            .filter(|version| compare_versions(version, &installed) == Ordering::Greater);
+        report.push(Outdated {
+            name,
+            installed,
+            latest: latest.package.version.clone(),
+            allowed,
+            repository: latest.source.repo.name.clone(),
+        });
+    }
+    report.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(report)
+}
+
+// The newest version of a package that its hold or pin and the version
+// constraints of every installed dependent accept
+fn allowed_version(
+    conn: &Connection,
+    sources: &[Source],
+    name: &str,
+    pins: &Pins,
+) -> Result<Option<String>> {
+    let mut req = VersionReq::STAR;
+    for (dependent, _, constraint) in get_dependents(conn, name)? {
+        if let Some(constraint) = constraint {
+            let dependent_req = VersionReq::parse(&constraint).context(format!(
+                "Invalid version constraint '{}' recorded for {}",
+                constraint, dependent
+            ))?;
+            req.comparators.extend(dependent_req.comparators);
+        }
+    }
+    Ok(resolve::find_candidate(sources, name, Some(&req), pins)
+        .map(|candidate| candidate.package.version.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::hold::pin;
+    use crate::commands::install::install;
+    use crate::commands::testing::{pack, source};
+    use crate::db::connection::open_in_memory;
+    use crate::utils::root;
+
+    #[test]
+    fn test_outdated_reports_newest_and_allowed_versions() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let local = tempfile::tempdir().unwrap();
+        for name in ["stale", "current"] {
+            let file = pack(local.path(), name, "1.0.0", "", &[("data", "1")]);
+            install(&mut conn, &file, &[]).unwrap();
+        }
+        let depends = "\n[dependencies]\nstale = \"<1.3\"\n";
+        let file = pack(local.path(), "stale-app", "1.0.0", depends, &[("app", "1")]);
+        install(&mut conn, &file, &[]).unwrap();
+
+        let repo = tempfile::tempdir().unwrap();
+        for version in ["1.2.0", "1.4.0"] {
+            pack(repo.path(), "stale", version, "", &[("data", version)]);
+        }
+        pack(repo.path(), "current", "1.0.0", "", &[("data", "1")]);
+        let sources = vec![source(repo.path())];
+
+        // stale-app keeps stale below 1.3
+        let report = find_outdated(&conn, &sources).unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].name, "stale");
+        assert_eq!(report[0].installed, "1.0.0");
+        assert_eq!(report[0].latest, "1.4.0");
+        assert_eq!(report[0].allowed.as_deref(), Some("1.2.0"));
+        assert_eq!(report[0].repository, "test");
+
+        pin(&conn, "stale", "<1.2").unwrap();
+        let report = find_outdated(&conn, &sources).unwrap();
+        assert_eq!(report[0].latest, "1.4.0");
+        assert_eq!(report[0].allowed, None);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::commands::lint::check;
 use crate::package::archive::{create_archive, create_tar_and_checksum};
 use crate::package::metadata::{MetaFile, parse_metadata};
 use anyhow::{Context, Result};
@@ -27,6 +28,9 @@ pub fn pack() -> Result<()> {
         ));
     }
 
+    // Refuse to build from metadata with problems
+    check(dir_path)?;
+
     let files_dir = dir_path.join("files");
     if !files_dir.exists() || !files_dir.is_dir() {
         return Err(anyhow::anyhow!(
@@ -74,8 +78,10 @@ pub fn pack() -> Result<()> {
     let output_path = dir_path.join(&output_filename);
 
     // Create the .lpkg archive
+    let scripts_dir = dir_path.join("scripts");
     create_archive(
         &files_dir_abs,
+        scripts_dir.is_dir().then_some(scripts_dir.as_path()),
         &meta_path,
         output_path.to_str().unwrap_or(&output_filename),
     )
@@ -1,40 +1,164 @@
+use crate::commands::sign::load_key_pair;
+use crate::config::{self, Config, MirrorSelection, RepositoryAuth, RepositoryConfig};
+use crate::repository::advisories::{fetch_advisories, save_advisories};
+use crate::repository::builder;
+use crate::repository::http::HttpClient;
+use crate::repository::mirrors::Mirrors;
+use crate::repository::{
+    IndexFetch, RepositoryIndex, cache, fetch_index, index_cache_path, load_cached_index,
+    load_validators, package_builds, save_index, save_validators, search_package,
+};
+use crate::utils::platform;
 use anyhow::{Context, Result};
 use rusqlite::Connection;
-use crate::repository::{fetch_index, save_index, load_cached_index, search_package, download_package};
-use std::path::PathBuf;
+use std::fs;
+use std::path::Path;
 
-pub fn add_repo(_conn: &mut Connection, url: &str, name: &str) -> Result<()> {
+#[allow(clippy::too_many_arguments)]
+pub fn add_repo(
+    _conn: &mut Connection,
+    url: &str,
+    name: &str,
+    mirrors: &[String],
+    priority: i32,
+    fastest: bool,
+    proxy: Option<&str>,
+    ca_bundles: &[String],
+    auth_helper: Option<&str>,
+) -> Result<()> {
     println!("Adding repository: {} with name {}", url, name);
 
-    // In a real scenario, you might want to store repository info in the DB
-    // For now, we just fetch and cache the index.
-
-    let index = fetch_index(url).context(format!("Failed to fetch index from {}", url))?;
+    let repo = RepositoryConfig {
+        name: name.to_string(),
+        url: url.to_string(),
+        mirrors: mirrors.to_vec(),
+        mirror_selection: if fastest {
+            MirrorSelection::Fastest
+        } else {
+            MirrorSelection::Ordered
+        },
+        priority,
+        proxy: proxy.map(str::to_string),
+        ca_bundles: ca_bundles.to_vec(),
+        // Passwords and tokens are kept off the command line, where they would
+        // end up in the shell history and the process list
+        auth: auth_helper.map(|command| RepositoryAuth::Helper {
+            command: command.to_string(),
+        }),
+    };
 
-    let cache_dir = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
-    let cache_path = cache_dir.join(format!("lpkg_repo_{}.json", name));
-    save_index(&index, cache_path.to_str().unwrap_or_default())
-        .context(format!("Failed to cache repository index for {}", name))?;
+    // Make sure the repository is reachable before saving it
+    refresh_repository(&repo).context(format!("Failed to fetch index for {}", name))?;
+    repo.save().context(format!(
+        "Failed to save configuration for repository {}",
+        name
+    ))?;
 
     println!("Repository '{}' added successfully.", name);
     Ok(())
 }
 
+pub fn refresh_repos(repo_name: Option<&str>) -> Result<()> {
+    let repositories = match repo_name {
+        Some(name) => vec![config::load_repository(name)?.context(format!(
+            "Repository '{}' not found. Please add it first with 'lpkg repo add'.",
+            name
+        ))?],
+        None => config::load_repositories()?,
+    };
+
+    if repositories.is_empty() {
+        println!("No repositories configured. Add one with 'lpkg repo add'.");
+        return Ok(());
+    }
+
+    let mut failures = 0;
+    for repo in &repositories {
+        if let Err(e) = refresh_repository(repo) {
+            eprintln!(
+                "Error: failed to refresh repository '{}': {:#}",
+                repo.name, e
+            );
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} repositories could not be refreshed",
+            failures,
+            repositories.len()
+        ));
+    }
+    Ok(())
+}
+
+// Fetch a repository's index from the first working mirror and cache it.
+// An index the server reports as unchanged is not downloaded again.
+fn refresh_repository(repo: &RepositoryConfig) -> Result<()> {
+    let cache_path = index_cache_path(&repo.name);
+    let cached = load_cached_index(cache_path.to_str().unwrap_or_default()).unwrap_or(None);
+    let client = HttpClient::for_repository(&Config::load()?, repo)?;
+    let mirrors = Mirrors::for_repository(repo, cached.as_ref(), &client);
+    // Validators are only useful while the cached index they describe exists
+    let previous = cached.as_ref().and_then(|_| load_validators(&repo.name));
+
+    let (fetch, mirror) = fetch_index(&mirrors, previous.as_ref(), &client)?;
+    match fetch {
+        IndexFetch::NotModified => {
+            println!(
+                "{}: index unchanged ({} package(s)), checked on {}",
+                repo.name,
+                cached.map(|index| index.packages.len()).unwrap_or_default(),
+                mirror
+            );
+        }
+        IndexFetch::Modified(index, validators) => {
+            save_index(&index, cache_path.to_str().unwrap_or_default()).context(format!(
+                "Failed to cache repository index for {}",
+                repo.name
+            ))?;
+            save_validators(&repo.name, &validators)?;
+            println!(
+                "{}: {} package(s), index served by {}",
+                repo.name,
+                index.packages.len(),
+                validators.url
+            );
+        }
+    }
+
+    // Advisories are optional, a repository without them still refreshes
+    match fetch_advisories(&mirrors, &client) {
+        Ok(feed) => {
+            if let Some(feed) = &feed {
+                let count: usize = feed.advisories.values().map(Vec::len).sum();
+                println!("{}: {} advisories", repo.name, count);
+            }
+            save_advisories(&repo.name, feed.as_ref())?;
+        }
+        Err(e) => eprintln!(
+            "Warning: failed to fetch advisories for '{}': {:#}",
+            repo.name, e
+        ),
+    }
+    Ok(())
+}
+
 pub fn search_repo(_conn: &Connection, package_name: &str, repo_name: Option<&str>) -> Result<()> {
     println!(
         "Searching for package: {} in repository: {:?}",
         package_name, repo_name
     );
 
-    let cache_dir = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
     let repo_name = repo_name.unwrap_or("default"); // Assumes a default repo if not specified
-    let cache_path = cache_dir.join(format!("lpkg_repo_{}.json", repo_name));
+    let cache_path = index_cache_path(repo_name);
 
     if let Some(index) = load_cached_index(cache_path.to_str().unwrap_or_default())? {
         if let Some(package) = search_package(&index, package_name) {
             println!("Found package: {} ({}) - {}", package.name, package.version, package.description.as_deref().unwrap_or_default());
         } else {
-            println!("Package '{}' not found in repository '{}'.", package_name, repo_name);
+            report_not_found(&index, package_name, repo_name);
         }
     } else {
         println!("Repository '{}' not found. Please add it first with 'lpkg repo add'.", repo_name);
@@ -43,30 +167,148 @@ pub fn search_repo(_conn: &Connection, package_name: &str, repo_name: Option<&st
     Ok(())
 }
 
-pub fn install_from_repo(conn: &mut Connection, package_name: &str, repo_name: Option<&str>) -> Result<()> {
+pub fn install_from_repo(
+    conn: &mut Connection,
+    package_name: &str,
+    repo_name: Option<&str>,
+    features: &[String],
+) -> Result<()> {
     println!(
         "Installing package: {} from repository: {:?}",
         package_name, repo_name
     );
 
-    let cache_dir = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
     let repo_name = repo_name.unwrap_or("default"); // Assumes a default repo if not specified
-    let cache_path = cache_dir.join(format!("lpkg_repo_{}.json", repo_name));
+    let cache_path = index_cache_path(repo_name);
+
+    let repo = match config::load_repository(repo_name)? {
+        Some(repo) => repo,
+        None => {
+            println!(
+                "Repository '{}' not found. Please add it first with 'lpkg repo add'.",
+                repo_name
+            );
+            return Ok(());
+        }
+    };
 
     if let Some(index) = load_cached_index(cache_path.to_str().unwrap_or_default())? {
         if let Some(package) = search_package(&index, package_name) {
-            let download_dir = dirs::download_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
-            let destination = download_dir.join(format!("{}-{}.lpkg", package.name, package.version));
-            download_package(package, destination.to_str().unwrap_or_default())?;
-            // After downloading, you would typically call the local install command
-            crate::commands::install::install(conn, destination.to_str().unwrap_or_default())?;
+            let client = HttpClient::for_repository(&Config::load()?, &repo)?;
+            let mirrors = Mirrors::for_repository(&repo, Some(&index), &client);
+            let destination = cache::fetch_package(package, &mirrors, &client)?;
+            crate::commands::install::install(
+                conn,
+                destination.to_str().unwrap_or_default(),
+                features,
+            )?;
             println!("Package installed from repository.");
         } else {
-            println!("Package '{}' not found in repository '{}'.", package_name, repo_name);
+            report_not_found(&index, package_name, repo_name);
         }
     } else {
-        println!("Repository '{}' not found. Please add it first with 'lpkg repo add'.", repo_name);
+        println!(
+            "Repository '{}' has no cached index. Run 'lpkg repo refresh' first.",
+            repo_name
+        );
     }
 
     Ok(())
 }
+
+// Explain why a package cannot be picked from an index: it is missing, or
+// only built for other platforms
+fn report_not_found(index: &RepositoryIndex, package_name: &str, repo_name: &str) {
+    if package_builds(index, package_name).is_empty() {
+        println!(
+            "Package '{}' not found in repository '{}'.",
+            package_name, repo_name
+        );
+    } else {
+        println!(
+            "Repository '{}' has no build of '{}' for this system ({}).",
+            repo_name,
+            package_name,
+            platform::host()
+        );
+    }
+}
+
+pub fn build_index(repo_dir: &str, sign_key: Option<&str>, compress: bool) -> Result<()> {
+    let dir = Path::new(repo_dir);
+    if !dir.is_dir() {
+        return Err(anyhow::anyhow!("'{}' is not a directory", repo_dir));
+    }
+    let index_path = dir.join("index.json");
+    println!("Building repository index for {}", dir.display());
+
+    // Reuse the existing index so unchanged packages are not read and hashed again
+    let previous = load_cached_index(index_path.to_str().unwrap_or_default()).context(format!(
+        "Failed to read existing index {}",
+        index_path.display()
+    ))?;
+
+    let stamps = builder::load_stamps(dir);
+    let (index, stamps, report) = builder::build_index(dir, previous, &stamps)?;
+
+    let index_json =
+        serde_json::to_vec_pretty(&index).context("Failed to serialize repository index")?;
+    write_atomically(&index_path, &index_json)?;
+    let stamps_json = serde_json::to_vec(&stamps).context("Failed to serialize index stamps")?;
+    write_atomically(&dir.join(builder::STAMPS_FILE), &stamps_json)?;
+
+    // Variants and signatures left from a previous build would no longer
+    // match. Clients try the compressed variants first, so every one that is
+    // not written again has to go.
+    let zst_path = dir.join("index.json.zst");
+    if compress {
+        let compressed = zstd::stream::encode_all(&index_json[..], 19)
+            .context("Failed to compress repository index")?;
+        write_atomically(&zst_path, &compressed)?;
+    } else {
+        remove_stale(&zst_path)?;
+    }
+    remove_stale(&dir.join("index.json.gz"))?;
+
+    let sig_path = dir.join("index.json.sig");
+    if let Some(key_path) = sign_key {
+        let key_pair = load_key_pair(key_path)?;
+        let signature = key_pair.sign(&index_json);
+        write_atomically(&sig_path, signature.as_ref())?;
+        println!("Index signed: {}", sig_path.display());
+    } else {
+        remove_stale(&sig_path)?;
+    }
+
+    for package in &report.added {
+        println!("  + {}", package);
+    }
+    for package in &report.removed {
+        println!("  - {}", package);
+    }
+    println!(
+        "Wrote {} with {} package(s): {} added, {} removed, {} unchanged.",
+        index_path.display(),
+        index.packages.len(),
+        report.added.len(),
+        report.removed.len(),
+        report.unchanged
+    );
+    Ok(())
+}
+
+// Replace a published file through a temporary file and a rename, so a
+// server never sends it half written
+fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    fs::write(&temp_path, content).context(format!("Failed to write {}", temp_path.display()))?;
+    fs::rename(&temp_path, path).context(format!("Failed to write {}", path.display()))
+}
+
+fn remove_stale(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path).context(format!("Failed to remove stale {}", path.display()))?;
+    }
+    Ok(())
+}
@@ -1,38 +1,103 @@
+use crate::commands::install::{check_pin, commit_upgrade, prepare_upgrade, stage};
+use crate::commands::upgrade::check_dependents;
+use crate::db::operations::{get_package_data, get_previous_version, remove_previous_version};
+use crate::utils::checksum::verify_checksum;
+use crate::utils::root;
 use anyhow::{Context, Result};
-use crate::db::operations::{get_package_files_by_id, remove_package_by_id};
 use rusqlite::Connection;
-use std::fs;
-use std::path::Path;
+use std::collections::HashMap;
 
-pub fn rollback(conn: &mut Connection, package_id: i64) -> Result<()> {
-    println!("Attempting to rollback package with ID: {}", package_id);
+// Restore the version of a package that was installed before the last
+// upgrade or downgrade, from the copy kept in the package cache. The version
+// is replaced in place like an upgrade, so files, the command link and the
+// database record all move back together, and no network access is needed.
+pub fn rollback(conn: &mut Connection, package_name: &str) -> Result<()> {
+    let (package_id, current_version, _) = get_package_data(conn, package_name)?
+        .ok_or_else(|| anyhow::anyhow!("Package '{}' is not installed.", package_name))?;
+    let (previous_id, previous_version, lpkg_path) = get_previous_version(conn, package_name)?
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "No previous version of '{}' is available to roll back to.",
+                package_name
+            )
+        })?;
+    println!(
+        "Rolling back {} from {} to {}",
+        package_name, current_version, previous_version
+    );
 
-    let files_to_remove = get_package_files_by_id(conn, package_id)
-        .context(format!("Failed to get files for package ID {}", package_id))?;
-
-    if files_to_remove.is_empty() {
-        println!("No files found for package ID {}. Already rolled back or never installed.", package_id);
-        return Ok(());
+    // Cached packages are named after their checksum
+    let path = root::path(&lpkg_path);
+    let file = path.to_str().unwrap_or_default();
+    if !path.is_file() {
+        return Err(anyhow::anyhow!(
+            "The cached package for {} {} is missing ({}).",
+            package_name,
+            previous_version,
+            lpkg_path
+        ));
     }
-
-    for file_path in &files_to_remove {
-        let path = Path::new(file_path);
-        if path.exists() {
-            if path.is_file() {
-                fs::remove_file(path).context(format!("Failed to remove file: {}", file_path))?;
-            } else if path.is_dir() {
-                // Only remove empty directories, or handle recursively if needed
-                // For now, we'll just try to remove files and let the user clean up empty dirs
-                println!("Skipping directory: {} (manual cleanup might be required)", file_path);
-            }
-        }
-        println!("Removed: {}", file_path);
+    if let Some(expected) = path.file_stem().and_then(|s| s.to_str())
+        && !verify_checksum(file, expected)?
+    {
+        return Err(anyhow::anyhow!(
+            "The cached package for {} {} is corrupt ({}).",
+            package_name,
+            previous_version,
+            lpkg_path
+        ));
     }
 
-    remove_package_by_id(conn, package_id)
-        .context(format!("Failed to remove package with ID {} from database", package_id))?;
+    check_pin(conn, package_name, &previous_version)?;
+    check_dependents(conn, package_name, &previous_version, &[])?;
 
-    println!("Rollback successful for package ID {}.", package_id);
+    let staged = stage(file)?;
+    let prepared = prepare_upgrade(conn, package_id, staged, &HashMap::new())?;
+    commit_upgrade(conn, prepared).context(format!("Failed to roll back '{}'", package_name))?;
+    remove_previous_version(conn, previous_id)?;
 
+    println!(
+        "Rolled back {} to version {}.",
+        package_name, previous_version
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::install::{install, install_dir};
+    use crate::commands::testing::pack;
+    use crate::db::connection::open_in_memory;
+    use std::fs;
+
+    #[test]
+    fn test_rollback_restores_the_previous_version_from_the_cache() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let local = tempfile::tempdir().unwrap();
+        for version in ["1.0.0", "1.1.0"] {
+            let file = pack(local.path(), "retro", version, "", &[("data", version)]);
+            install(&mut conn, &file, &[]).unwrap();
+        }
+        // The archives are gone; rollback works from the package cache
+        drop(local);
+
+        rollback(&mut conn, "retro").unwrap();
+        let (_, version, _) = get_package_data(&conn, "retro").unwrap().unwrap();
+        assert_eq!(version, "1.0.0");
+        let data = root::path(install_dir("retro", "1.0.0")).join("data");
+        assert_eq!(fs::read_to_string(data).unwrap(), "1.0.0");
+        assert!(!root::path(install_dir("retro", "1.1.0")).exists());
+
+        // The version rolled back from takes its place, so the rollback can
+        // itself be rolled back
+        let (_, previous, _) = get_previous_version(&conn, "retro").unwrap().unwrap();
+        assert_eq!(previous, "1.1.0");
+        rollback(&mut conn, "retro").unwrap();
+        let (_, version, _) = get_package_data(&conn, "retro").unwrap().unwrap();
+        assert_eq!(version, "1.1.0");
+        let (_, previous, _) = get_previous_version(&conn, "retro").unwrap().unwrap();
+        assert_eq!(previous, "1.0.0");
+    }
+}
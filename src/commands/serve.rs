@@ -0,0 +1,517 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Serve a repository directory over HTTP so index refreshes and package
+// downloads can be exercised against localhost or shared on a LAN.
+pub fn serve(dir: &str, bind: &str) -> Result<()> {
+    let root = Path::new(dir)
+        .canonicalize()
+        .context(format!("Failed to resolve repository directory {}", dir))?;
+    if !root.is_dir() {
+        return Err(anyhow::anyhow!("'{}' is not a directory", dir));
+    }
+
+    let listener = TcpListener::bind(bind).context(format!("Failed to bind to {}", bind))?;
+    println!(
+        "Serving {} on http://{}/ (index at http://{}/index.json)",
+        root.display(),
+        bind,
+        bind
+    );
+    serve_connections(listener, root);
+    Ok(())
+}
+
+// Answer every connection to `listener` from its own thread
+fn serve_connections(listener: TcpListener, root: PathBuf) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Warning: failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let root = root.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &root) {
+                eprintln!("Warning: {:#}", e);
+            }
+        });
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn handle_connection(stream: TcpStream, root: &Path) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let request = match read_request(&mut reader)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let status = respond(&request, root, &mut writer)?;
+    println!("{} {} {}", request.method, request.path, status);
+    Ok(())
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok(Some(Request {
+        method,
+        path,
+        headers,
+    }))
+}
+
+fn respond<W: Write>(request: &Request, root: &Path, writer: &mut W) -> Result<u16> {
+    if request.method != "GET" && request.method != "HEAD" {
+        write_head(
+            writer,
+            405,
+            "Method Not Allowed",
+            &[("Allow", "GET, HEAD".into())],
+            0,
+        )?;
+        return Ok(405);
+    }
+
+    let file_path = match resolve_path(root, &request.path) {
+        Some(path) if path.is_file() => path,
+        _ => {
+            let body = b"Not Found\n";
+            write_head(writer, 404, "Not Found", &[], body.len() as u64)?;
+            if request.method == "GET" {
+                writer.write_all(body)?;
+            }
+            return Ok(404);
+        }
+    };
+
+    let metadata = fs::metadata(&file_path)?;
+    let size = metadata.len();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    // A file rebuilt within the same second, usually at the same size, is
+    // told apart by its inode and the nanoseconds
+    let etag = format!(
+        "\"{:x}-{:x}-{:x}.{:x}\"",
+        size,
+        metadata.ino(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    );
+    let last_modified = http_date(modified.as_secs());
+
+    let mut headers = vec![
+        ("ETag", etag.clone()),
+        ("Last-Modified", last_modified.clone()),
+        ("Accept-Ranges", "bytes".to_string()),
+        ("Content-Type", content_type(&file_path).to_string()),
+    ];
+
+    let not_modified = match request.header("If-None-Match") {
+        Some(tags) => tags
+            .split(',')
+            .any(|tag| tag.trim() == etag || tag.trim() == "*"),
+        None => request.header("If-Modified-Since") == Some(last_modified.as_str()),
+    };
+    if not_modified {
+        write_head(writer, 304, "Not Modified", &headers, 0)?;
+        return Ok(304);
+    }
+
+    let (status, start, length) = match request.header("Range") {
+        Some(range) => match parse_range(range, size) {
+            Some((start, end)) => {
+                headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end, size)));
+                (206, start, end - start + 1)
+            }
+            None => {
+                headers.push(("Content-Range", format!("bytes */{}", size)));
+                write_head(writer, 416, "Range Not Satisfiable", &headers, 0)?;
+                return Ok(416);
+            }
+        },
+        None => (200, 0, size),
+    };
+
+    let reason = if status == 206 {
+        "Partial Content"
+    } else {
+        "OK"
+    };
+    write_head(writer, status, reason, &headers, length)?;
+    if request.method == "GET" {
+        let mut file = File::open(&file_path)?;
+        file.seek(SeekFrom::Start(start))?;
+        std::io::copy(&mut file.take(length), writer)
+            .context(format!("Failed to send {}", file_path.display()))?;
+    }
+    Ok(status)
+}
+
+fn write_head<W: Write>(
+    writer: &mut W,
+    status: u16,
+    reason: &str,
+    headers: &[(&str, String)],
+    content_length: u64,
+) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    for (key, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", content_length));
+    head.push_str("Connection: close\r\n\r\n");
+    writer.write_all(head.as_bytes())?;
+    Ok(())
+}
+
+// Map a request path onto the repository directory, refusing anything that
+// would escape it, also through a symlink. Paths that do not exist resolve
+// to nothing.
+fn resolve_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let path = request_path.split(['?', '#']).next().unwrap_or_default();
+    let decoded = percent_decode(path)?;
+    let relative = Path::new(decoded.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let root = root.canonicalize().ok()?;
+    let path = root.join(relative).canonicalize().ok()?;
+    path.starts_with(&root).then_some(path)
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Parse a single `bytes=` range into inclusive start and end offsets.
+fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') || size == 0 {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = if start.is_empty() {
+        // Suffix range: the last N bytes
+        let suffix: u64 = end.parse().ok()?;
+        (size.saturating_sub(suffix), size - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            size - 1
+        } else {
+            end.parse::<u64>().ok()?.min(size - 1)
+        };
+        (start, end)
+    };
+    (start <= end && start < size).then_some((start, end))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => "application/json",
+        Some("zst") => "application/zstd",
+        Some("gz") => "application/gzip",
+        Some("toml") => "application/toml",
+        _ => "application/octet-stream",
+    }
+}
+
+// Format a Unix timestamp as an RFC 7231 date, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+fn http_date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = secs / 86400;
+    let rem = secs % 86400;
+
+    // Civil-from-days conversion for the proleptic Gregorian calendar
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, MirrorSelection, RepositoryConfig};
+    use crate::package::archive::create_archive;
+    use crate::repository::http::HttpClient;
+    use crate::repository::mirrors::Mirrors;
+    use crate::repository::{
+        IndexFetch, RepositoryIndex, download::download_file, download_package, fetch_index,
+        package_builds,
+    };
+
+    // Pack a package with a payload of `size` bytes into `repo`
+    fn add_package(repo: &Path, name: &str, size: usize) -> Vec<u8> {
+        let source = tempfile::tempdir().unwrap();
+        fs::create_dir(source.path().join("files")).unwrap();
+        // Pseudo-random bytes, so the archive is about as large as the payload
+        let mut state: u32 = 0x2545_f491;
+        let payload: Vec<u8> = (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        fs::write(source.path().join("files/data"), &payload).unwrap();
+        let meta = source.path().join("meta.toml");
+        fs::write(
+            &meta,
+            format!("[package]\nname = \"{}\"\nversion = \"1.0.0\"\n", name),
+        )
+        .unwrap();
+        let file = repo.join(format!("{}-1.0.0.lpkg", name));
+        create_archive(
+            &source.path().join("files"),
+            None,
+            &meta,
+            file.to_str().unwrap(),
+        )
+        .unwrap();
+        fs::read(file).unwrap()
+    }
+
+    fn fetched(fetch: IndexFetch) -> Option<RepositoryIndex> {
+        match fetch {
+            IndexFetch::Modified(index, _) => Some(index),
+            IndexFetch::NotModified => None,
+        }
+    }
+
+    #[test]
+    fn test_fetch_and_download_from_localhost() {
+        let repo = tempfile::tempdir().unwrap();
+        let archive = add_package(repo.path(), "foo", 200_000);
+        let repo_dir = repo.path().to_str().unwrap();
+        crate::commands::repo::build_index(repo_dir, None, false).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/index.json", listener.local_addr().unwrap());
+        let root = repo.path().to_path_buf();
+        thread::spawn(move || serve_connections(listener, root));
+
+        let mut config = Config::default();
+        config.download.retries = 0;
+        let repo_config = RepositoryConfig {
+            name: "local".to_string(),
+            url: url.clone(),
+            mirrors: Vec::new(),
+            mirror_selection: MirrorSelection::Ordered,
+            priority: 50,
+            proxy: None,
+            ca_bundles: Vec::new(),
+            auth: None,
+        };
+        let client = HttpClient::for_repository(&config, &repo_config).unwrap();
+        let mirrors = Mirrors {
+            primary: url.clone(),
+            urls: vec![url.clone()],
+        };
+
+        // The first fetch falls back from the compressed variants to the JSON
+        // file, a repeated one is answered with 304 through its ETag
+        let (fetch, _) = fetch_index(&mirrors, None, &client).unwrap();
+        let IndexFetch::Modified(index, validators) = fetch else {
+            panic!("expected a new index");
+        };
+        assert_eq!(validators.url, url);
+        assert!(validators.etag.is_some());
+        let (fetch, _) = fetch_index(&mirrors, Some(&validators), &client).unwrap();
+        assert!(fetched(fetch).is_none());
+
+        // A rebuilt index is fetched again, and not a stale variant left from
+        // an earlier build
+        add_package(repo.path(), "bar", 1_000);
+        fs::write(repo.path().join("index.json.gz"), "stale").unwrap();
+        crate::commands::repo::build_index(repo_dir, None, false).unwrap();
+        assert!(!repo.path().join("index.json.gz").exists());
+        let (fetch, _) = fetch_index(&mirrors, Some(&validators), &client).unwrap();
+        let rebuilt = fetched(fetch).expect("expected the rebuilt index");
+        assert_eq!(rebuilt.packages.len(), 2);
+
+        // An interrupted download resumes from its .part file with a Range
+        // request, and the result is verified against the index
+        let package = &package_builds(&index, "foo")[0];
+        let downloads = tempfile::tempdir().unwrap();
+        let destination = downloads.path().join("foo.lpkg");
+        fs::write(downloads.path().join("foo.lpkg.part"), &archive[..50_000]).unwrap();
+        download_package(package, &mirrors, destination.to_str().unwrap(), &client).unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), archive);
+
+        // Only the missing bytes are sent: a marked prefix is kept as it is
+        let package_url = mirrors.package_url(&url, &package.url);
+        let marked = downloads.path().join("marked.lpkg");
+        fs::write(downloads.path().join("marked.lpkg.part"), vec![0; 1_000]).unwrap();
+        download_file(&package_url, &marked, None, &client).unwrap();
+        let content = fs::read(&marked).unwrap();
+        assert_eq!(content[..1_000], [0; 1_000]);
+        assert_eq!(content[1_000..], archive[1_000..]);
+
+        // A complete .part file is answered with 416 and kept, even without
+        // retries and with the size taken from the Content-Range header
+        let complete = downloads.path().join("complete.lpkg");
+        fs::write(downloads.path().join("complete.lpkg.part"), &archive).unwrap();
+        download_file(&package_url, &complete, None, &client).unwrap();
+        assert_eq!(fs::read(&complete).unwrap(), archive);
+
+        // A .part file longer than the remote file is downloaded again
+        let stale = downloads.path().join("stale.lpkg");
+        let mut longer = archive.clone();
+        longer.extend_from_slice(&[0; 100]);
+        fs::write(downloads.path().join("stale.lpkg.part"), &longer).unwrap();
+        download_file(&package_url, &stale, package.size, &client).unwrap();
+        assert_eq!(fs::read(&stale).unwrap(), archive);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=500-", 1000), Some((500, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+    }
+
+    #[test]
+    fn test_resolve_path_rejects_traversal() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("pkgs")).unwrap();
+        fs::write(root.join("pkgs/foo 1.lpkg"), "").unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("pkgs/etc")).unwrap();
+        std::os::unix::fs::symlink("foo 1.lpkg", root.join("pkgs/foo.lpkg")).unwrap();
+        assert_eq!(
+            resolve_path(&root, "/pkgs/foo%201.lpkg?x=1"),
+            Some(root.join("pkgs/foo 1.lpkg"))
+        );
+        assert_eq!(
+            resolve_path(&root, "/pkgs/foo.lpkg"),
+            Some(root.join("pkgs/foo 1.lpkg"))
+        );
+        assert_eq!(resolve_path(&root, "/../etc/passwd"), None);
+        assert_eq!(resolve_path(&root, "/%2e%2e/etc/passwd"), None);
+        assert_eq!(resolve_path(&root, "/pkgs/etc/passwd"), None);
+        assert_eq!(resolve_path(&root, "/pkgs/missing.lpkg"), None);
+    }
+
+    #[test]
+    fn test_etag_changes_when_a_file_is_replaced_at_the_same_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let etag = || {
+            let request = Request {
+                method: "HEAD".to_string(),
+                path: "/index.json".to_string(),
+                headers: Vec::new(),
+            };
+            let mut response = Vec::new();
+            respond(&request, dir.path(), &mut response).unwrap();
+            String::from_utf8(response)
+                .unwrap()
+                .lines()
+                .find_map(|line| line.strip_prefix("ETag: ").map(str::to_string))
+                .unwrap()
+        };
+        fs::write(dir.path().join("index.json"), "first").unwrap();
+        let first = etag();
+        // Written and renamed into place like build-index does, right away
+        fs::write(dir.path().join("index.json.tmp"), "again").unwrap();
+        fs::rename(
+            dir.path().join("index.json.tmp"),
+            dir.path().join("index.json"),
+        )
+        .unwrap();
+        assert_ne!(etag(), first);
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
@@ -1,9 +1,23 @@
 use anyhow::{Context, Result};
-use ring::rand::SystemRandom;
 use ring::signature::Ed25519KeyPair;
 use std::fs;
 use std::path::Path;
 
+// Load an Ed25519 key pair from a file holding the raw 32-byte secret seed.
+pub fn load_key_pair(key_path: &str) -> Result<Ed25519KeyPair> {
+    let key_content =
+        fs::read(key_path).context(format!("Failed to read secret key from {}", key_path))?;
+
+    // The ring crate uses the raw 32-byte seed for Ed25519.
+    if key_content.len() != 32 {
+        return Err(anyhow::anyhow!(
+            "Invalid secret key length: Ed25519 seeds must be 32 bytes."
+        ));
+    }
+    Ed25519KeyPair::from_seed_unchecked(&key_content)
+        .map_err(|e| anyhow::anyhow!("Failed to create key pair from seed: {:?}", e))
+}
+
 pub fn sign(package_path: &str, key_path: &str, comment: Option<&str>) -> Result<()> {
     println!("Signing package: {}", package_path);
 
@@ -15,20 +29,7 @@ pub fn sign(package_path: &str, key_path: &str, comment: Option<&str>) -> Result
         ));
     }
 
-    // Read the secret key from the provided key file
-    let key_content =
-        fs::read(key_path).context(format!("Failed to read secret key from {}", key_path))?;
-
-    // Parse the key pair from the secret key seed.
-    // The ring crate uses the raw 32-byte seed for Ed25519.
-    if key_content.len() != 32 {
-        return Err(anyhow::anyhow!(
-            "Invalid secret key length: Ed25519 seeds must be 32 bytes."
-        ));
-    }
-    let _rng = SystemRandom::new();
-    let key_pair = Ed25519KeyPair::from_seed_unchecked(&key_content)
-        .map_err(|e| anyhow::anyhow!("Failed to create key pair from seed: {:?}", e))?;
+    let key_pair = load_key_pair(key_path)?;
 
     // Read the package file content to sign
     let package_data =
@@ -0,0 +1,52 @@
+// Helpers for the tests of commands that install packages. They install into
+// the shared test root (see root::set_for_tests), so each test uses package
+// names of its own.
+use crate::package::archive::create_archive;
+use crate::repository::builder::{self, Stamps};
+use crate::repository::resolve::Source;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// Pack `name` `version` into `dir` as <name>-<version>.lpkg, from (path,
+// content) files. `meta` is appended to the [package] table and may open
+// further tables. Files under usr/bin/ are executable.
+pub fn pack(dir: &Path, name: &str, version: &str, meta: &str, files: &[(&str, &str)]) -> String {
+    let source = tempfile::tempdir().unwrap();
+    let files_dir = source.path().join("files");
+    fs::create_dir(&files_dir).unwrap();
+    for (path, content) in files {
+        let path = files_dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        if path.starts_with(files_dir.join("usr/bin")) {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+    let meta_path = source.path().join("meta.toml");
+    fs::write(
+        &meta_path,
+        format!(
+            "[package]\nname = \"{}\"\nversion = \"{}\"\n{}",
+            name, version, meta
+        ),
+    )
+    .unwrap();
+    let file = dir.join(format!("{}-{}.lpkg", name, version));
+    let file = file.to_str().unwrap().to_string();
+    create_archive(&files_dir, None, &meta_path, &file).unwrap();
+    file
+}
+
+// A repository of the packages in `dir`, as the resolver sees it
+pub fn source(dir: &Path) -> Source {
+    let (index, _, _) = builder::build_index(dir, None, &Stamps::new()).unwrap();
+    Source {
+        repo: toml::from_str(&format!(
+            "name = \"test\"\nurl = \"file://{}/index.json\"",
+            dir.display()
+        ))
+        .unwrap(),
+        index,
+    }
+}
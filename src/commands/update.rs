@@ -1,91 +1,158 @@
+use crate::commands::hold::load_pins;
+use crate::commands::repo::refresh_repos;
+use crate::commands::upgrade;
+use crate::db::operations::get_package_data;
+use crate::repository::resolve::{self, Features, Pins, Source};
 use anyhow::{Context, Result};
 use rusqlite::Connection;
-use crate::db::operations::{get_package_data, remove_package_by_id};
-use crate::commands::install::install;
-use crate::repository::{search_package, fetch_index};
-use std::fs;
-use std::path::PathBuf;
+use semver::Version;
+use std::cmp::Ordering;
 
-pub fn update(conn: &mut Connection, package_name: &str) -> Result<()> {
+pub fn update(conn: &mut Connection, package_name: &str, allow_downgrade: bool) -> Result<()> {
     println!("Attempting to update package: {}", package_name);
 
-    // 1. Check if the package is installed and get its current version and ID
-    let (package_id, current_version, lpkg_path) = match get_package_data(conn, package_name)? {
-        Some((id, version, path)) => (id, version, path),
+    // 1. Check if the package is installed and get its current version
+    let current_version = match get_package_data(conn, package_name)? {
+        Some((_, version, _)) => version,
         None => {
-            println!("Package '{}' is not installed. Cannot update.", package_name);
+            println!(
+                "Package '{}' is not installed. Cannot update.",
+                package_name
+            );
             return Ok(());
         }
     };
-
-    let _lpkg_path = lpkg_path;
-
-    println!("Currently installed version of '{}': {}", package_name, current_version);
-
-    // 2. Search for a newer version in repositories
-    // For simplicity, we'll assume a 'default' repository for now.
-    // In a real scenario, you'd iterate through configured repositories.
-    let _cache_dir = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
-    let repo_name = "default"; // Assuming a default repo
-    let _cache_path = _cache_dir.join(format!("lpkg_repo_{}.json", repo_name));
-
-    let index = fetch_index("file:///home/raja/Desktop/linuxpackage/lpkg/repo/index.json")?;
-    let latest_package_meta = search_package(&index, package_name);
-
-    let latest_package = match latest_package_meta {
-        Some(p) => p,
+    println!(
+        "Currently installed version of '{}': {}",
+        package_name, current_version
+    );
+
+    // 2. Search the configured repositories, in priority order, for the newest version
+    if let Err(e) = refresh_repos(None) {
+        eprintln!("Warning: {:#}. Using cached indexes.", e);
+    }
+    let sources = resolve::load_sources()?;
+    let pins = load_pins(conn)?;
+    let latest_version = match newest_version(&sources, package_name, &pins) {
+        Some(version) => version,
+        None if pins.contains_key(package_name) => {
+            println!(
+                "No version of '{}' in the configured repositories is allowed: it is {}.",
+                package_name, pins[package_name].reason
+            );
+            return Ok(());
+        }
         None => {
-            println!("No newer version of '{}' found in repositories.", package_name);
+            println!(
+                "Package '{}' was not found in any configured repository.",
+                package_name
+            );
             return Ok(());
         }
     };
-
-    if latest_package.version == current_version {
-        println!("Package '{}' is already at the latest version ({}).", package_name, current_version);
-        return Ok(());
+    if let Some(pin) = pins.get(package_name)
+        && let Some(newest) = newest_version(&sources, package_name, &Pins::new())
+        && newest > latest_version
+    {
+        println!(
+            "Skipping {} {}: {} is {}.",
+            package_name, newest, package_name, pin.reason
+        );
     }
 
-    println!("Newer version available: {}", latest_package.version);
-
-    // 3. Download the new version
-    let download_dir = dirs::download_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
-    let new_lpkg_path = download_dir.join(format!("{}-{}.lpkg", latest_package.name, latest_package.version));
-
-    crate::repository::download_package(&latest_package, new_lpkg_path.to_str().unwrap())
-        .context("Failed to download new package version")?;
+    // 3. Only move forward unless a downgrade was asked for
+    let current = Version::parse(&current_version).context(format!(
+        "Installed version '{}' of {} is not a valid semantic version",
+        current_version, package_name
+    ))?;
+    match latest_version.cmp(&current) {
+        Ordering::Equal => {
+            println!(
+                "Package '{}' is already at the latest version ({}).",
+                package_name, current_version
+            );
+            return Ok(());
+        }
+        Ordering::Less if !allow_downgrade => {
+            println!(
+                "The newest available version of '{}' ({}) is older than the installed version ({}). Use --allow-downgrade to install it anyway.",
+                package_name, latest_version, current_version
+            );
+            return Ok(());
+        }
+        Ordering::Less => println!("Downgrading to version: {}", latest_version),
+        Ordering::Greater => println!("Newer version available: {}", latest_version),
+    }
 
-    println!("Downloaded new version to: {}", new_lpkg_path.display());
+    // 4. Download the chosen version and any new dependencies, then replace
+    // the installed version in place
+    let request = format!("{} ={}", package_name, latest_version);
+    upgrade::run(
+        conn,
+        &sources,
+        &[request],
+        &[],
+        allow_downgrade,
+        &Features::new(),
+    )
+    .context(format!("Failed to update '{}'", package_name))?;
+    println!(
+        "Update of '{}' to version {} completed successfully.",
+        package_name, latest_version
+    );
+    Ok(())
+}
 
-    // 4. Perform atomic update: Install new, remove old on success, rollback on failure
-    // This is a simplified atomic update. A more robust solution would involve transactions
-    // and potentially a temporary installation directory for the new version.
-    let install_result = install(conn, new_lpkg_path.to_str().unwrap());
+// The newest semver version of a package allowed by its pin, in the
+// highest-priority repository that provides one. Versions that are not valid
+// semver are ignored.
+fn newest_version(sources: &[Source], package_name: &str, pins: &Pins) -> Option<Version> {
+    let pin = pins.get(package_name);
+    sources.iter().find_map(|source| {
+        crate::repository::package_versions(&source.index, package_name)
+            .into_iter()
+            .filter_map(|package| Version::parse(&package.version).ok())
+            .filter(|version| pin.is_none_or(|pin| pin.req.matches(version)))
+            .max()
+    })
+}
 
-    match install_result {
-        Ok(_) => {
-            println!("Successfully installed new version of '{}'. Removing old version.", package_name);
-            // Remove old package from DB and file system
-            remove_package_by_id(conn, package_id)
-                .context(format!("Failed to remove old package (ID: {}) from database", package_id))?;
-            // Note: Actual file removal for the old version would happen here, 
-            // but it's complex due to shared files and would require a more sophisticated
-            // file tracking system. For now, we rely on the 'remove' command's logic.
-            println!("Update of '{}' to version {} completed successfully.", package_name, latest_package.version);
-        },
-        Err(e) => {
-            eprintln!("Failed to install new version of '{}': {:?}. Attempting rollback.", package_name, e);
-            // Rollback the new installation (if any files were written before failure)
-            // This rollback is tricky as 'install' might have partially written files.
-            // A more robust rollback would involve snapshotting the system or using transactions.
-            // For now, we'll just report the failure and leave the old version installed.
-            println!("Old version of '{}' (ID: {}) remains installed.", package_name, package_id);
-            return Err(anyhow::anyhow!("Update failed, old version retained."));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::testing::{pack, source};
+    use crate::repository::resolve::Pin;
+    use semver::VersionReq;
+
+    #[test]
+    fn test_newest_version_orders_by_semver_and_repository_priority() {
+        let preferred = tempfile::tempdir().unwrap();
+        for version in ["1.9.0", "1.10.0", "1.10.0-rc.1"] {
+            pack(
+                preferred.path(),
+                "ticker",
+                version,
+                "",
+                &[("data", version)],
+            );
         }
+        let fallback = tempfile::tempdir().unwrap();
+        pack(fallback.path(), "ticker", "2.0.0", "", &[("data", "2")]);
+        let sources = vec![source(preferred.path()), source(fallback.path())];
+
+        // 1.10.0 sorts before 1.9.0 as a string
+        let newest = newest_version(&sources, "ticker", &Pins::new());
+        assert_eq!(newest, Some(Version::new(1, 10, 0)));
+
+        let pins = Pins::from([(
+            "ticker".to_string(),
+            Pin {
+                req: VersionReq::parse("<1.10").unwrap(),
+                reason: "pinned to <1.10".to_string(),
+            },
+        )]);
+        let newest = newest_version(&sources, "ticker", &pins);
+        assert_eq!(newest, Some(Version::new(1, 9, 0)));
+        assert_eq!(newest_version(&sources, "missing", &Pins::new()), None);
     }
-
-    // Clean up downloaded .lpkg file
-    fs::remove_file(&new_lpkg_path)
-        .context(format!("Failed to remove downloaded file: {}", new_lpkg_path.display()))?;
-
-    Ok(())
 }
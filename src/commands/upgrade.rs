@@ -0,0 +1,721 @@
+use crate::commands::hold::load_pins;
+use crate::commands::install::{
+    abort_upgrade, commit_upgrade, install_dir, install_staged, prepare_upgrade, stage,
+    unknown_feature,
+};
+use crate::commands::repo::refresh_repos;
+use crate::config::Config;
+use crate::db::operations::{
+    get_dependents, get_installed_packages, get_package_data, get_package_features,
+    get_package_files_by_id, get_package_id, is_package_installed, remove_package_by_id,
+};
+use crate::package::{desktop, systemd};
+use crate::repository::advisories::{AdvisoryFeed, load_advisories};
+use crate::repository::http::HttpClient;
+use crate::repository::mirrors::Mirrors;
+use crate::repository::resolve::{self, Features, Pins, Resolved, Source};
+use crate::repository::{cache, compare_versions};
+use crate::utils::format::format_size;
+use crate::utils::root;
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use semver::{Version, VersionReq};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+// One step of an upgrade plan. `installed` is the (id, version) of the
+// version being replaced, or None for a newly required dependency.
+struct Step<'a> {
+    resolved: Resolved<'a>,
+    installed: Option<(i64, String)>,
+}
+
+// Upgrade installed packages to the newest versions available in the
+// configured repositories. All packages are downloaded before anything is
+// changed, and if any step fails the new dependencies installed so far are
+// rolled back and the prepared upgrades discarded, leaving the previous
+// versions in place. With `security`, only packages affected by an advisory
+// are upgraded, and only to a version the advisory no longer affects.
+pub fn upgrade(
+    conn: &mut Connection,
+    packages: &[String],
+    exclude: &[String],
+    security: bool,
+) -> Result<()> {
+    if let Err(e) = refresh_repos(None) {
+        eprintln!("Warning: {:#}. Using cached indexes.", e);
+    }
+    let sources = resolve::load_sources()?;
+    let pins = load_pins(conn)?;
+    let advisories = if security {
+        load_advisories()?
+    } else {
+        AdvisoryFeed::default()
+    };
+
+    let installed: HashMap<String, (i64, String)> = get_installed_packages(conn)?
+        .into_iter()
+        .map(|(id, name, version)| (name, (id, version)))
+        .collect();
+    for name in packages {
+        if !installed.contains_key(name) {
+            return Err(anyhow::anyhow!("Package '{}' is not installed.", name));
+        }
+    }
+
+    let mut scope: Vec<&String> = if packages.is_empty() {
+        installed.keys().collect()
+    } else {
+        packages.iter().collect()
+    };
+    scope.retain(|name| !exclude.contains(name));
+    scope.sort();
+
+    let requested = if security {
+        security_fixes(&sources, &scope, &installed, &pins, &advisories)?
+    } else {
+        scope
+            .into_iter()
+            .filter(|name| {
+                let candidate = resolve::find_candidate(&sources, name, None, &pins);
+                report_pinned(&sources, name, candidate.as_ref(), &pins);
+                candidate.is_some_and(|c| {
+                    compare_versions(&c.package.version, &installed[*name].1) == Ordering::Greater
+                })
+            })
+            .cloned()
+            .collect()
+    };
+    if requested.is_empty() {
+        if security {
+            println!("No security upgrades to apply.");
+        } else {
+            println!("All packages are up to date.");
+        }
+        return Ok(());
+    }
+
+    run(conn, &sources, &requested, exclude, false, &Features::new())
+}
+
+// Requests for the packages in `scope` affected by an advisory, each for the
+// newest available version that no advisory affects. That may be older than
+// the newest version, which a later advisory can affect again.
+fn security_fixes(
+    sources: &[Source],
+    scope: &[&String],
+    installed: &HashMap<String, (i64, String)>,
+    pins: &Pins,
+    advisories: &AdvisoryFeed,
+) -> Result<Vec<String>> {
+    let mut requested = Vec::new();
+    for name in scope {
+        let current = &installed[*name].1;
+        if advisories.affecting(name, current)?.is_empty() {
+            continue;
+        }
+        let candidates = resolve::find_candidates(sources, name, pins);
+        report_pinned(sources, name, candidates.first(), pins);
+        let mut fixed = None;
+        for candidate in candidates
+            .iter()
+            .filter(|c| compare_versions(&c.package.version, current) == Ordering::Greater)
+        {
+            let version = &candidate.package.version;
+            let unfixed: Vec<&str> = advisories
+                .affecting(name, version)?
+                .iter()
+                .map(|a| a.id.as_str())
+                .collect();
+            if unfixed.is_empty() {
+                fixed = Some(version);
+                break;
+            }
+            println!(
+                "Skipping {} {}: still affected by {}.",
+                name,
+                version,
+                unfixed.join(", ")
+            );
+        }
+        match fixed {
+            Some(version) => requested.push(format!("{} ={}", name, version)),
+            None => println!("No fixed version of {} is available.", name),
+        }
+    }
+    Ok(requested)
+}
+
+// Install an exact version of a package from the configured repositories,
+// replacing the installed version. Going back to an older version requires
+// `allow_downgrade`.
+pub fn install_version(
+    conn: &mut Connection,
+    package_name: &str,
+    version: &str,
+    allow_downgrade: bool,
+    features: &[String],
+) -> Result<()> {
+    let version = Version::parse(version)
+        .context(format!("'{}' is not a valid semantic version", version))?;
+    if let Some((package_id, installed_version, _)) = get_package_data(conn, package_name)? {
+        // The installed version is reinstalled only to select more features
+        let selected = get_package_features(conn, package_id)?;
+        if Version::parse(&installed_version).is_ok_and(|v| v == version)
+            && features.iter().all(|f| selected.contains(f))
+        {
+            println!(
+                "Package '{}' version {} is already installed.",
+                package_name, version
+            );
+            return Ok(());
+        }
+        if compare_versions(&version.to_string(), &installed_version) == Ordering::Less
+            && !allow_downgrade
+        {
+            return Err(anyhow::anyhow!(
+                "Package '{}' version {} is installed, which is newer than {}. Use --allow-downgrade to install it anyway.",
+                package_name,
+                installed_version,
+                version
+            ));
+        }
+    }
+
+    if let Err(e) = refresh_repos(None) {
+        eprintln!("Warning: {:#}. Using cached indexes.", e);
+    }
+    let sources = resolve::load_sources()?;
+    let request = format!("{} ={}", package_name, version);
+    let features = Features::from([(package_name.to_string(), features.to_vec())]);
+    run(conn, &sources, &[request], &[], allow_downgrade, &features)
+}
+
+// Plan, download and apply the changes needed to install the requested
+// packages (in the "name constraint" form the resolver takes) over the
+// installed versions. Moving an installed package to an older version is an
+// error unless `allow_downgrade` is set. `features` are optional features to
+// select in addition to those already selected for installed packages.
+pub fn run(
+    conn: &mut Connection,
+    sources: &[Source],
+    requested: &[String],
+    exclude: &[String],
+    allow_downgrade: bool,
+    features: &Features,
+) -> Result<()> {
+    let installed: HashMap<String, (i64, String)> = get_installed_packages(conn)?
+        .into_iter()
+        .map(|(id, name, version)| (name, (id, version)))
+        .collect();
+    let pins = load_pins(conn)?;
+    let mut selected = features.clone();
+    for (name, (id, _)) in &installed {
+        for feature in get_package_features(conn, *id)? {
+            let entry = selected.entry(name.clone()).or_default();
+            if !entry.contains(&feature) {
+                entry.push(feature);
+            }
+        }
+    }
+    let steps = plan(
+        conn,
+        sources,
+        requested,
+        &pins,
+        &selected,
+        &installed,
+        exclude,
+        allow_downgrade,
+    )?;
+    // Requested features are checked against the index before anything is
+    // downloaded
+    for step in &steps {
+        let package = step.resolved.package;
+        let available: Vec<&String> = package.features.iter().flatten().map(|(f, _)| f).collect();
+        for feature in features.get(&package.name).into_iter().flatten() {
+            if !available.contains(&feature) {
+                return Err(unknown_feature(
+                    &package.name,
+                    &package.version,
+                    feature,
+                    &available,
+                ));
+            }
+        }
+    }
+    print_plan(&steps);
+
+    // Download everything up front so a network failure cannot leave the
+    // system half upgraded
+    let files = download(&steps)?;
+
+    apply(conn, &steps, &files, features)?;
+    println!("Upgrade complete.");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn plan<'a>(
+    conn: &Connection,
+    sources: &'a [Source],
+    requested: &[String],
+    pins: &Pins,
+    features: &Features,
+    installed: &HashMap<String, (i64, String)>,
+    exclude: &[String],
+    allow_downgrade: bool,
+) -> Result<Vec<Step<'a>>> {
+    // Dependencies already satisfied by an installed version are left alone
+    let resolved = resolve::resolve(sources, requested, pins, features, &|name, constraint| {
+        if requested
+            .iter()
+            .any(|r| resolve::parse_dependency(r).0 == name)
+        {
+            return Ok(false);
+        }
+        is_package_installed(conn, name, constraint)
+    })?;
+
+    // Dependents that are replaced as well were resolved against the new versions
+    let replaced: Vec<String> = resolved
+        .iter()
+        .map(|r| r.package.name.clone())
+        .filter(|name| installed.contains_key(name))
+        .collect();
+    resolved
+        .into_iter()
+        .map(|resolved| {
+            let name = &resolved.package.name;
+            let current = installed.get(name).cloned();
+            if let Some((_, version)) = &current {
+                if exclude.contains(name) {
+                    return Err(anyhow::anyhow!(
+                        "Upgrade requires {} {}, but {} is excluded",
+                        name,
+                        resolved.package.version,
+                        name
+                    ));
+                }
+                // A requested package may be reinstalled at the same version
+                let reinstall = requested
+                    .iter()
+                    .any(|r| resolve::parse_dependency(r).0 == name);
+                let order = compare_versions(&resolved.package.version, version);
+                if (order == Ordering::Less || (order == Ordering::Equal && !reinstall))
+                    && !allow_downgrade
+                {
+                    return Err(anyhow::anyhow!(
+                        "Upgrade would downgrade {} from {} to {}",
+                        name,
+                        version,
+                        resolved.package.version
+                    ));
+                }
+            }
+            if current.is_some() {
+                check_dependents(conn, name, &resolved.package.version, &replaced)?;
+            }
+            Ok(Step {
+                resolved,
+                installed: current,
+            })
+        })
+        .collect()
+}
+
+// Make sure every installed package that depends on the one being replaced
+// still accepts the new version
+pub fn check_dependents(
+    conn: &Connection,
+    name: &str,
+    version: &str,
+    replaced: &[String],
+) -> Result<()> {
+    let parsed = Version::parse(version).context(format!(
+        "Version '{}' of {} is not a valid semantic version",
+        version, name
+    ))?;
+    for (dependent, dependent_version, constraint) in get_dependents(conn, name)? {
+        let Some(constraint) = constraint else {
+            continue;
+        };
+        if replaced.contains(&dependent) {
+            continue;
+        }
+        let req = VersionReq::parse(&constraint).context(format!(
+            "Invalid version constraint '{}' recorded for {}",
+            constraint, dependent
+        ))?;
+        if !req.matches(&parsed) {
+            return Err(anyhow::anyhow!(
+                "Cannot install {} {}: {} {} requires {} {}",
+                name,
+                version,
+                dependent,
+                dependent_version,
+                name,
+                constraint
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Tell the user when a hold or pin keeps a package from its newest version
+fn report_pinned(sources: &[Source], name: &str, candidate: Option<&Resolved>, pins: &Pins) {
+    let Some(pin) = pins.get(name) else {
+        return;
+    };
+    let Some(newest) = resolve::find_candidate(sources, name, None, &Pins::new()) else {
+        return;
+    };
+    let skipped = candidate.is_none_or(|c| {
+        compare_versions(&newest.package.version, &c.package.version) == Ordering::Greater
+    });
+    if skipped {
+        println!(
+            "Skipping {} {}: {} is {}.",
+            name, newest.package.version, name, pin.reason
+        );
+    }
+}
+
+fn print_plan(steps: &[Step]) {
+    let (upgrades, new): (Vec<&Step>, Vec<&Step>) =
+        steps.iter().partition(|step| step.installed.is_some());
+    if !upgrades.is_empty() {
+        println!("Packages to upgrade:");
+        for step in &upgrades {
+            let current = step
+                .installed
+                .as_ref()
+                .map(|(_, v)| v.as_str())
+                .unwrap_or_default();
+            let note = match compare_versions(&step.resolved.package.version, current) {
+                Ordering::Less => " (downgrade)",
+                Ordering::Equal => " (reinstall)",
+                Ordering::Greater => "",
+            };
+            println!(
+                "  {} {} -> {}{}",
+                step.resolved.package.name, current, step.resolved.package.version, note
+            );
+        }
+    }
+    if !new.is_empty() {
+        println!("New dependencies to install:");
+        for step in &new {
+            println!(
+                "  {} {}",
+                step.resolved.package.name, step.resolved.package.version
+            );
+        }
+    }
+    let total: u64 = steps.iter().filter_map(|s| s.resolved.package.size).sum();
+    println!(
+        "{} upgraded, {} newly installed, {} to download at most.",
+        upgrades.len(),
+        new.len(),
+        format_size(total)
+    );
+}
+
+fn download(steps: &[Step]) -> Result<Vec<PathBuf>> {
+    let config = Config::load()?;
+    let mut clients: HashMap<&str, (HttpClient, Mirrors)> = HashMap::new();
+    let mut files = Vec::new();
+    for step in steps {
+        let source = step.resolved.source;
+        if !clients.contains_key(source.repo.name.as_str()) {
+            let client = HttpClient::for_repository(&config, &source.repo)?;
+            let mirrors = Mirrors::for_repository(&source.repo, Some(&source.index), &client);
+            clients.insert(&source.repo.name, (client, mirrors));
+        }
+        let (client, mirrors) = &clients[source.repo.name.as_str()];
+        files.push(cache::fetch_package(
+            step.resolved.package,
+            mirrors,
+            client,
+        )?);
+    }
+    Ok(files)
+}
+
+// Install the downloaded packages of a plan. `features` are the newly
+// requested features; upgrades keep the ones already selected by themselves.
+fn apply(
+    conn: &mut Connection,
+    steps: &[Step],
+    files: &[PathBuf],
+    features: &Features,
+) -> Result<()> {
+    // Versions this plan installs, so dependency checks can count on them
+    let planned: HashMap<String, String> = steps
+        .iter()
+        .map(|s| {
+            (
+                s.resolved.package.name.clone(),
+                s.resolved.package.version.clone(),
+            )
+        })
+        .collect();
+
+    // New dependencies are installed right away, while upgrades are only
+    // prepared next to the old version until every step has succeeded
+    let mut new_installs = Vec::new();
+    let mut prepared = Vec::new();
+    for (step, file) in steps.iter().zip(files) {
+        let package = step.resolved.package;
+        let file = file.to_str().unwrap_or_default();
+        let requested = features
+            .get(&package.name)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let result = stage(file).and_then(|mut staged| {
+            staged.select_features(requested)?;
+            match &step.installed {
+                None => install_staged(conn, &staged, &planned),
+                Some((package_id, _)) => prepare_upgrade(conn, *package_id, staged, &planned)
+                    .map(|upgrade| prepared.push(upgrade)),
+            }
+        });
+        // New dependencies are removed again if a later step fails
+        if step.installed.is_none()
+            && result.is_ok()
+            && let Ok(Some(id)) = get_package_id(conn, &package.name, &package.version)
+        {
+            new_installs.push((step, id));
+        }
+        if let Err(e) = result {
+            eprintln!(
+                "Error: failed to install {} {}: {:#}. Rolling back.",
+                package.name, package.version, e
+            );
+            for upgrade in prepared {
+                abort_upgrade(upgrade);
+            }
+            roll_back(conn, &new_installs);
+            return Err(anyhow::anyhow!(
+                "Upgrade failed, previously installed versions were kept."
+            ));
+        }
+    }
+
+    for upgrade in prepared {
+        let name = upgrade.name().to_string();
+        commit_upgrade(conn, upgrade).context(format!("Failed to upgrade {}", name))?;
+    }
+    Ok(())
+}
+
+// Remove the new dependencies installed so far
+fn roll_back(conn: &mut Connection, new_installs: &[(&Step, i64)]) {
+    for (step, id) in new_installs.iter().rev() {
+        let package = step.resolved.package;
+        let files = get_package_files_by_id(conn, *id).unwrap_or_default();
+        systemd::stop_units(&files);
+        for file in &files {
+            if let Err(e) = crate::utils::file_ops::remove_file(&root::path(file).to_string_lossy())
+            {
+                eprintln!("Warning: failed to remove {}: {:#}", file, e);
+            }
+        }
+        desktop::refresh_caches(&files);
+        systemd::reload(&files);
+        remove_package_by_id(conn, *id).ok();
+        fs::remove_dir_all(root::path(install_dir(&package.name, &package.version))).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::install::install;
+    use crate::commands::testing::{pack, source};
+    use crate::db::connection::open_in_memory;
+
+    #[test]
+    fn test_new_dependency_can_require_a_version_upgraded_in_the_same_run() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let local = tempfile::tempdir().unwrap();
+        for name in ["gadget", "libgadget"] {
+            let file = pack(local.path(), name, "1.0.0", "", &[("data", "1")]);
+            install(&mut conn, &file, &[]).unwrap();
+        }
+
+        // gadget 2 needs a new plugin, which needs the new libgadget
+        let repo = tempfile::tempdir().unwrap();
+        let depends = |on: &str| format!("\n[dependencies]\n{}\n", on);
+        pack(
+            repo.path(),
+            "gadget",
+            "2.0.0",
+            &depends("gadget-plugin = \">=1\""),
+            &[("data", "2")],
+        );
+        pack(
+            repo.path(),
+            "gadget-plugin",
+            "1.0.0",
+            &depends("libgadget = \">=2\""),
+            &[("data", "1")],
+        );
+        pack(repo.path(), "libgadget", "2.0.0", "", &[("data", "2")]);
+
+        let sources = vec![source(repo.path())];
+        let requested = ["gadget".to_string(), "libgadget".to_string()];
+        run(
+            &mut conn,
+            &sources,
+            &requested,
+            &[],
+            false,
+            &Features::new(),
+        )
+        .unwrap();
+        for (name, version) in [
+            ("gadget", "2.0.0"),
+            ("gadget-plugin", "1.0.0"),
+            ("libgadget", "2.0.0"),
+        ] {
+            let (_, installed, _) = get_package_data(&conn, name).unwrap().unwrap();
+            assert_eq!(installed, version);
+        }
+    }
+
+    #[test]
+    fn test_security_upgrade_takes_the_newest_version_no_advisory_affects() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let local = tempfile::tempdir().unwrap();
+        for name in ["leaky", "sound"] {
+            let file = pack(local.path(), name, "1.0.0", "", &[("data", "1")]);
+            install(&mut conn, &file, &[]).unwrap();
+        }
+
+        let repo = tempfile::tempdir().unwrap();
+        for version in ["1.1.0", "1.2.0"] {
+            pack(repo.path(), "leaky", version, "", &[("data", version)]);
+        }
+        pack(repo.path(), "sound", "2.0.0", "", &[("data", "2")]);
+        let sources = vec![source(repo.path())];
+        // 1.1.0 fixes the first advisory, and 1.2.0 brought a new one
+        let advisories: AdvisoryFeed = serde_json::from_str(
+            r#"{"advisories": {"leaky": [
+                {"id": "LPKG-20", "affected": "<1.1.0", "severity": "high"},
+                {"id": "LPKG-21", "affected": ">=1.2.0", "severity": "medium"}
+            ]}}"#,
+        )
+        .unwrap();
+
+        let installed: HashMap<String, (i64, String)> = get_installed_packages(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|(id, name, version)| (name, (id, version)))
+            .collect();
+        let (leaky, sound) = ("leaky".to_string(), "sound".to_string());
+        let requested = security_fixes(
+            &sources,
+            &[&leaky, &sound],
+            &installed,
+            &Pins::new(),
+            &advisories,
+        )
+        .unwrap();
+        assert_eq!(requested, ["leaky =1.1.0"]);
+
+        run(
+            &mut conn,
+            &sources,
+            &requested,
+            &[],
+            false,
+            &Features::new(),
+        )
+        .unwrap();
+        let (_, version, _) = get_package_data(&conn, "leaky").unwrap().unwrap();
+        assert_eq!(version, "1.1.0");
+        let (_, version, _) = get_package_data(&conn, "sound").unwrap().unwrap();
+        assert_eq!(version, "1.0.0");
+
+        // Once every newer version is affected there is nothing to apply
+        let advisories: AdvisoryFeed = serde_json::from_str(
+            r#"{"advisories": {"leaky": [
+                {"id": "LPKG-22", "affected": ">=1.0.0", "severity": "high"}
+            ]}}"#,
+        )
+        .unwrap();
+        let installed: HashMap<String, (i64, String)> = get_installed_packages(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|(id, name, version)| (name, (id, version)))
+            .collect();
+        let requested =
+            security_fixes(&sources, &[&leaky], &installed, &Pins::new(), &advisories).unwrap();
+        assert!(requested.is_empty());
+    }
+
+    #[test]
+    fn test_install_version_downgrades_only_when_allowed_and_accepted() {
+        root::set_for_tests();
+        let mut conn = open_in_memory();
+        let local = tempfile::tempdir().unwrap();
+        let file = pack(local.path(), "rewind", "2.0.0", "", &[("data", "2")]);
+        install(&mut conn, &file, &[]).unwrap();
+        let depends = "\n[dependencies]\nrewind = \">=1.5\"\n";
+        let file = pack(
+            local.path(),
+            "rewind-app",
+            "1.0.0",
+            depends,
+            &[("app", "1")],
+        );
+        install(&mut conn, &file, &[]).unwrap();
+
+        let repo = tempfile::tempdir().unwrap();
+        for version in ["1.0.0", "1.5.0", "2.0.0"] {
+            pack(repo.path(), "rewind", version, "", &[("data", version)]);
+        }
+        let sources = vec![source(repo.path())];
+        let request = |version: &str| [format!("rewind ={}", version)];
+        let installed = |conn: &Connection| get_package_data(conn, "rewind").unwrap().unwrap().1;
+
+        let error = install_version(&mut conn, "rewind", "1.5.0", false, &[]).unwrap_err();
+        assert!(
+            error.to_string().contains("--allow-downgrade"),
+            "{:#}",
+            error
+        );
+        let features = Features::new();
+        assert!(
+            run(
+                &mut conn,
+                &sources,
+                &request("1.5.0"),
+                &[],
+                false,
+                &features
+            )
+            .is_err()
+        );
+
+        // rewind-app still needs at least 1.5
+        let error = run(&mut conn, &sources, &request("1.0.0"), &[], true, &features).unwrap_err();
+        assert!(
+            error.to_string().contains("rewind-app 1.0.0 requires"),
+            "{:#}",
+            error
+        );
+        assert_eq!(installed(&conn), "2.0.0");
+
+        run(&mut conn, &sources, &request("1.5.0"), &[], true, &features).unwrap();
+        assert_eq!(installed(&conn), "1.5.0");
+        let data = root::path(install_dir("rewind", "1.5.0")).join("data");
+        assert_eq!(fs::read_to_string(data).unwrap(), "1.5.0");
+        assert!(!root::path(install_dir("rewind", "2.0.0")).exists());
+    }
+}
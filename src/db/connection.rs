@@ -1,15 +1,15 @@
+use crate::utils::root;
 use anyhow::{Context, Result};
 use rusqlite::{Connection, OpenFlags};
 use std::fs;
-use std::path::Path;
 
 pub fn get_connection(read_only: bool) -> Result<Connection> {
-    let db_path = "/var/lib/lpkg/db.sqlite";
-    let db_dir = Path::new("/var/lib/lpkg");
+    let db_path = root::path("/var/lib/lpkg/db.sqlite");
+    let db_dir = root::path("/var/lib/lpkg");
 
     // Ensure the directory exists (requires sudo for system-wide location)
     if !db_dir.exists() {
-        fs::create_dir_all(db_dir).context(format!(
+        fs::create_dir_all(&db_dir).context(format!(
             "Failed to create database directory: {}",
             db_dir.display()
         ))?;
@@ -21,7 +21,24 @@ pub fn get_connection(read_only: bool) -> Result<Connection> {
         OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
     };
 
-    let conn = Connection::open_with_flags(db_path, flags)
-        .context(format!("Failed to open database at: {}", db_path))?;
+    let conn = Connection::open_with_flags(&db_path, flags)
+        .context(format!("Failed to open database at: {}", db_path.display()))?;
+    enable_foreign_keys(&conn)?;
     Ok(conn)
 }
+
+// SQLite only enforces foreign keys, and so deletes the rows of a removed
+// package along with it, when asked to on every connection
+pub fn enable_foreign_keys(conn: &Connection) -> Result<()> {
+    conn.pragma_update(None, "foreign_keys", true)
+        .context("Failed to enable foreign keys")
+}
+
+// A fresh database with the current schema, for tests
+#[cfg(test)]
+pub fn open_in_memory() -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    enable_foreign_keys(&conn).unwrap();
+    crate::db::schema::initialize_schema(&mut conn).unwrap();
+    conn
+}
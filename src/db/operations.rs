@@ -1,10 +1,13 @@
+use crate::db::schema::has_table;
+use crate::package::metadata::provide_satisfies;
 use anyhow::{Context, Result};
-use rusqlite::{Connection, params, OptionalExtension};
-use std::fmt::Write;
+use rusqlite::{Connection, OptionalExtension, params};
 use semver::{Version, VersionReq};
+use std::fmt::Write;
 
+#[allow(clippy::too_many_arguments)]
 pub fn add_package(
-    conn: &mut Connection,
+    conn: &Connection,
     package_name: &str,
     version: &str,
     description: Option<&str>,
@@ -39,8 +42,61 @@ pub fn add_package(
     Ok(package_id)
 }
 
+// Move an installed package's record to a new version in place, keeping its
+// id. Callers replace its files and dependencies in the same transaction.
+#[allow(clippy::too_many_arguments)]
+pub fn update_package(
+    conn: &Connection,
+    package_id: i64,
+    version: &str,
+    description: Option<&str>,
+    license: Option<&str>,
+    homepage: Option<&str>,
+    repository: Option<&str>,
+    authors: Option<&str>,
+    lpkg_path: Option<&str>,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE packages SET version = ?2, description = ?3, license = ?4, homepage = ?5,
+         repository = ?6, authors = ?7, lpkg_path = ?8, installed_at = CURRENT_TIMESTAMP
+         WHERE id = ?1",
+        params![
+            package_id,
+            version,
+            description,
+            license,
+            homepage,
+            repository,
+            authors,
+            lpkg_path
+        ],
+    )
+    .context("Failed to update package in database")?;
+    conn.execute(
+        "DELETE FROM package_files WHERE package_id = ?1",
+        params![package_id],
+    )
+    .context("Failed to clear package files in database")?;
+    conn.execute(
+        "DELETE FROM dependencies WHERE package_id = ?1",
+        params![package_id],
+    )
+    .context("Failed to clear package dependencies in database")?;
+    conn.execute(
+        "DELETE FROM provides WHERE package_id = ?1",
+        params![package_id],
+    )
+    .context("Failed to clear package provides in database")?;
+    conn.execute(
+        "DELETE FROM package_features WHERE package_id = ?1",
+        params![package_id],
+    )
+    .context("Failed to clear package features in database")?;
+    Ok(())
+}
+
 pub fn add_package_file(
-    conn: &mut Connection,
+    conn: &Connection,
     package_id: i64,
     path: &str,
     checksum: Option<&str>,
@@ -54,7 +110,7 @@ pub fn add_package_file(
 }
 
 pub fn add_dependency(
-    conn: &mut Connection,
+    conn: &Connection,
     package_id: i64,
     dependency_name: &str,
     dependency_version: Option<&str>,
@@ -66,6 +122,63 @@ pub fn add_dependency(
     Ok(())
 }
 
+pub fn add_provide(
+    conn: &Connection,
+    package_id: i64,
+    name: &str,
+    version: Option<&str>,
+    replaced: bool,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO provides (package_id, name, version, replaced) VALUES (?1, ?2, ?3, ?4)",
+        params![package_id, name, version, replaced],
+    )
+    .context("Failed to insert provide into database")?;
+    Ok(())
+}
+
+pub fn add_package_feature(conn: &Connection, package_id: i64, feature: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO package_features (package_id, feature) VALUES (?1, ?2)",
+        params![package_id, feature],
+    )
+    .context("Failed to insert package feature into database")?;
+    Ok(())
+}
+
+// Features selected for an installed package, sorted
+pub fn get_package_features(conn: &Connection, package_id: i64) -> Result<Vec<String>> {
+    if !has_table(conn, "package_features")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT feature FROM package_features WHERE package_id = ?1 ORDER BY feature ASC",
+    )?;
+    let features = stmt.query_map(params![package_id], |row| row.get(0))?;
+    features
+        .collect::<Result<Vec<String>, _>>()
+        .context("Failed to collect package features")
+}
+
+// Installed packages providing or replacing a name, as (package name,
+// provided version, replaced)
+pub fn get_providers(conn: &Connection, name: &str) -> Result<Vec<(String, Option<String>, bool)>> {
+    if !has_table(conn, "provides")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT p.name, pr.version, pr.replaced FROM provides pr
+         JOIN packages p ON p.id = pr.package_id
+         WHERE pr.name = ?1 ORDER BY p.name ASC",
+    )?;
+    let providers = stmt.query_map(params![name], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    providers
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect package providers")
+}
+
 #[allow(dead_code)]
 pub fn add_conflict(
     conn: &mut Connection,
@@ -150,6 +263,12 @@ pub fn get_package_info(conn: &Connection, package_name: &str) -> Result<Option<
         if let Some(auth) = authors {
             writeln!(info, "Authors: {}", auth)?;
         }
+        if let Some((package_id, _, _)) = get_package_data(conn, &name)? {
+            let features = get_package_features(conn, package_id)?;
+            if !features.is_empty() {
+                writeln!(info, "Features: {}", features.join(", "))?;
+            }
+        }
 
         
 
@@ -202,8 +321,11 @@ pub fn is_package_installed(
             Ok(true)
         }
     } else {
-        // Package not installed
-        Ok(false)
+        // Package not installed, but another one may provide or replace it
+        let providers = get_providers(conn, package_name)?;
+        Ok(providers.iter().any(|(_, version, replaced)| {
+            *replaced || provide_satisfies(version.as_deref(), version_constraint)
+        }))
     }
 }
 
@@ -215,6 +337,40 @@ pub fn get_package_data(conn: &Connection, package_name: &str) -> Result<Option<
     Ok(result)
 }
 
+// Installed files of a package with their recorded checksums
+pub fn get_package_file_checksums(
+    conn: &Connection,
+    package_id: i64,
+) -> Result<Vec<(String, Option<String>)>> {
+    let mut stmt =
+        conn.prepare("SELECT path, checksum FROM package_files WHERE package_id = ?1")?;
+    let files = stmt.query_map(params![package_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    files
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect package file checksums")
+}
+
+// Drop a path from a package's files, e.g. when another package takes it over
+pub fn remove_package_file(conn: &Connection, package_id: i64, path: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM package_files WHERE package_id = ?1 AND path = ?2",
+        params![package_id, path],
+    )
+    .context("Failed to remove package file from database")?;
+    Ok(())
+}
+
+// The installed package a file belongs to, if any
+pub fn get_file_owner(conn: &Connection, path: &str) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT p.name FROM package_files f JOIN packages p ON p.id = f.package_id WHERE f.path = ?1",
+        params![path],
+        |row| row.get(0),
+    )
+    .optional()
+    .context("Failed to look up file owner")
+}
+
 pub fn get_package_files_by_id(conn: &Connection, package_id: i64) -> Result<Vec<String>> {
     let mut stmt = conn.prepare("SELECT path FROM package_files WHERE package_id = ?1")?;
     let files = stmt.query_map(params![package_id], |row| row.get(0))?;
@@ -226,3 +382,436 @@ pub fn remove_package_by_id(conn: &mut Connection, package_id: i64) -> Result<bo
         .context("Failed to delete package by ID from database")?;
     Ok(result > 0)
 }
+
+// Every installed package as (id, name, version), ordered by name
+pub fn get_installed_packages(conn: &Connection) -> Result<Vec<(i64, String, String)>> {
+    let mut stmt = conn
+        .prepare("SELECT id, name, version FROM packages ORDER BY name ASC")
+        .context("Failed to prepare select statement for installed packages")?;
+    let packages = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    packages
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect installed packages")
+}
+
+pub fn get_package_id(conn: &Connection, package_name: &str, version: &str) -> Result<Option<i64>> {
+    conn.query_row(
+        "SELECT id FROM packages WHERE name = ?1 AND version = ?2",
+        params![package_name, version],
+        |row| row.get(0),
+    )
+    .optional()
+    .context("Failed to query package ID")
+}
+
+// Hold a package at its installed version, or release the hold
+pub fn set_hold(conn: &Connection, package_name: &str, held: bool) -> Result<()> {
+    conn.execute(
+        "INSERT INTO package_pins (name, held) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET held = excluded.held",
+        params![package_name, held],
+    )
+    .context("Failed to update package hold")?;
+    remove_unused_pin(conn, package_name)
+}
+
+// Pin a package to a version range, or remove the pin with None
+pub fn set_pin(conn: &Connection, package_name: &str, pin: Option<&str>) -> Result<()> {
+    conn.execute(
+        "INSERT INTO package_pins (name, pin) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET pin = excluded.pin",
+        params![package_name, pin],
+    )
+    .context("Failed to update package pin")?;
+    remove_unused_pin(conn, package_name)
+}
+
+fn remove_unused_pin(conn: &Connection, package_name: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM package_pins WHERE name = ?1 AND held = 0 AND pin IS NULL",
+        params![package_name],
+    )
+    .context("Failed to clean up package pins")?;
+    Ok(())
+}
+
+// Every hold and pin as (name, held, pin), ordered by name
+pub fn get_pins(conn: &Connection) -> Result<Vec<(String, bool, Option<String>)>> {
+    if !has_table(conn, "package_pins")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare("SELECT name, held, pin FROM package_pins ORDER BY name ASC")?;
+    let pins = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    pins.collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect package pins")
+}
+
+// Installed packages that depend on a package, as (name, version, constraint)
+pub fn get_dependents(
+    conn: &Connection,
+    package_name: &str,
+) -> Result<Vec<(String, String, Option<String>)>> {
+    let mut stmt = conn.prepare(
+        "SELECT p.name, p.version, d.dependency_version FROM dependencies d
+         JOIN packages p ON p.id = d.package_id
+         WHERE d.dependency_name = ?1 ORDER BY p.name ASC",
+    )?;
+    let dependents = stmt.query_map(params![package_name], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    dependents
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect package dependents")
+}
+
+// Remember the cached package of a replaced version, keeping only the newest
+// `keep` entries for the package
+pub fn add_previous_version(
+    conn: &Connection,
+    package_name: &str,
+    version: &str,
+    lpkg_path: &str,
+    keep: usize,
+) -> Result<()> {
+    conn.execute(
+        "DELETE FROM previous_versions WHERE name = ?1 AND version = ?2",
+        params![package_name, version],
+    )
+    .context("Failed to replace previous package version")?;
+    conn.execute(
+        "INSERT INTO previous_versions (name, version, lpkg_path) VALUES (?1, ?2, ?3)",
+        params![package_name, version, lpkg_path],
+    )
+    .context("Failed to record previous package version")?;
+    conn.execute(
+        "DELETE FROM previous_versions WHERE name = ?1 AND id NOT IN
+         (SELECT id FROM previous_versions WHERE name = ?1 ORDER BY id DESC LIMIT ?2)",
+        params![package_name, keep as i64],
+    )
+    .context("Failed to prune previous package versions")?;
+    Ok(())
+}
+
+// The most recently replaced version of a package as (id, version, lpkg_path)
+pub fn get_previous_version(
+    conn: &Connection,
+    package_name: &str,
+) -> Result<Option<(i64, String, String)>> {
+    if !has_table(conn, "previous_versions")? {
+        return Ok(None);
+    }
+    conn.query_row(
+        "SELECT id, version, lpkg_path FROM previous_versions WHERE name = ?1 ORDER BY id DESC LIMIT 1",
+        params![package_name],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .optional()
+    .context("Failed to query previous package version")
+}
+
+pub fn remove_previous_version(conn: &Connection, id: i64) -> Result<()> {
+    conn.execute("DELETE FROM previous_versions WHERE id = ?1", params![id])
+        .context("Failed to remove previous package version")?;
+    Ok(())
+}
+
+// Package files installed or kept for rollback, which the cache must not drop
+pub fn get_retained_package_files(conn: &Connection) -> Result<Vec<String>> {
+    let mut query = "SELECT lpkg_path FROM packages WHERE lpkg_path IS NOT NULL".to_string();
+    for (table, column) in [
+        ("previous_versions", "lpkg_path"),
+        ("generation_packages", "lpkg_path"),
+        ("transaction_changes", "old_lpkg_path"),
+    ] {
+        if has_table(conn, table)? {
+            write!(
+                query,
+                " UNION SELECT {} FROM {} WHERE {} IS NOT NULL",
+                column, table, column
+            )?;
+        }
+    }
+    let mut stmt = conn.prepare(&query)?;
+    let paths = stmt.query_map([], |row| row.get(0))?;
+    paths
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect retained package files")
+}
+
+// A package in a generation snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationPackage {
+    pub name: String,
+    pub version: String,
+    pub sha256: Option<String>,
+    pub lpkg_path: Option<String>,
+}
+
+// Record a generation with the given packages and the checksums of every
+// installed file, dropping the oldest generations beyond `keep`. Returns the
+// new generation number.
+pub fn add_generation(
+    conn: &mut Connection,
+    command: &str,
+    packages: &[GenerationPackage],
+    keep: usize,
+) -> Result<i64> {
+    let tx = conn.transaction()?;
+    let generation_id: i64 = tx
+        .query_row(
+            "INSERT INTO generations (command) VALUES (?1) RETURNING id",
+            params![command],
+            |row| row.get(0),
+        )
+        .context("Failed to insert generation")?;
+    for package in packages {
+        tx.execute(
+            "INSERT INTO generation_packages (generation_id, name, version, sha256, lpkg_path) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![generation_id, package.name, package.version, package.sha256, package.lpkg_path],
+        )
+        .context(format!("Failed to record {} in generation", package.name))?;
+    }
+    tx.execute(
+        "INSERT INTO generation_files (generation_id, package_name, path, checksum)
+         SELECT ?1, p.name, f.path, f.checksum FROM package_files f JOIN packages p ON p.id = f.package_id",
+        params![generation_id],
+    )
+    .context("Failed to record generation files")?;
+
+    let cutoff = "(SELECT id FROM generations ORDER BY id DESC LIMIT -1 OFFSET ?1)";
+    for table in ["generation_files", "generation_packages"] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE generation_id IN {}", table, cutoff),
+            params![keep as i64],
+        )
+        .context("Failed to prune old generations")?;
+    }
+    tx.execute(
+        &format!("DELETE FROM generations WHERE id IN {}", cutoff),
+        params![keep as i64],
+    )
+    .context("Failed to prune old generations")?;
+    tx.commit().context("Failed to commit generation")?;
+    Ok(generation_id)
+}
+
+// Every generation as (id, created_at, command, package count), oldest first
+pub fn get_generations(conn: &Connection) -> Result<Vec<(i64, String, String, i64)>> {
+    if !has_table(conn, "generations")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT g.id, g.created_at, g.command,
+                (SELECT COUNT(*) FROM generation_packages p WHERE p.generation_id = g.id)
+         FROM generations g ORDER BY g.id ASC",
+    )?;
+    let generations = stmt.query_map([], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    generations
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect generations")
+}
+
+pub fn get_generation_packages(
+    conn: &Connection,
+    generation_id: i64,
+) -> Result<Vec<GenerationPackage>> {
+    if !has_table(conn, "generation_packages")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT name, version, sha256, lpkg_path FROM generation_packages
+         WHERE generation_id = ?1 ORDER BY name ASC",
+    )?;
+    let packages = stmt.query_map(params![generation_id], |row| {
+        Ok(GenerationPackage {
+            name: row.get(0)?,
+            version: row.get(1)?,
+            sha256: row.get(2)?,
+            lpkg_path: row.get(3)?,
+        })
+    })?;
+    packages
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect generation packages")
+}
+
+pub fn set_package_lpkg_path(conn: &Connection, package_id: i64, lpkg_path: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE packages SET lpkg_path = ?2 WHERE id = ?1",
+        params![package_id, lpkg_path],
+    )
+    .context("Failed to update package file path")?;
+    Ok(())
+}
+
+// A package added (no old version), removed (no new version) or changed by a
+// transaction. The old version's cached package allows undoing it.
+#[derive(Debug, Clone)]
+pub struct TransactionChange {
+    pub name: String,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    pub old_sha256: Option<String>,
+    pub old_lpkg_path: Option<String>,
+}
+
+// A recorded transaction
+#[derive(Debug)]
+pub struct TransactionRecord {
+    pub id: i64,
+    pub command: String,
+    pub user: String,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub result: String,
+    pub changes: i64,
+}
+
+pub fn begin_transaction(conn: &Connection, command: &str, user: &str) -> Result<i64> {
+    conn.query_row(
+        "INSERT INTO transactions (command, user) VALUES (?1, ?2) RETURNING id",
+        params![command, user],
+        |row| row.get(0),
+    )
+    .context("Failed to record transaction")
+}
+
+pub fn finish_transaction(
+    conn: &mut Connection,
+    transaction_id: i64,
+    result: &str,
+    changes: &[TransactionChange],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "UPDATE transactions SET result = ?2, finished_at = CURRENT_TIMESTAMP WHERE id = ?1",
+        params![transaction_id, result],
+    )
+    .context("Failed to record transaction result")?;
+    for change in changes {
+        tx.execute(
+            "INSERT INTO transaction_changes (transaction_id, name, old_version, new_version, old_sha256, old_lpkg_path)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![transaction_id, change.name, change.old_version, change.new_version, change.old_sha256, change.old_lpkg_path],
+        )
+        .context(format!("Failed to record change of {} in transaction", change.name))?;
+    }
+    tx.commit().context("Failed to commit transaction record")
+}
+
+const TRANSACTION_COLUMNS: &str = "t.id, t.command, t.user, t.started_at, t.finished_at, t.result,
+    (SELECT COUNT(*) FROM transaction_changes c WHERE c.transaction_id = t.id)";
+
+fn transaction_from_row(row: &rusqlite::Row) -> rusqlite::Result<TransactionRecord> {
+    Ok(TransactionRecord {
+        id: row.get(0)?,
+        command: row.get(1)?,
+        user: row.get(2)?,
+        started_at: row.get(3)?,
+        finished_at: row.get(4)?,
+        result: row.get(5)?,
+        changes: row.get(6)?,
+    })
+}
+
+// Every transaction, oldest first
+pub fn get_transactions(conn: &Connection) -> Result<Vec<TransactionRecord>> {
+    if !has_table(conn, "transactions")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM transactions t ORDER BY t.id ASC",
+        TRANSACTION_COLUMNS
+    ))?;
+    let transactions = stmt.query_map([], transaction_from_row)?;
+    transactions
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect transactions")
+}
+
+pub fn get_transaction(
+    conn: &Connection,
+    transaction_id: i64,
+) -> Result<Option<TransactionRecord>> {
+    if !has_table(conn, "transactions")? {
+        return Ok(None);
+    }
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM transactions t WHERE t.id = ?1",
+            TRANSACTION_COLUMNS
+        ),
+        params![transaction_id],
+        transaction_from_row,
+    )
+    .optional()
+    .context("Failed to query transaction")
+}
+
+pub fn get_transaction_changes(
+    conn: &Connection,
+    transaction_id: i64,
+) -> Result<Vec<TransactionChange>> {
+    if !has_table(conn, "transaction_changes")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT name, old_version, new_version, old_sha256, old_lpkg_path FROM transaction_changes
+         WHERE transaction_id = ?1 ORDER BY name ASC",
+    )?;
+    let changes = stmt.query_map(params![transaction_id], |row| {
+        Ok(TransactionChange {
+            name: row.get(0)?,
+            old_version: row.get(1)?,
+            new_version: row.get(2)?,
+            old_sha256: row.get(3)?,
+            old_lpkg_path: row.get(4)?,
+        })
+    })?;
+    changes
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to collect transaction changes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_tolerate_tables_an_older_database_lacks() {
+        // Read-only commands open a database created before these tables
+        // existed without adding them
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE packages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                version TEXT NOT NULL,
+                lpkg_path TEXT
+            )",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO packages (name, version, lpkg_path) VALUES ('old', '1.0.0', '/cache/old.lpkg')",
+            [],
+        )
+        .unwrap();
+
+        assert!(get_generations(&conn).unwrap().is_empty());
+        assert!(get_generation_packages(&conn, 1).unwrap().is_empty());
+        assert!(get_transactions(&conn).unwrap().is_empty());
+        assert!(get_transaction(&conn, 1).unwrap().is_none());
+        assert!(get_transaction_changes(&conn, 1).unwrap().is_empty());
+        assert!(get_pins(&conn).unwrap().is_empty());
+        assert!(get_providers(&conn, "old").unwrap().is_empty());
+        assert!(get_package_features(&conn, 1).unwrap().is_empty());
+        assert!(get_previous_version(&conn, "old").unwrap().is_none());
+        assert_eq!(
+            get_retained_package_files(&conn).unwrap(),
+            vec!["/cache/old.lpkg".to_string()]
+        );
+    }
+}
@@ -61,5 +61,155 @@ pub fn initialize_schema(conn: &mut Connection) -> Result<()> {
     )
     .context("Failed to create conflicts table")?;
 
+    // Create package_pins table for holds and version pins, kept by name so
+    // they survive upgrades and reinstalls
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS package_pins (
+            name TEXT PRIMARY KEY,
+            held INTEGER NOT NULL DEFAULT 0,
+            pin TEXT
+        )",
+        [],
+    )
+    .context("Failed to create package_pins table")?;
+
+    // Create previous_versions table for the cached packages of versions that
+    // were replaced, newest last, so they can be rolled back to offline
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS previous_versions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            version TEXT NOT NULL,
+            lpkg_path TEXT NOT NULL,
+            replaced_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )
+    .context("Failed to create previous_versions table")?;
+
+    // Create generation tables: a numbered snapshot of the installed packages
+    // (with their cached package files) and their file checksums after every
+    // change to the system
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS generations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            command TEXT NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )
+    .context("Failed to create generations table")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS generation_packages (
+            generation_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            version TEXT NOT NULL,
+            sha256 TEXT,
+            lpkg_path TEXT,
+            FOREIGN KEY (generation_id) REFERENCES generations(id) ON DELETE CASCADE
+        )",
+        [],
+    )
+    .context("Failed to create generation_packages table")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS generation_files (
+            generation_id INTEGER NOT NULL,
+            package_name TEXT NOT NULL,
+            path TEXT NOT NULL,
+            checksum TEXT,
+            FOREIGN KEY (generation_id) REFERENCES generations(id) ON DELETE CASCADE
+        )",
+        [],
+    )
+    .context("Failed to create generation_files table")?;
+
+    // Create provides table: virtual names an installed package provides, and
+    // the packages it replaced, which it stands in for whatever version is
+    // required
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS provides (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            package_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            version TEXT,
+            replaced INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY (package_id) REFERENCES packages(id) ON DELETE CASCADE
+        )",
+        [],
+    )
+    .context("Failed to create provides table")?;
+
+    // Create package_features table: optional features selected for an
+    // installed package
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS package_features (
+            package_id INTEGER NOT NULL,
+            feature TEXT NOT NULL,
+            FOREIGN KEY (package_id) REFERENCES packages(id) ON DELETE CASCADE,
+            UNIQUE(package_id, feature)
+        )",
+        [],
+    )
+    .context("Failed to create package_features table")?;
+
+    // Create transaction tables: every package operation with who ran it, its
+    // result and the packages it added, removed or changed
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS transactions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            command TEXT NOT NULL,
+            user TEXT NOT NULL,
+            started_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            finished_at TIMESTAMP,
+            result TEXT NOT NULL DEFAULT 'interrupted'
+        )",
+        [],
+    )
+    .context("Failed to create transactions table")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS transaction_changes (
+            transaction_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            old_version TEXT,
+            new_version TEXT,
+            old_sha256 TEXT,
+            old_lpkg_path TEXT,
+            FOREIGN KEY (transaction_id) REFERENCES transactions(id) ON DELETE CASCADE
+        )",
+        [],
+    )
+    .context("Failed to create transaction_changes table")?;
+
+    // Drop rows left behind by packages removed before foreign keys were
+    // enforced
+    for table in [
+        "package_files",
+        "dependencies",
+        "conflicts",
+        "provides",
+        "package_features",
+    ] {
+        conn.execute(
+            &format!(
+                "DELETE FROM {} WHERE package_id NOT IN (SELECT id FROM packages)",
+                table
+            ),
+            [],
+        )
+        .context(format!("Failed to clean up {} table", table))?;
+    }
+
     Ok(())
 }
+
+// Whether the database has a table. Read-only commands cannot add the tables
+// introduced since the database was created, so they read missing ones as
+// empty.
+pub fn has_table(conn: &Connection, name: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        [name],
+        |row| row.get(0),
+    )
+    .context(format!("Failed to look up table {}", name))
+}
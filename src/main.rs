@@ -2,6 +2,7 @@ use anyhow::Result;
 use clap::{Parser, Subcommand};
 
 mod commands;
+mod config;
 mod db;
 mod package;
 mod repository;
@@ -12,6 +13,9 @@ mod utils;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Manage the system installed under this directory, e.g. an image being built. Files are placed but no scripts run and no services are touched.
+    #[arg(long, global = true, value_name = "DIR")]
+    root: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -22,8 +26,14 @@ enum Commands {
     Setup,
     /// Install a local .lpkg package file
     Install {
-        /// Path to the .lpkg file
-        file: String,
+        /// Path to a .lpkg file, or name=version to install that version from the configured repositories
+        package: String,
+        /// Allow replacing the installed version with an older one
+        #[arg(long)]
+        allow_downgrade: bool,
+        /// Optional features to enable, e.g. --features gpu,codecs
+        #[arg(long, value_delimiter = ',')]
+        features: Vec<String>,
     },
     /// Remove an installed package
     Remove {
@@ -39,6 +49,12 @@ enum Commands {
     },
     /// Build a .lpkg file from a prepared directory
     Pack,
+    /// Check a package's meta.toml for problems
+    Lint {
+        /// Package source directory containing meta.toml
+        #[arg(default_value = ".")]
+        directory: String,
+    },
     /// Sign a package with a private key
     Sign {
         /// Path to the .lpkg file
@@ -54,19 +70,85 @@ enum Commands {
         /// Path to the .lpkg file
         package: String,
     },
-    /// Rollback a package installation
+    /// Restore the version of a package installed before its last upgrade
     Rollback {
-        /// ID of the package to rollback
-        package_id: i64,
+        /// Name of the package to roll back
+        package: String,
     },
     /// Update an installed package to its latest version
     Update {
         /// Name of the package to update
         package: String,
+        /// Install the newest available version even if it is older than the installed one
+        #[arg(long)]
+        allow_downgrade: bool,
+    },
+    /// Upgrade installed packages to the newest versions in the configured repositories
+    Upgrade {
+        /// Only upgrade these packages (and any dependencies they need)
+        packages: Vec<String>,
+        /// Leave this package at its current version (can be repeated)
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Only apply upgrades that fix security advisories
+        #[arg(long)]
+        security: bool,
+    },
+    /// Check installed packages against the cached security advisories
+    Audit,
+    /// List installed packages with newer versions in the cached repository indexes
+    Outdated {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Hold a package at its installed version, or list holds and pins
+    Hold {
+        /// Name of the package to hold
+        package: Option<String>,
+    },
+    /// Release a hold
+    Unhold {
+        /// Name of the held package
+        package: String,
+    },
+    /// Restrict a package to a version range, e.g. '>=2.1, <2.3'
+    Pin {
+        /// Name of the package to pin
+        package: String,
+        /// Allowed version range
+        range: String,
+    },
+    /// Remove a version pin
+    Unpin {
+        /// Name of the pinned package
+        package: String,
+    },
+    /// Snapshots of the installed packages
+    #[command(subcommand)]
+    Generations(GenerationsCommands),
+    /// List package transactions, or show or undo one
+    History {
+        #[command(subcommand)]
+        command: Option<HistoryCommands>,
     },
     /// Repository management commands
     #[command(subcommand)]
     Repo(RepoCommands),
+    /// Serve a repository directory over HTTP
+    Serve {
+        /// Directory containing index.json and the packages
+        dir: String,
+        /// Address to listen on
+        #[arg(short, long, default_value = "127.0.0.1:8080")]
+        bind: String,
+    },
+    /// Manage the local package download cache
+    #[command(subcommand)]
+    Cache(CacheCommands),
+    /// Create and install offline bundles for machines without network access
+    #[command(subcommand)]
+    Bundle(BundleCommands),
 }
 
 #[derive(Subcommand, Debug)]
@@ -77,6 +159,40 @@ enum RepoCommands {
         url: String,
         /// Name to identify the repository
         name: String,
+        /// Index URL of a mirror for this repository (can be repeated)
+        #[arg(long = "mirror")]
+        mirrors: Vec<String>,
+        /// Search priority, repositories with a lower value are searched first
+        #[arg(long, default_value_t = 50)]
+        priority: i32,
+        /// Probe all mirrors and use the fastest one first
+        #[arg(long)]
+        fastest: bool,
+        /// HTTP proxy for this repository, overriding the global proxy
+        #[arg(long)]
+        proxy: Option<String>,
+        /// PEM file with extra CA certificates to trust (can be repeated)
+        #[arg(long = "ca-bundle")]
+        ca_bundles: Vec<String>,
+        /// Command printing the credentials for this repository
+        #[arg(long, value_name = "COMMAND")]
+        auth_helper: Option<String>,
+    },
+    /// Generate index.json for a directory of .lpkg files
+    BuildIndex {
+        /// Directory containing the packages
+        dir: String,
+        /// Sign the index with this private key, writing index.json.sig
+        #[arg(short, long)]
+        sign: Option<String>,
+        /// Also write a zstd-compressed index.json.zst
+        #[arg(long)]
+        compress: bool,
+    },
+    /// Fetch the latest index of all repositories, or of a single one
+    Refresh {
+        /// Optional repository name to refresh
+        name: Option<String>,
     },
     /// Search for a package in a repository
     Search {
@@ -93,24 +209,128 @@ enum RepoCommands {
         /// Optional repository name to install from
         #[arg(short, long)]
         repo: Option<String>,
+        /// Optional features to enable, e.g. --features gpu,codecs
+        #[arg(long, value_delimiter = ',')]
+        features: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommands {
+    /// List cached package files
+    List,
+    /// Remove all cached package files
+    Clean,
+    /// Remove old cached versions of each package
+    Prune {
+        /// Number of newest versions to keep per package
+        #[arg(long, default_value_t = 1)]
+        keep: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BundleCommands {
+    /// Resolve packages and their dependencies into a single signed bundle file
+    Create {
+        /// Names of the packages to bundle
+        #[arg(required = true)]
+        packages: Vec<String>,
+        /// Path of the bundle file to write
+        #[arg(short, long)]
+        output: String,
+        /// Private key used to sign the bundle index
+        #[arg(short, long)]
+        sign: String,
+    },
+    /// Install all packages from a bundle without network access
+    Install {
+        /// Path to the bundle file
+        file: String,
+        /// Public key to trust in addition to those in /etc/lpkg/keys
+        #[arg(long)]
+        key: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum GenerationsCommands {
+    /// List recorded generations, marking the current one
+    List,
+    /// Install, remove and change packages to match an earlier generation
+    Switch {
+        /// Generation number
+        generation: i64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum HistoryCommands {
+    /// Show the packages a transaction changed and its result
+    Show {
+        /// Transaction number
+        id: i64,
+    },
+    /// Reverse a transaction using cached packages
+    Undo {
+        /// Transaction number
+        id: i64,
     },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    if let Some(root) = &cli.root {
+        utils::root::set(root)?;
+    }
 
-    let is_read_only_command = match &cli.command {
+    // Serving and building repositories and bundles only work on files, so
+    // the database is neither opened nor created for them
+    if let Some(result) = run_without_database(&cli.command) {
+        if let Err(e) = result {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let is_read_only_command = matches!(
+        &cli.command,
         Commands::List
-        | Commands::Info { .. }
-        | Commands::Verify { .. }
-        | Commands::Pack
-        | Commands::Sign { .. } => true,
-        Commands::Repo(repo_cmd) => match repo_cmd {
-            RepoCommands::Search { .. } => true,
-            _ => false,
-        },
-        _ => false,
-    };
+            | Commands::Info { .. }
+            | Commands::Verify { .. }
+            | Commands::Pack
+            | Commands::Lint { .. }
+            | Commands::Sign { .. }
+            | Commands::Repo(RepoCommands::Search { .. })
+            | Commands::Cache(CacheCommands::List)
+            | Commands::Outdated { .. }
+            | Commands::Audit
+            | Commands::Generations(GenerationsCommands::List)
+            | Commands::History {
+                command: None | Some(HistoryCommands::Show { .. })
+            }
+    );
+
+    // Operations that can change the installed packages are recorded as transactions
+    let is_package_operation = matches!(
+        &cli.command,
+        Commands::Install { .. }
+            | Commands::Remove { .. }
+            | Commands::Rollback { .. }
+            | Commands::Update { .. }
+            | Commands::Upgrade { .. }
+            | Commands::Repo(RepoCommands::Install { .. })
+            | Commands::Bundle(BundleCommands::Install { .. })
+            | Commands::Generations(GenerationsCommands::Switch { .. })
+            | Commands::History {
+                command: Some(HistoryCommands::Undo { .. })
+            }
+    );
+    let command = std::iter::once("lpkg".to_string())
+        .chain(std::env::args().skip(1))
+        .collect::<Vec<_>>()
+        .join(" ");
 
     let mut conn = db::connection::get_connection(is_read_only_command)?;
 
@@ -119,14 +339,31 @@ fn main() -> Result<()> {
         db::schema::initialize_schema(&mut conn)?;
     }
 
+    let transaction = if is_package_operation {
+        match commands::history::begin(&conn, &command) {
+            Ok(transaction) => Some(transaction),
+            Err(e) => {
+                eprintln!("Warning: failed to record transaction: {:#}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     let result = match &cli.command {
         Commands::Init => commands::init::init(&mut conn),
         Commands::Setup => commands::setup::setup(),
-        Commands::Install { file } => commands::install::install(&mut conn, file),
+        Commands::Install {
+            package,
+            allow_downgrade,
+            features,
+        } => commands::install::install_package(&mut conn, package, *allow_downgrade, features),
         Commands::Remove { package } => commands::remove::remove(&mut conn, package),
         Commands::List => commands::list::list(&conn),
         Commands::Info { package } => commands::info::info(&conn, package),
         Commands::Pack => commands::pack::pack(),
+        Commands::Lint { directory } => commands::lint::lint(directory),
         Commands::Sign {
             package,
             key,
@@ -135,23 +372,112 @@ fn main() -> Result<()> {
         Commands::Verify {
             package,
         } => commands::verify::verify_content_checksum(package),
-        Commands::Rollback { package_id } => commands::rollback::rollback(&mut conn, *package_id),
-        Commands::Update { package } => commands::update::update(&mut conn, package),
+        Commands::Rollback { package } => commands::rollback::rollback(&mut conn, package),
+        Commands::Update {
+            package,
+            allow_downgrade,
+        } => commands::update::update(&mut conn, package, *allow_downgrade),
+        Commands::Upgrade {
+            packages,
+            exclude,
+            security,
+        } => commands::upgrade::upgrade(&mut conn, packages, exclude, *security),
+        Commands::Audit => commands::audit::audit(&conn),
+        Commands::Outdated { json } => commands::outdated::outdated(&conn, *json),
+        Commands::Hold { package } => commands::hold::hold(&conn, package.as_deref()),
+        Commands::Unhold { package } => commands::hold::unhold(&conn, package),
+        Commands::Pin { package, range } => commands::hold::pin(&conn, package, range),
+        Commands::Unpin { package } => commands::hold::unpin(&conn, package),
         Commands::Repo(repo_cmd) => match repo_cmd {
-            RepoCommands::Add { url, name } => commands::repo::add_repo(&mut conn, url, name),
+            RepoCommands::Add {
+                url,
+                name,
+                mirrors,
+                priority,
+                fastest,
+                proxy,
+                ca_bundles,
+                auth_helper,
+            } => commands::repo::add_repo(
+                &mut conn,
+                url,
+                name,
+                mirrors,
+                *priority,
+                *fastest,
+                proxy.as_deref(),
+                ca_bundles,
+                auth_helper.as_deref(),
+            ),
+            RepoCommands::BuildIndex { .. } => unreachable!("handled without the database"),
+            RepoCommands::Refresh { name } => commands::repo::refresh_repos(name.as_deref()),
             RepoCommands::Search { package, repo } => {
                 commands::repo::search_repo(&conn, package, repo.as_deref())
             }
-            RepoCommands::Install { package, repo } => {
-                commands::repo::install_from_repo(&mut conn, package, repo.as_deref())
+            RepoCommands::Install {
+                package,
+                repo,
+                features,
+            } => commands::repo::install_from_repo(&mut conn, package, repo.as_deref(), features),
+        },
+        Commands::Serve { .. } => unreachable!("handled without the database"),
+        Commands::Cache(cache_cmd) => match cache_cmd {
+            CacheCommands::List => commands::cache::list(),
+            CacheCommands::Clean => commands::cache::clean(&conn),
+            CacheCommands::Prune { keep } => commands::cache::prune(&conn, *keep),
+        },
+        Commands::Generations(generations_cmd) => match generations_cmd {
+            GenerationsCommands::List => commands::generations::list(&conn),
+            GenerationsCommands::Switch { generation } => {
+                commands::generations::switch(&mut conn, *generation)
+            }
+        },
+        Commands::History { command } => match command {
+            None => commands::history::list(&conn),
+            Some(HistoryCommands::Show { id }) => commands::history::show(&conn, *id),
+            Some(HistoryCommands::Undo { id }) => commands::history::undo(&mut conn, *id),
+        },
+        Commands::Bundle(bundle_cmd) => match bundle_cmd {
+            BundleCommands::Create { .. } => unreachable!("handled without the database"),
+            BundleCommands::Install { file, key } => {
+                commands::bundle::install(&mut conn, file, key.as_deref())
             }
         },
     };
 
+    if let Some(transaction) = transaction
+        && let Err(e) = commands::history::finish(&mut conn, transaction, &result)
+    {
+        eprintln!("Warning: failed to record transaction: {:#}", e);
+    }
+
     if let Err(e) = result {
         eprintln!("Error: {:?}", e);
         std::process::exit(1);
     }
 
+    // Every completed change to the installed packages becomes a generation
+    if !is_read_only_command && let Err(e) = commands::generations::snapshot(&mut conn, &command) {
+        eprintln!("Warning: failed to record generation: {:#}", e);
+    }
+
     Ok(())
 }
+
+// Run a command that does not use the database, or return None
+fn run_without_database(command: &Commands) -> Option<Result<()>> {
+    match command {
+        Commands::Serve { dir, bind } => Some(commands::serve::serve(dir, bind)),
+        Commands::Repo(RepoCommands::BuildIndex {
+            dir,
+            sign,
+            compress,
+        }) => Some(commands::repo::build_index(dir, sign.as_deref(), *compress)),
+        Commands::Bundle(BundleCommands::Create {
+            packages,
+            output,
+            sign,
+        }) => Some(commands::bundle::create(packages, output, sign)),
+        _ => None,
+    }
+}
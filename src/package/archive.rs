@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
 use std::fs::{self, File};
+use std::io::Read;
 use std::path::Path;
 use tar::Archive;
 use zstd::stream::read::Decoder as ZstdDecoder;
@@ -30,7 +31,32 @@ pub fn extract_archive(file: &str, destination: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn create_archive(files_dir: &Path, meta_path: &Path, output_file: &str) -> Result<()> {
+// Read meta.toml straight out of a .lpkg archive without extracting the files
+pub fn read_metadata_file(file: &str) -> Result<String> {
+    let archive_file =
+        File::open(file).context(format!("Failed to open archive file: {}", file))?;
+    let decoder = ZstdDecoder::new(archive_file).context("Failed to create zstd decoder")?;
+    let mut archive = Archive::new(decoder);
+
+    for entry in archive.entries().context("Failed to read tar archive")? {
+        let mut entry = entry.context("Failed to read tar archive entry")?;
+        if entry.path()?.as_os_str() == "meta.toml" {
+            let mut content = String::new();
+            entry
+                .read_to_string(&mut content)
+                .context(format!("Failed to read meta.toml from {}", file))?;
+            return Ok(content);
+        }
+    }
+    Err(anyhow::anyhow!("No meta.toml found in {}", file))
+}
+
+pub fn create_archive(
+    files_dir: &Path,
+    scripts_dir: Option<&Path>,
+    meta_path: &Path,
+    output_file: &str,
+) -> Result<()> {
     let output_path = Path::new(output_file);
 
     // Create the output file
@@ -55,7 +81,21 @@ pub fn create_archive(files_dir: &Path, meta_path: &Path, output_file: &str) ->
                 "Failed to strip prefix from path {}",
                 path.display()
             ))?;
-            builder.append_path_with_name(path, &format!("files/{}", rel_path.display()))?;
+            builder.append_path_with_name(path, format!("files/{}", rel_path.display()))?;
+        }
+    }
+
+    // Add install, remove and upgrade scripts referenced from meta.toml
+    if let Some(scripts_dir) = scripts_dir {
+        for entry in WalkDir::new(scripts_dir).into_iter().filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_file() {
+                let rel_path = path.strip_prefix(scripts_dir).context(format!(
+                    "Failed to strip prefix from path {}",
+                    path.display()
+                ))?;
+                builder.append_path_with_name(path, format!("scripts/{}", rel_path.display()))?;
+            }
         }
     }
 
@@ -79,7 +119,10 @@ pub fn create_tar_and_checksum(source_dir: &Path) -> Result<String> {
     use crate::utils::checksum::calculate_sha256_from_reader;
     use walkdir::WalkDir;
 
-    println!("DEBUG: create_tar_and_checksum called with source_dir: {}", source_dir.display());
+    println!(
+        "DEBUG: create_tar_and_checksum called with source_dir: {}",
+        source_dir.display()
+    );
 
     let mut tar_builder = tar::Builder::new(Vec::new());
 
@@ -98,12 +141,17 @@ pub fn create_tar_and_checksum(source_dir: &Path) -> Result<String> {
     }
 
     if !found_files {
-        println!("DEBUG: No files found in source_dir: {}", source_dir.display());
-        return Err(anyhow::anyhow!("No files found in the directory to create checksum."));
+        println!(
+            "DEBUG: No files found in source_dir: {}",
+            source_dir.display()
+        );
+        return Err(anyhow::anyhow!(
+            "No files found in the directory to create checksum."
+        ));
     }
 
     let tar_data = tar_builder.into_inner().context("Failed to get tar data")?;
 
     calculate_sha256_from_reader(&mut &tar_data[..])
         .context("Failed to calculate checksum from tar data")
-}
\ No newline at end of file
+}
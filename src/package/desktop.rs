@@ -0,0 +1,312 @@
+use crate::package::metadata::{Desktop, MetaFile, check_relative_path};
+use crate::utils::root;
+use anyhow::Result;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Where desktop environments look for the files of locally installed
+// applications
+const APPLICATIONS_DIR: &str = "/usr/local/share/applications";
+const ICONS_DIR: &str = "/usr/local/share/icons/hicolor";
+const MIME_DIR: &str = "/usr/local/share/mime";
+const METAINFO_DIR: &str = "/usr/local/share/metainfo";
+
+const ICON_EXTENSIONS: &[&str] = &["png", "svg", "svgz", "xpm"];
+const SCALABLE_ICON_EXTENSIONS: &[&str] = &["svg", "svgz"];
+
+// A file of a package that is copied to a shared location
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedFile {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+}
+
+// The desktop integration files of a package extracted to `files_dir`:
+// those declared in [desktop] or, without it, whatever the package ships in
+// the standard locations under usr/share
+pub fn shared_files(meta_file: &MetaFile, files_dir: &Path) -> Result<Vec<SharedFile>> {
+    match &meta_file.desktop {
+        Some(desktop) => declared_files(desktop, &meta_file.package.name, files_dir),
+        None => Ok(discovered_files(files_dir)),
+    }
+}
+
+fn declared_files(
+    desktop: &Desktop,
+    package_name: &str,
+    files_dir: &Path,
+) -> Result<Vec<SharedFile>> {
+    let mut shared = Vec::new();
+    let lists = [
+        (&desktop.entries, "desktop", PathBuf::from(APPLICATIONS_DIR)),
+        (
+            &desktop.mime_types,
+            "xml",
+            Path::new(MIME_DIR).join("packages"),
+        ),
+        (&desktop.metainfo, "xml", PathBuf::from(METAINFO_DIR)),
+    ];
+    for (paths, extension, dest_dir) in lists {
+        for path in paths.iter().flatten() {
+            check_file(path, &[extension]).map_err(|e| anyhow::anyhow!("[desktop] {}", e))?;
+            let file_name = Path::new(path).file_name().unwrap_or_default();
+            shared.push(SharedFile {
+                source: package_file(files_dir, path)?,
+                dest: dest_dir.join(file_name),
+            });
+        }
+    }
+    for icon in desktop.icons.iter().flatten() {
+        check_icon(&icon.path, &icon.size).map_err(|e| anyhow::anyhow!("[desktop] {}", e))?;
+        let name = icon.name.as_deref().unwrap_or(package_name);
+        if name.is_empty() || name.contains('/') {
+            return Err(anyhow::anyhow!(
+                "[desktop] '{}' is not a valid icon name",
+                name
+            ));
+        }
+        let extension = extension(&icon.path).unwrap_or_default();
+        shared.push(SharedFile {
+            source: package_file(files_dir, &icon.path)?,
+            dest: Path::new(ICONS_DIR)
+                .join(&icon.size)
+                .join("apps")
+                .join(format!("{}.{}", name, extension)),
+        });
+    }
+    // Files of the same name in different directories would be installed
+    // over each other
+    for (i, file) in shared.iter().enumerate() {
+        if let Some(other) = shared[..i].iter().find(|other| other.dest == file.dest) {
+            return Err(anyhow::anyhow!(
+                "[desktop] {} and {} would both be installed as {}",
+                other
+                    .source
+                    .strip_prefix(files_dir)
+                    .unwrap_or(&other.source)
+                    .display(),
+                file.source
+                    .strip_prefix(files_dir)
+                    .unwrap_or(&file.source)
+                    .display(),
+                file.dest.display()
+            ));
+        }
+    }
+    Ok(shared)
+}
+
+fn package_file(files_dir: &Path, path: &str) -> Result<PathBuf> {
+    let source = files_dir.join(path);
+    if !source.is_file() {
+        return Err(anyhow::anyhow!(
+            "[desktop] declares {}, which is not a file in the package",
+            path
+        ));
+    }
+    Ok(source)
+}
+
+fn discovered_files(files_dir: &Path) -> Vec<SharedFile> {
+    let share = files_dir.join("usr/share");
+    let mut shared = Vec::new();
+    let mut add = |dir: PathBuf, extensions: &[&str], dest_dir: PathBuf| {
+        for source in files_in(&dir) {
+            if extension(&source.to_string_lossy()).is_some_and(|e| extensions.contains(&e)) {
+                let dest = dest_dir.join(source.file_name().unwrap_or_default());
+                // A file in both metainfo/ and appdata/ is installed once
+                if !shared.iter().any(|file: &SharedFile| file.dest == dest) {
+                    shared.push(SharedFile { source, dest });
+                }
+            }
+        }
+    };
+    add(
+        share.join("applications"),
+        &["desktop"],
+        PathBuf::from(APPLICATIONS_DIR),
+    );
+    for size in dirs_in(&share.join("icons/hicolor")) {
+        let size_name = size.file_name().unwrap_or_default();
+        add(
+            size.join("apps"),
+            ICON_EXTENSIONS,
+            Path::new(ICONS_DIR).join(size_name).join("apps"),
+        );
+    }
+    add(
+        share.join("mime/packages"),
+        &["xml"],
+        Path::new(MIME_DIR).join("packages"),
+    );
+    // appdata/ is the old name of metainfo/
+    add(
+        share.join("metainfo"),
+        &["xml"],
+        PathBuf::from(METAINFO_DIR),
+    );
+    add(share.join("appdata"), &["xml"], PathBuf::from(METAINFO_DIR));
+    shared
+}
+
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    files
+}
+
+fn dirs_in(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+fn extension(path: &str) -> Option<&str> {
+    Path::new(path).extension()?.to_str()
+}
+
+// A declared file must stay inside files/ and have one of `extensions`
+pub fn check_file(path: &str, extensions: &[&str]) -> Result<(), String> {
+    check_relative_path(path, "files/").map_err(|e| format!("'{}' {}", path, e))?;
+    if !extension(path).is_some_and(|e| extensions.contains(&e)) {
+        return Err(format!(
+            "'{}' must be a .{} file",
+            path,
+            extensions.join(" or .")
+        ));
+    }
+    Ok(())
+}
+
+// Icons go into a hicolor size directory: "scalable" for SVG images, or a
+// square size such as "48x48"
+pub fn check_icon(path: &str, size: &str) -> Result<(), String> {
+    if size == "scalable" {
+        return check_file(path, SCALABLE_ICON_EXTENSIONS);
+    }
+    let square = size.split_once('x').is_some_and(|(width, height)| {
+        width == height && width.parse::<u32>().is_ok_and(|width| width > 0)
+    });
+    if !square {
+        return Err(format!(
+            "icon size '{}' must be \"scalable\" or a square size such as \"48x48\"",
+            size
+        ));
+    }
+    check_file(path, ICON_EXTENSIONS)
+}
+
+// Have the desktop environment pick up the shared files among `paths`, which
+// were just installed or removed. Missing tools are skipped and failures
+// only warned about, as the files themselves are in place.
+pub fn refresh_caches<S: AsRef<str>>(paths: &[S]) {
+    // Only the caches of the running system are refreshed
+    if root::is_alternate() {
+        return;
+    }
+    let touched = |dir: &str| paths.iter().any(|p| Path::new(p.as_ref()).starts_with(dir));
+    if touched(APPLICATIONS_DIR) {
+        run_update("update-desktop-database", &["-q", APPLICATIONS_DIR]);
+    }
+    if touched(ICONS_DIR) {
+        run_update("gtk-update-icon-cache", &["-q", "-t", "-f", ICONS_DIR]);
+    }
+    if touched(MIME_DIR) {
+        run_update("update-mime-database", &[MIME_DIR]);
+    }
+    if touched(METAINFO_DIR) {
+        run_update("appstreamcli", &["refresh-cache"]);
+    }
+}
+
+fn run_update(tool: &str, args: &[&str]) {
+    match Command::new(tool).args(args).output() {
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Warning: failed to run {}: {}", tool, e),
+        Ok(output) if !output.status.success() => eprintln!(
+            "Warning: {} failed: {}",
+            tool,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Ok(_) => println!("Ran {}.", tool),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::metadata::parse_metadata;
+
+    #[test]
+    fn test_shared_files() {
+        let files = tempfile::tempdir().unwrap();
+        for path in [
+            "usr/share/applications/demo.desktop",
+            "usr/share/icons/hicolor/48x48/apps/demo.png",
+            "usr/share/icons/hicolor/scalable/apps/demo.svg",
+            "usr/share/metainfo/org.example.demo.metainfo.xml",
+            "art/logo.svg",
+        ] {
+            let path = files.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let dests = |meta: &str| -> Vec<String> {
+            let meta_file = parse_metadata(meta).unwrap();
+            shared_files(&meta_file, files.path())
+                .unwrap()
+                .iter()
+                .map(|f| f.dest.to_string_lossy().into_owned())
+                .collect()
+        };
+
+        let package = "[package]\nname = \"demo\"\nversion = \"1.0.0\"\n";
+        assert_eq!(
+            dests(package),
+            [
+                "/usr/local/share/applications/demo.desktop",
+                "/usr/local/share/icons/hicolor/48x48/apps/demo.png",
+                "/usr/local/share/icons/hicolor/scalable/apps/demo.svg",
+                "/usr/local/share/metainfo/org.example.demo.metainfo.xml",
+            ]
+        );
+
+        let declared = format!(
+            "{}\n[desktop]\nentries = [\"usr/share/applications/demo.desktop\"]\nicons = [{{ path = \"art/logo.svg\", size = \"scalable\", name = \"org.example.demo\" }}]\n",
+            package
+        );
+        assert_eq!(
+            dests(&declared),
+            [
+                "/usr/local/share/applications/demo.desktop",
+                "/usr/local/share/icons/hicolor/scalable/apps/org.example.demo.svg",
+            ]
+        );
+
+        let clashing = format!(
+            "{}\n[desktop]\nentries = [\"usr/share/applications/demo.desktop\", \"art/demo.desktop\"]\n",
+            package
+        );
+        fs::write(files.path().join("art/demo.desktop"), "").unwrap();
+        let meta_file = parse_metadata(&clashing).unwrap();
+        assert!(shared_files(&meta_file, files.path()).is_err());
+
+        assert!(check_icon("art/logo.png", "scalable").is_err());
+        assert!(check_icon("art/logo.png", "48x32").is_err());
+        assert!(check_icon("art/logo.png", "64x64").is_ok());
+    }
+}
@@ -0,0 +1,905 @@
+use crate::package::metadata::{
+    check_command_name, check_env_name, check_relative_path, parse_provide,
+};
+use crate::package::{desktop, systemd};
+use crate::utils::platform;
+use semver::{Version, VersionReq};
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use toml::Spanned;
+use toml_edit::{ImDocument, Item, Key, Table, Value};
+
+const TOP_LEVEL_FIELDS: &[&str] = &[
+    "package",
+    "dependencies",
+    "optional-dependencies",
+    "bin",
+    "desktop",
+    "unit",
+];
+const PACKAGE_FIELDS: &[&str] = &[
+    "name",
+    "version",
+    "description",
+    "license",
+    "authors",
+    "homepage",
+    "repository",
+    "content_checksum",
+    "scripts",
+    "application_id",
+    "arch",
+    "libc",
+    "min_kernel",
+    "provides",
+    "replaces",
+];
+const DESKTOP_FIELDS: &[&str] = &["entries", "icons", "mime_types", "metainfo"];
+const ICON_FIELDS: &[&str] = &["path", "size", "name"];
+const BIN_FIELDS: &[&str] = &["name", "path", "working_dir", "env", "wrapper"];
+const UNIT_FIELDS: &[&str] = &["path", "enable", "start", "restart"];
+const SCRIPT_FIELDS: &[&str] = &[
+    "pre_install",
+    "pre_remove",
+    "post_install",
+    "post_remove",
+    "pre_upgrade",
+    "post_upgrade",
+];
+
+// A problem found in meta.toml, with the 1-based line and column it refers to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+// A TOML value that keeps the position of every key and value in it, unlike
+// toml::Value
+enum Node {
+    Table(Vec<(Spanned<String>, Located)>),
+    Array(Vec<Located>),
+    String(String),
+    Boolean(bool),
+    Other(&'static str), // Integers, floats and dates, by type name
+}
+
+impl Node {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Node::Table(_) => "a table",
+            Node::Array(_) => "an array",
+            Node::String(_) => "a string",
+            Node::Boolean(_) => "a boolean",
+            Node::Other(name) => name,
+        }
+    }
+}
+
+// A Node with its position. Tables only implied by a header such as [a.b],
+// or by a dotted key, have no position of their own and get the position of
+// their key instead.
+struct Located {
+    span: Option<Range<usize>>,
+    node: Node,
+}
+
+impl Located {
+    fn get_ref(&self) -> &Node {
+        &self.node
+    }
+
+    fn span(&self) -> Range<usize> {
+        self.span.clone().unwrap_or_default()
+    }
+
+    fn from_item(item: &Item) -> Located {
+        match item {
+            Item::Value(value) => Located::from_value(value),
+            Item::Table(table) => Located::from_table(table),
+            Item::ArrayOfTables(tables) => Located {
+                span: tables.span(),
+                node: Node::Array(tables.iter().map(Located::from_table).collect()),
+            },
+            Item::None => Located {
+                span: None,
+                node: Node::Table(Vec::new()),
+            },
+        }
+    }
+
+    fn from_table(table: &Table) -> Located {
+        Located {
+            span: table.span(),
+            node: Node::Table(entries(
+                table
+                    .iter()
+                    .map(|(name, item)| (table.key(name), Located::from_item(item))),
+            )),
+        }
+    }
+
+    fn from_value(value: &Value) -> Located {
+        let node = match value {
+            Value::String(s) => Node::String(s.value().clone()),
+            Value::Boolean(b) => Node::Boolean(*b.value()),
+            Value::Integer(_) => Node::Other("an integer"),
+            Value::Float(_) => Node::Other("a float"),
+            Value::Datetime(_) => Node::Other("a date"),
+            Value::Array(array) => Node::Array(array.iter().map(Located::from_value).collect()),
+            Value::InlineTable(table) => Node::Table(entries(
+                table
+                    .iter()
+                    .map(|(name, value)| (table.key(name), Located::from_value(value))),
+            )),
+        };
+        Located {
+            span: value.span(),
+            node,
+        }
+    }
+}
+
+fn entries<'k>(
+    items: impl Iterator<Item = (Option<&'k Key>, Located)>,
+) -> Vec<(Spanned<String>, Located)> {
+    items
+        .filter_map(|(key, mut value)| {
+            let key = key?;
+            let key_span = key.span().unwrap_or_default();
+            value.span.get_or_insert(key_span.clone());
+            Some((Spanned::new(key_span, key.get().to_string()), value))
+        })
+        .collect()
+}
+
+// Check the content of a meta.toml. Declared scripts are looked up in
+// `scripts_dir`. Returns the problems found, in the order they appear.
+pub fn lint(content: &str, scripts_dir: &Path) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        content,
+        scripts_dir,
+        diagnostics: Vec::new(),
+    };
+    match ImDocument::parse(content) {
+        Ok(document) => linter.check_root(&Located::from_item(document.as_item())),
+        Err(e) => {
+            let span = e.span().unwrap_or(0..0);
+            linter.report(&span, e.message().to_string());
+        }
+    }
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+struct Linter<'a> {
+    content: &'a str,
+    scripts_dir: &'a Path,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, span: &Range<usize>, message: String) {
+        let before = &self.content[..span.start.min(self.content.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rfind('\n')
+            .map_or(before, |newline| &before[newline + 1..])
+            .chars()
+            .count()
+            + 1;
+        self.diagnostics.push(Diagnostic {
+            line,
+            column,
+            message,
+        });
+    }
+
+    // The entries of a table value, or None (after reporting it) for anything else
+    fn table<'n>(
+        &mut self,
+        key: &str,
+        value: &'n Located,
+    ) -> Option<&'n [(Spanned<String>, Located)]> {
+        match value.get_ref() {
+            Node::Table(entries) => Some(entries),
+            other => {
+                self.report(
+                    &value.span(),
+                    format!("'{}' must be a table, found {}", key, other.type_name()),
+                );
+                None
+            }
+        }
+    }
+
+    fn string<'n>(&mut self, key: &str, value: &'n Located) -> Option<&'n str> {
+        match value.get_ref() {
+            Node::String(s) => Some(s),
+            other => {
+                self.report(
+                    &value.span(),
+                    format!("'{}' must be a string, found {}", key, other.type_name()),
+                );
+                None
+            }
+        }
+    }
+
+    fn boolean(&mut self, key: &str, value: &Located) -> Option<bool> {
+        match value.get_ref() {
+            Node::Boolean(b) => Some(*b),
+            other => {
+                self.report(
+                    &value.span(),
+                    format!("'{}' must be a boolean, found {}", key, other.type_name()),
+                );
+                None
+            }
+        }
+    }
+
+    fn unknown_field(&mut self, key: &Spanned<String>, known: &[&str]) {
+        let message = match suggestion(key.get_ref(), known) {
+            Some(similar) => format!(
+                "unknown field '{}', did you mean '{}'?",
+                key.get_ref(),
+                similar
+            ),
+            None => format!(
+                "unknown field '{}', expected one of: {}",
+                key.get_ref(),
+                known.join(", ")
+            ),
+        };
+        self.report(&key.span(), message);
+    }
+
+    fn check_root(&mut self, root: &Located) {
+        let Some(entries) = self.table("meta.toml", root) else {
+            return;
+        };
+        let mut has_package = false;
+        for (key, value) in entries {
+            match key.get_ref().as_str() {
+                "package" => {
+                    has_package = true;
+                    self.check_package(key, value);
+                }
+                "dependencies" => self.check_dependencies("dependencies", value),
+                "optional-dependencies" => self.check_optional_dependencies(value),
+                "bin" => self.check_bin(value),
+                "desktop" => self.check_desktop(value),
+                "unit" => self.check_units(value),
+                _ => self.unknown_field(key, TOP_LEVEL_FIELDS),
+            }
+        }
+        if !has_package {
+            self.report(&(0..0), "missing [package] table".to_string());
+        }
+    }
+
+    fn check_package(&mut self, package_key: &Spanned<String>, package: &Located) {
+        let Some(entries) = self.table("package", package) else {
+            return;
+        };
+        for required in ["name", "version"] {
+            if !entries.iter().any(|(key, _)| key.get_ref() == required) {
+                self.report(
+                    &package_key.span(),
+                    format!("missing required field 'package.{}'", required),
+                );
+            }
+        }
+        for (key, value) in entries {
+            let field = key.get_ref().as_str();
+            match field {
+                "name" => {
+                    if let Some(name) = self.string(field, value) {
+                        self.check_name(name, &value.span());
+                    }
+                }
+                "version" => {
+                    if let Some(version) = self.string(field, value)
+                        && let Err(e) = Version::parse(version)
+                    {
+                        self.report(
+                            &value.span(),
+                            format!("'{}' is not a valid semantic version: {}", version, e),
+                        );
+                    }
+                }
+                "license" => {
+                    if let Some(license) = self.string(field, value)
+                        && let Err(e) = spdx::Expression::parse(license)
+                    {
+                        self.report(
+                            &value.span(),
+                            format!(
+                                "'{}' is not a valid SPDX license expression: {}",
+                                license, e.reason
+                            ),
+                        );
+                    }
+                }
+                "homepage" | "repository" => {
+                    if let Some(url) = self.string(field, value) {
+                        self.check_url(field, url, &value.span());
+                    }
+                }
+                "arch" => {
+                    if let Some(arch) = self.string(field, value)
+                        && platform::normalize_arch(arch).is_none()
+                    {
+                        self.report(
+                            &value.span(),
+                            format!(
+                                "unknown architecture '{}', expected one of: {}",
+                                arch,
+                                platform::ARCHITECTURES.join(", ")
+                            ),
+                        );
+                    }
+                }
+                "libc" => {
+                    if let Some(libc) = self.string(field, value)
+                        && let Err(e) = platform::LibcRequirement::parse(libc)
+                    {
+                        self.report(&value.span(), e);
+                    }
+                }
+                "min_kernel" => {
+                    if let Some(kernel) = self.string(field, value)
+                        && platform::parse_version(kernel).is_none()
+                    {
+                        self.report(
+                            &value.span(),
+                            format!("'{}' is not a valid kernel version", kernel),
+                        );
+                    }
+                }
+                "authors" | "replaces" => {
+                    self.strings(field, value);
+                }
+                "provides" => self.check_provides(value),
+                "scripts" => self.check_scripts(value),
+                "description" | "content_checksum" | "application_id" => {
+                    self.string(field, value);
+                }
+                _ => self.unknown_field(key, PACKAGE_FIELDS),
+            }
+        }
+    }
+
+    fn check_name(&mut self, name: &str, span: &Range<usize>) {
+        if name.is_empty() {
+            self.report(span, "package name cannot be empty".to_string());
+        } else if name.starts_with('.') {
+            // Names like "." and ".." would point the package's directories
+            // somewhere else
+            self.report(
+                span,
+                format!("package name '{}' may not start with '.'", name),
+            );
+        } else if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'))
+        {
+            // The name becomes part of file and directory names
+            self.report(
+                span,
+                format!(
+                    "package name '{}' may only contain letters, digits, '-', '_', '.' and '+'",
+                    name
+                ),
+            );
+        }
+    }
+
+    fn check_url(&mut self, field: &str, value: &str, span: &Range<usize>) {
+        match url::Url::parse(value) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+            Ok(_) => self.report(
+                span,
+                format!(
+                    "'{}' must be an http or https URL, found '{}'",
+                    field, value
+                ),
+            ),
+            Err(e) => self.report(
+                span,
+                format!("'{}' is not a valid URL: {} ('{}')", field, e, value),
+            ),
+        }
+    }
+
+    // The strings of an array value with their positions. Anything else is
+    // reported and left out.
+    fn strings<'n>(&mut self, key: &str, value: &'n Located) -> Vec<(&'n str, Range<usize>)> {
+        let Node::Array(items) = value.get_ref() else {
+            self.report(
+                &value.span(),
+                format!(
+                    "'{}' must be an array of strings, found {}",
+                    key,
+                    value.get_ref().type_name()
+                ),
+            );
+            return Vec::new();
+        };
+        items
+            .iter()
+            .filter_map(|item| Some((self.string(key, item)?, item.span())))
+            .collect()
+    }
+
+    fn check_provides(&mut self, value: &Located) {
+        for (entry, span) in self.strings("provides", value) {
+            let (name, version) = parse_provide(entry);
+            if let Some(version) = version
+                && let Err(e) = Version::parse(version)
+            {
+                self.report(
+                    &span,
+                    format!(
+                        "'{}' provides {} with an invalid version '{}': {}",
+                        entry, name, version, e
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_scripts(&mut self, value: &Located) {
+        let Some(entries) = self.table("scripts", value) else {
+            return;
+        };
+        for (key, value) in entries {
+            if !SCRIPT_FIELDS.contains(&key.get_ref().as_str()) {
+                self.unknown_field(key, SCRIPT_FIELDS);
+                continue;
+            }
+            let Some(script) = self.string(key.get_ref(), value) else {
+                continue;
+            };
+            // Scripts are run from scripts/ and must not name a file outside it
+            if let Err(e) = check_relative_path(script, "scripts/") {
+                self.report(&value.span(), format!("'{}' {}", key.get_ref(), e));
+                continue;
+            }
+            if !self.scripts_dir.join(script).is_file() {
+                self.report(
+                    &value.span(),
+                    format!("script '{}' does not exist in scripts/", script),
+                );
+            }
+        }
+    }
+
+    fn check_optional_dependencies(&mut self, value: &Located) {
+        let Some(features) = self.table("optional-dependencies", value) else {
+            return;
+        };
+        for (feature, dependencies) in features {
+            self.check_dependencies(feature.get_ref(), dependencies);
+        }
+    }
+
+    fn check_bin(&mut self, value: &Located) {
+        let Node::Array(items) = value.get_ref() else {
+            self.report(
+                &value.span(),
+                format!(
+                    "'bin' must be an array of tables ([[bin]]), found {}",
+                    value.get_ref().type_name()
+                ),
+            );
+            return;
+        };
+        let mut names = Vec::new();
+        for item in items {
+            let Some(entries) = self.table("bin", item) else {
+                continue;
+            };
+            for required in ["name", "path"] {
+                if !entries.iter().any(|(key, _)| key.get_ref() == required) {
+                    self.report(
+                        &item.span(),
+                        format!("missing required field 'bin.{}'", required),
+                    );
+                }
+            }
+            let mut needs_wrapper = None;
+            let mut wrapper = None;
+            for (key, value) in entries {
+                let field = key.get_ref().as_str();
+                match field {
+                    "name" => {
+                        let Some(name) = self.string(field, value) else {
+                            continue;
+                        };
+                        if let Err(e) = check_command_name(name) {
+                            self.report(&value.span(), e);
+                        } else if names.contains(&name) {
+                            self.report(
+                                &value.span(),
+                                format!("command '{}' is declared more than once", name),
+                            );
+                        }
+                        names.push(name);
+                    }
+                    "path" | "working_dir" => {
+                        if let Some(path) = self.string(field, value)
+                            && let Err(e) = check_relative_path(path, "files/")
+                        {
+                            self.report(&value.span(), format!("'{}' {}", field, e));
+                        }
+                        if field == "working_dir" {
+                            needs_wrapper = Some(key);
+                        }
+                    }
+                    "env" => {
+                        needs_wrapper = Some(key);
+                        let Some(variables) = self.table(field, value) else {
+                            continue;
+                        };
+                        for (name, value) in variables {
+                            if let Err(e) = check_env_name(name.get_ref()) {
+                                self.report(&name.span(), e);
+                            }
+                            self.string(name.get_ref(), value);
+                        }
+                    }
+                    "wrapper" => wrapper = self.boolean(field, value).map(|b| (b, value)),
+                    _ => self.unknown_field(key, BIN_FIELDS),
+                }
+            }
+            if let (Some((false, value)), Some(key)) = (wrapper, needs_wrapper) {
+                self.report(
+                    &value.span(),
+                    format!(
+                        "'{}' needs a wrapper script, so 'wrapper' cannot be false",
+                        key.get_ref()
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_units(&mut self, value: &Located) {
+        let Node::Array(items) = value.get_ref() else {
+            self.report(
+                &value.span(),
+                format!(
+                    "'unit' must be an array of tables ([[unit]]), found {}",
+                    value.get_ref().type_name()
+                ),
+            );
+            return;
+        };
+        let mut names = Vec::new();
+        for item in items {
+            let Some(entries) = self.table("unit", item) else {
+                continue;
+            };
+            if !entries.iter().any(|(key, _)| key.get_ref() == "path") {
+                self.report(
+                    &item.span(),
+                    "missing required field 'unit.path'".to_string(),
+                );
+            }
+            for (key, value) in entries {
+                let field = key.get_ref().as_str();
+                match field {
+                    "path" => {
+                        let Some(path) = self.string(field, value) else {
+                            continue;
+                        };
+                        let name = path.rsplit('/').next().unwrap_or_default();
+                        if let Err(e) = systemd::check_unit(path) {
+                            self.report(&value.span(), e);
+                        } else if names.contains(&name) {
+                            self.report(
+                                &value.span(),
+                                format!("unit '{}' is declared more than once", name),
+                            );
+                        }
+                        names.push(name);
+                    }
+                    "enable" | "start" | "restart" => {
+                        self.boolean(field, value);
+                    }
+                    _ => self.unknown_field(key, UNIT_FIELDS),
+                }
+            }
+        }
+    }
+
+    fn check_desktop(&mut self, value: &Located) {
+        let Some(entries) = self.table("desktop", value) else {
+            return;
+        };
+        for (key, value) in entries {
+            let field = key.get_ref().as_str();
+            let extension = match field {
+                "entries" => "desktop",
+                "mime_types" | "metainfo" => "xml",
+                "icons" => {
+                    self.check_icons(value);
+                    continue;
+                }
+                _ => {
+                    self.unknown_field(key, DESKTOP_FIELDS);
+                    continue;
+                }
+            };
+            for (path, span) in self.strings(field, value) {
+                if let Err(e) = desktop::check_file(path, &[extension]) {
+                    self.report(&span, e);
+                }
+            }
+        }
+    }
+
+    fn check_icons(&mut self, value: &Located) {
+        let Node::Array(items) = value.get_ref() else {
+            self.report(
+                &value.span(),
+                format!(
+                    "'icons' must be an array of tables, found {}",
+                    value.get_ref().type_name()
+                ),
+            );
+            return;
+        };
+        for item in items {
+            let Some(entries) = self.table("icons", item) else {
+                continue;
+            };
+            let (mut path, mut size) = (None, None);
+            for (key, value) in entries {
+                let field = key.get_ref().as_str();
+                match field {
+                    "path" => path = self.string(field, value).map(|path| (path, value)),
+                    "size" => size = self.string(field, value),
+                    "name" => {
+                        if let Some(name) = self.string(field, value)
+                            && (name.is_empty() || name.contains('/'))
+                        {
+                            self.report(
+                                &value.span(),
+                                format!("'{}' is not a valid icon name", name),
+                            );
+                        }
+                    }
+                    _ => self.unknown_field(key, ICON_FIELDS),
+                }
+            }
+            for required in ["path", "size"] {
+                if !entries.iter().any(|(key, _)| key.get_ref() == required) {
+                    self.report(
+                        &item.span(),
+                        format!("missing required field 'icons.{}'", required),
+                    );
+                }
+            }
+            if let (Some((path, value)), Some(size)) = (path, size)
+                && let Err(e) = desktop::check_icon(path, size)
+            {
+                self.report(&value.span(), e);
+            }
+        }
+    }
+
+    fn check_dependencies(&mut self, key: &str, value: &Located) {
+        let Some(entries) = self.table(key, value) else {
+            return;
+        };
+        for (key, value) in entries {
+            let name = key.get_ref();
+            let Some(constraint) = self.string(name, value) else {
+                continue;
+            };
+            if let Err(e) = VersionReq::parse(constraint) {
+                self.report(
+                    &value.span(),
+                    format!(
+                        "invalid version constraint '{}' for dependency '{}': {}",
+                        constraint, name, e
+                    ),
+                );
+            }
+        }
+    }
+}
+
+// The known field closest to a misspelled one, if any is close enough to be
+// a likely typo
+fn suggestion<'a>(field: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|candidate| (edit_distance(field, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_reports_positions() {
+        let scripts = tempfile::tempdir().unwrap();
+        std::fs::write(scripts.path().join("setup.sh"), "").unwrap();
+        let content = r#"[package]
+name = "demo"
+version = "1.0"
+licnese = "MIT"
+homepage = "not a url"
+
+[package.scripts]
+post_install = "setup.sh"
+pre_remove = "missing.sh"
+
+[dependencies]
+foo = ">=1.0"
+bar = "~>2"
+"#;
+        let found: Vec<String> = lint(content, scripts.path())
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(found.len(), 5, "{:?}", found);
+        assert!(found[0].starts_with("3:11: '1.0' is not a valid semantic version"));
+        assert_eq!(
+            found[1],
+            "4:1: unknown field 'licnese', did you mean 'license'?"
+        );
+        assert!(found[2].starts_with("5:12: 'homepage' is not a valid URL"));
+        assert_eq!(
+            found[3],
+            "9:14: script 'missing.sh' does not exist in scripts/"
+        );
+        assert!(found[4].starts_with("13:7: invalid version constraint '~>2'"));
+    }
+
+    #[test]
+    fn test_lint_syntax_error() {
+        let found = lint(
+            "[package]\nname = \"demo\nversion = \"1.0.0\"\n",
+            Path::new("."),
+        );
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].line, 2);
+    }
+
+    #[test]
+    fn test_lint_optional_dependencies() {
+        // [optional-dependencies] itself is only implied by the headers
+        let content = r#"[package]
+name = "demo"
+version = "1.0.0"
+
+[optional-dependencies.gpu]
+vulkan = "^1"
+
+[optional-dependencies.codecs]
+ffmpeg = "six"
+"#;
+        let found = lint(content, Path::new("."));
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(
+            found[0]
+                .to_string()
+                .starts_with("9:10: invalid version constraint 'six'")
+        );
+    }
+    #[test]
+    fn test_lint_bin() {
+        let content = r#"[package]
+name = "demo"
+version = "1.0.0"
+
+[[bin]]
+name = "demo"
+path = "usr/bin/demo"
+
+[[bin]]
+name = "demo-gui"
+path = "../demo-gui"
+env = { "GDK-BACKEND" = "x11" }
+wrapper = false
+
+[[bin]]
+name = "demo"
+"#;
+        let found: Vec<String> = lint(content, Path::new("."))
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(found.len(), 5, "{:?}", found);
+        assert!(found[0].starts_with("11:8: 'path' may not contain '..'"));
+        assert!(found[1].starts_with("12:9: 'GDK-BACKEND' is not a valid environment"));
+        assert!(found[2].starts_with("13:11: 'env' needs a wrapper script"));
+        assert!(found[3].starts_with("15:1: missing required field 'bin.path'"));
+        assert!(found[4].starts_with("16:8: command 'demo' is declared more than once"));
+    }
+    #[test]
+    fn test_lint_desktop() {
+        let content = r#"[package]
+name = "demo"
+version = "1.0.0"
+
+[desktop]
+entries = ["share/demo.desktop", "share/demo.txt"]
+metainfo = ["/usr/share/metainfo/demo.xml"]
+icons = [
+    { path = "icons/demo.svg", size = "scalable" },
+    { path = "icons/demo.png", size = "scalable" },
+    { path = "icons/demo-64.png", size = "64x48" },
+    { path = "icons/demo-32.png" },
+]
+"#;
+        let found: Vec<String> = lint(content, Path::new("."))
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(found.len(), 5, "{:?}", found);
+        assert_eq!(found[0], "6:34: 'share/demo.txt' must be a .desktop file");
+        assert!(found[1].starts_with("7:13: '/usr/share/metainfo/demo.xml' must be relative"));
+        assert_eq!(
+            found[2],
+            "10:14: 'icons/demo.png' must be a .svg or .svgz file"
+        );
+        assert!(found[3].starts_with("11:14: icon size '64x48' must be"));
+        assert_eq!(found[4], "12:5: missing required field 'icons.size'");
+    }
+
+    #[test]
+    fn test_lint_name_and_script_paths() {
+        let content = r#"[package]
+name = ".."
+version = "1.0.0"
+
+[package.scripts]
+post_install = "../../etc/setup.sh"
+pre_remove = "/bin/true"
+"#;
+        let found: Vec<String> = lint(content, Path::new("."))
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "2:8: package name '..' may not start with '.'",
+                "6:16: 'post_install' may not contain '..', found '../../etc/setup.sh'",
+                "7:14: 'pre_remove' must be relative to scripts/, found '/bin/true'",
+            ]
+        );
+    }
+}
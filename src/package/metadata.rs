@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use toml::from_str;
@@ -15,6 +16,11 @@ pub struct PackageMetadata {
     pub content_checksum: Option<String>,
     pub scripts: Option<Scripts>,
     pub application_id: Option<String>,
+    pub arch: Option<String>, // Architecture the package is built for, or "any"
+    pub libc: Option<String>, // C library it links against, e.g. "glibc >= 2.31" or "musl"
+    pub min_kernel: Option<String>, // Oldest Linux kernel it runs on, e.g. "5.10"
+    pub provides: Option<Vec<String>>, // Virtual packages it provides, as "name" or "name version"
+    pub replaces: Option<Vec<String>>, // Packages it takes over from, e.g. after a rename
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -23,6 +29,8 @@ pub struct Scripts {
     pub pre_remove: Option<String>,
     pub post_install: Option<String>,
     pub post_remove: Option<String>,
+    pub pre_upgrade: Option<String>, // Run before an installed version is replaced
+    pub post_upgrade: Option<String>, // Run after the new version is in place
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -30,6 +38,159 @@ pub struct MetaFile {
     pub package: PackageMetadata,
     #[serde(default)]
     pub dependencies: Option<HashMap<String, String>>,
+    // Dependencies only needed by optional features, by feature name
+    #[serde(default, rename = "optional-dependencies")]
+    pub optional_dependencies: Option<HashMap<String, HashMap<String, String>>>,
+    // Commands the package puts on the PATH, from [[bin]] tables
+    #[serde(default)]
+    pub bin: Option<Vec<BinEntry>>,
+    // Files registered with the desktop environment
+    #[serde(default)]
+    pub desktop: Option<Desktop>,
+    // systemd units the package installs, from [[unit]] tables
+    #[serde(default)]
+    pub unit: Option<Vec<Unit>>,
+}
+
+impl MetaFile {
+    // Names of the optional features, sorted
+    pub fn features(&self) -> Vec<&String> {
+        let mut features: Vec<&String> = self
+            .optional_dependencies
+            .iter()
+            .flatten()
+            .map(|(name, _)| name)
+            .collect();
+        features.sort();
+        features
+    }
+
+    // The dependencies needed with the given features selected, as
+    // (name, constraint)
+    pub fn dependencies_with(&self, features: &[String]) -> Vec<(&String, &String)> {
+        let mut dependencies: Vec<(&String, &String)> =
+            self.dependencies.iter().flatten().collect();
+        for feature in features {
+            if let Some(optional) = self
+                .optional_dependencies
+                .as_ref()
+                .and_then(|optional| optional.get(feature))
+            {
+                dependencies.extend(optional);
+            }
+        }
+        dependencies
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BinEntry {
+    pub name: String,                         // Command name in /usr/local/bin
+    pub path: String,                         // The executable, relative to files/
+    pub working_dir: Option<String>,          // Directory to run it in, relative to files/
+    pub env: Option<HashMap<String, String>>, // Environment variables to set
+    pub wrapper: Option<bool>,                // Run it through a generated script
+}
+
+impl BinEntry {
+    // A wrapper script is needed to change directory or set variables, and
+    // is used for those unless `wrapper` says otherwise
+    pub fn uses_wrapper(&self) -> bool {
+        self.wrapper
+            .unwrap_or(self.working_dir.is_some() || self.env.is_some())
+    }
+
+    // Check the entry for problems that would make it unusable
+    pub fn validate(&self) -> Result<(), String> {
+        check_command_name(&self.name)?;
+        check_relative_path(&self.path, "files/")
+            .map_err(|e| format!("path of command '{}' {}", self.name, e))?;
+        if let Some(dir) = &self.working_dir {
+            check_relative_path(dir, "files/")
+                .map_err(|e| format!("working_dir of command '{}' {}", self.name, e))?;
+        }
+        for key in self.env.iter().flatten().map(|(key, _)| key) {
+            check_env_name(key).map_err(|e| format!("command '{}' sets {}", self.name, e))?;
+        }
+        if self.wrapper == Some(false) && (self.working_dir.is_some() || self.env.is_some()) {
+            return Err(format!(
+                "command '{}' sets working_dir or env, which needs wrapper = true",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Desktop integration files, by path relative to files/
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Desktop {
+    pub entries: Option<Vec<String>>,    // .desktop files
+    pub icons: Option<Vec<Icon>>,        // Application icons in any number of sizes
+    pub mime_types: Option<Vec<String>>, // shared-mime-info XML files
+    pub metainfo: Option<Vec<String>>,   // AppStream metainfo files
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Icon {
+    pub path: String,         // The image, relative to files/
+    pub size: String,         // "scalable" or e.g. "48x48"
+    pub name: Option<String>, // Icon name, defaults to the package name
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Unit {
+    pub path: String,          // .service, .socket or .timer file, relative to files/
+    pub enable: Option<bool>,  // Enable the unit, off by default
+    pub start: Option<bool>,   // Start it when it is first installed, off by default
+    pub restart: Option<bool>, // Restart a running service on upgrade, on by default
+}
+
+impl Unit {
+    // The unit name systemd knows it by, e.g. "myd.service"
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+}
+
+// A command name must be a single file name
+pub fn check_command_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(format!("'{}' is not a valid command name", name));
+    }
+    Ok(())
+}
+
+// Variables set by a wrapper script must have names the shell accepts
+pub fn check_env_name(name: &str) -> Result<(), String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!(
+            "'{}' is not a valid environment variable name",
+            name
+        ));
+    }
+    Ok(())
+}
+
+// Paths in meta.toml point into files/ and may not leave it
+pub fn check_relative_path(path: &str, dir: &str) -> Result<(), String> {
+    let path = std::path::Path::new(path);
+    if path.is_absolute() {
+        return Err(format!(
+            "must be relative to {}, found '{}'",
+            dir,
+            path.display()
+        ));
+    }
+    if path
+        .components()
+        .any(|c| matches!(c, std::path::Component::ParentDir))
+    {
+        return Err(format!("may not contain '..', found '{}'", path.display()));
+    }
+    Ok(())
 }
 
 pub fn parse_metadata(data: &str) -> Result<MetaFile> {
@@ -42,3 +203,24 @@ pub fn parse_metadata(data: &str) -> Result<MetaFile> {
     }
     Ok(meta_file)
 }
+
+// Split a `provides` entry into the provided name and version
+pub fn parse_provide(entry: &str) -> (&str, Option<&str>) {
+    match entry.trim().split_once(char::is_whitespace) {
+        Some((name, version)) => (name, Some(version.trim())),
+        None => (entry.trim(), None),
+    }
+}
+
+// Whether a provided version satisfies a dependency constraint. A name
+// provided without a version only satisfies dependencies that accept any
+// version.
+pub fn provide_satisfies(provided_version: Option<&str>, constraint: Option<&str>) -> bool {
+    let Some(req) = constraint.and_then(|c| VersionReq::parse(c).ok()) else {
+        return constraint.is_none();
+    };
+    match provided_version {
+        Some(version) => Version::parse(version).is_ok_and(|version| req.matches(&version)),
+        None => req == VersionReq::STAR,
+    }
+}
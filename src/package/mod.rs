@@ -1,3 +1,6 @@
 // Module for handling .lpkg package format
 pub mod archive;
+pub mod desktop;
+pub mod lint;
 pub mod metadata;
+pub mod systemd;
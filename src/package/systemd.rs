@@ -0,0 +1,194 @@
+use crate::package::desktop::{self, SharedFile};
+use crate::package::metadata::{MetaFile, Unit};
+use crate::utils::root;
+use anyhow::Result;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+// Where systemd looks for the units of locally installed software
+const UNIT_DIR: &str = "/usr/local/lib/systemd/system";
+
+const UNIT_TYPES: &[&str] = &["service", "socket", "timer"];
+
+// The unit files a package extracted to `files_dir` declares with [[unit]]
+pub fn unit_files(meta_file: &MetaFile, files_dir: &Path) -> Result<Vec<SharedFile>> {
+    let mut files: Vec<SharedFile> = Vec::new();
+    for unit in meta_file.unit.iter().flatten() {
+        check_unit(&unit.path).map_err(|e| anyhow::anyhow!("[[unit]] {}", e))?;
+        let dest = Path::new(UNIT_DIR).join(unit.name());
+        if files.iter().any(|file| file.dest == dest) {
+            return Err(anyhow::anyhow!(
+                "Unit '{}' is declared more than once",
+                unit.name()
+            ));
+        }
+        let source = files_dir.join(&unit.path);
+        if !source.is_file() {
+            return Err(anyhow::anyhow!(
+                "[[unit]] declares {}, which is not a file in the package",
+                unit.path
+            ));
+        }
+        files.push(SharedFile { source, dest });
+    }
+    Ok(files)
+}
+
+// A unit must stay inside files/ and be of a type lpkg manages
+pub fn check_unit(path: &str) -> Result<(), String> {
+    desktop::check_file(path, UNIT_TYPES)
+}
+
+// The names of the units among recorded package files
+pub fn installed_units<S: AsRef<str>>(paths: &[S]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| Path::new(path.as_ref()))
+        .filter(|path| path.parent() == Some(Path::new(UNIT_DIR)))
+        .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .collect()
+}
+
+// systemctl only acts on the running system, and only if it was booted with
+// systemd
+fn systemd_running() -> bool {
+    !root::is_alternate() && Path::new("/run/systemd/system").is_dir()
+}
+
+// Stop and disable the units among `paths`, before their files are removed
+pub fn stop_units<S: AsRef<str>>(paths: &[S]) {
+    let units = installed_units(paths);
+    if !units.is_empty() && systemd_running() {
+        systemctl("disable", &["--now"], &units);
+    }
+}
+
+// Have systemd forget the units among `paths`, after their files were removed
+pub fn reload<S: AsRef<str>>(paths: &[S]) {
+    if !installed_units(paths).is_empty() && systemd_running() {
+        systemctl::<&str>("daemon-reload", &[], &[]);
+    }
+}
+
+// Load the units of a package that was just installed or upgraded and apply
+// their enable/start policy. Units in `previous`, those of the replaced
+// version, are not started again but restarted if they are running services,
+// so they pick up the new files.
+pub fn apply(meta_file: &MetaFile, previous: &[String]) {
+    let units: Vec<&Unit> = meta_file.unit.iter().flatten().collect();
+    if units.is_empty() && previous.is_empty() {
+        return;
+    }
+    if !systemd_running() {
+        if units
+            .iter()
+            .any(|unit| unit.enable == Some(true) || unit.start == Some(true))
+        {
+            println!(
+                "Note: {}, units are installed but not enabled or started.",
+                if root::is_alternate() {
+                    "managing another root"
+                } else {
+                    "systemd is not running"
+                }
+            );
+        }
+        return;
+    }
+    systemctl::<&str>("daemon-reload", &[], &[]);
+    let selected = |wanted: &dyn Fn(&Unit) -> bool| -> Vec<&str> {
+        units
+            .iter()
+            .filter(|unit| wanted(unit))
+            .map(|unit| unit.name())
+            .collect()
+    };
+    let is_previous = |unit: &Unit| previous.iter().any(|name| name == unit.name());
+    let enable = selected(&|unit| unit.enable == Some(true));
+    let start = selected(&|unit| unit.start == Some(true) && !is_previous(unit));
+    let restart = selected(&|unit| {
+        unit.name().ends_with(".service") && unit.restart != Some(false) && is_previous(unit)
+    });
+    if !enable.is_empty() {
+        systemctl("enable", &[], &enable);
+    }
+    if !start.is_empty() {
+        systemctl("start", &[], &start);
+    }
+    if !restart.is_empty() {
+        systemctl("try-restart", &[], &restart);
+    }
+}
+
+// Failures are only warned about: the package's files are in place, and the
+// units can be fixed and started by hand
+fn systemctl<S: AsRef<str>>(command: &str, options: &[&str], units: &[S]) {
+    let units: Vec<&str> = units.iter().map(|unit| unit.as_ref()).collect();
+    let output = Command::new("systemctl")
+        .arg(command)
+        .args(options)
+        .args(&units)
+        .output();
+    let description = [&[command], options, &units].concat().join(" ");
+    match output {
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Warning: failed to run systemctl: {}", e),
+        Ok(output) if !output.status.success() => eprintln!(
+            "Warning: systemctl {} failed: {}",
+            description,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Ok(_) => println!("Ran systemctl {}.", description),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::metadata::parse_metadata;
+    use std::fs;
+
+    #[test]
+    fn test_unit_files() {
+        let files = tempfile::tempdir().unwrap();
+        for path in ["lib/systemd/myd.service", "lib/systemd/myd.socket"] {
+            let path = files.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let units = |declared: &str| {
+            let meta = format!(
+                "[package]\nname = \"myd\"\nversion = \"1.0.0\"\n{}",
+                declared
+            );
+            unit_files(&parse_metadata(&meta).unwrap(), files.path())
+        };
+
+        let dests: Vec<String> = units(
+            "[[unit]]\npath = \"lib/systemd/myd.service\"\n[[unit]]\npath = \"lib/systemd/myd.socket\"\nenable = true\n",
+        )
+        .unwrap()
+        .iter()
+        .map(|file| file.dest.to_string_lossy().into_owned())
+        .collect();
+        assert_eq!(
+            dests,
+            [
+                "/usr/local/lib/systemd/system/myd.service",
+                "/usr/local/lib/systemd/system/myd.socket",
+            ]
+        );
+        assert_eq!(
+            installed_units(&[dests[0].as_str(), "/usr/local/bin/myd"]),
+            ["myd.service"]
+        );
+
+        assert!(units("[[unit]]\npath = \"lib/systemd/myd.mount\"\n").is_err());
+        assert!(units("[[unit]]\npath = \"lib/systemd/other.service\"\n").is_err());
+        assert!(
+            units("[[unit]]\npath = \"lib/systemd/myd.service\"\n[[unit]]\npath = \"lib/systemd/myd.service\"\n")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use crate::config;
+use crate::repository::http::HttpClient;
+use crate::repository::index_cache_path;
+use crate::repository::mirrors::{Mirrors, base_url};
+use anyhow::{Context, Result};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        };
+        f.write_str(name)
+    }
+}
+
+// A security advisory for a range of versions of a package
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Advisory {
+    pub id: String,
+    pub affected: String, // Version requirement matching the affected versions, e.g. ">=1.0, <1.4.2"
+    pub severity: Severity,
+    pub fixed_version: Option<String>, // First version with the fix, if there is one
+    pub summary: Option<String>,
+    pub url: Option<String>,
+}
+
+impl Advisory {
+    // Whether the advisory affects a version. A range that does not parse or a
+    // version that is not semantic is an error, so that a malformed advisory
+    // cannot pass a vulnerable package as unaffected.
+    pub fn affects(&self, version: &str) -> Result<bool> {
+        let req = VersionReq::parse(&self.affected).context(format!(
+            "Advisory {} has an invalid affected range '{}'",
+            self.id, self.affected
+        ))?;
+        let version = Version::parse(version)
+            .context(format!("'{}' is not a valid semantic version", version))?;
+        Ok(req.matches(&version))
+    }
+}
+
+// The advisories feed a repository publishes as advisories.json next to its
+// index, keyed by package name
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AdvisoryFeed {
+    pub advisories: HashMap<String, Vec<Advisory>>,
+}
+
+impl AdvisoryFeed {
+    // Advisories of a package that affect the given version
+    pub fn affecting(&self, name: &str, version: &str) -> Result<Vec<&Advisory>> {
+        let mut affecting = Vec::new();
+        for advisory in self.advisories.get(name).into_iter().flatten() {
+            if advisory.affects(version)? {
+                affecting.push(advisory);
+            }
+        }
+        Ok(affecting)
+    }
+}
+
+// Location of the cached advisories of a named repository
+pub fn advisories_cache_path(repo_name: &str) -> PathBuf {
+    index_cache_path(repo_name).with_extension("advisories.json")
+}
+
+// Fetch the advisories feed from the first mirror that answers. Returns None
+// when the repository does not publish one.
+pub fn fetch_advisories(mirrors: &Mirrors, client: &HttpClient) -> Result<Option<AdvisoryFeed>> {
+    let mut last_error = None;
+    for mirror in &mirrors.urls {
+        let url = format!("{}/advisories.json", base_url(mirror));
+        if let Some(path) = url.strip_prefix("file://") {
+            if !Path::new(path).exists() {
+                return Ok(None);
+            }
+            let content = fs::read_to_string(path)
+                .context(format!("Failed to read advisories from {}", path))?;
+            return serde_json::from_str(&content)
+                .map(Some)
+                .context(format!("Failed to parse advisories from {}", url));
+        }
+        match client.get(&url).call() {
+            Ok(response) => {
+                return serde_json::from_reader(response.into_reader())
+                    .map(Some)
+                    .context(format!("Failed to parse advisories from {}", url));
+            }
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => {
+                eprintln!("Warning: mirror {} failed: {}", mirror, e);
+                last_error = Some(anyhow::Error::new(e));
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| anyhow::anyhow!("No mirrors configured"))
+        .context("No mirror could serve the advisories"))
+}
+
+// Cache a repository's advisories, or drop the cached ones when the
+// repository no longer publishes any
+pub fn save_advisories(repo_name: &str, feed: Option<&AdvisoryFeed>) -> Result<()> {
+    let path = advisories_cache_path(repo_name);
+    let Some(feed) = feed else {
+        if path.exists() {
+            fs::remove_file(&path).context(format!("Failed to remove {}", path.display()))?;
+        }
+        return Ok(());
+    };
+    let content = serde_json::to_string_pretty(feed).context("Failed to serialize advisories")?;
+    fs::write(&path, content).context(format!("Failed to write advisories to {}", path.display()))
+}
+
+// The cached advisories of every configured repository, merged into one feed
+pub fn load_advisories() -> Result<AdvisoryFeed> {
+    let mut merged = AdvisoryFeed::default();
+    for repo in config::load_repositories()? {
+        let path = advisories_cache_path(&repo.name);
+        if !path.exists() {
+            continue;
+        }
+        let content = fs::read_to_string(&path).context(format!(
+            "Failed to read cached advisories from {}",
+            path.display()
+        ))?;
+        let feed: AdvisoryFeed = serde_json::from_str(&content).context(format!(
+            "Failed to parse cached advisories from {}",
+            path.display()
+        ))?;
+        for (name, advisories) in feed.advisories {
+            let known = merged.advisories.entry(name).or_default();
+            // Repositories may mirror each other's advisories
+            for advisory in advisories {
+                if !known.iter().any(|a| a.id == advisory.id) {
+                    known.push(advisory);
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_affecting_matches_version_ranges() {
+        let feed: AdvisoryFeed = serde_json::from_str(
+            r#"{"advisories": {"foo": [
+                {"id": "LPKG-1", "affected": "<1.2.0", "severity": "high", "fixed_version": "1.2.0"},
+                {"id": "LPKG-2", "affected": ">=2.0.0, <2.0.3", "severity": "low"}
+            ]}}"#,
+        )
+        .unwrap();
+        let ids = |version| -> Vec<&str> {
+            feed.affecting("foo", version)
+                .unwrap()
+                .iter()
+                .map(|a| a.id.as_str())
+                .collect()
+        };
+        assert_eq!(ids("1.1.9"), ["LPKG-1"]);
+        assert!(ids("1.2.0").is_empty());
+        assert_eq!(ids("2.0.1"), ["LPKG-2"]);
+        assert!(feed.affecting("bar", "1.0.0").unwrap().is_empty());
+        assert!(Severity::Critical > Severity::High);
+    }
+
+    #[test]
+    fn test_unparsable_ranges_and_versions_are_errors() {
+        let feed: AdvisoryFeed = serde_json::from_str(
+            r#"{"advisories": {"foo": [
+                {"id": "LPKG-3", "affected": "before 1.2", "severity": "high"}
+            ], "bar": [
+                {"id": "LPKG-4", "affected": "<1.2.0", "severity": "high"}
+            ]}}"#,
+        )
+        .unwrap();
+        assert!(feed.affecting("foo", "1.0.0").is_err());
+        assert!(feed.affecting("bar", "1.0").is_err());
+        assert_eq!(feed.affecting("bar", "1.0.0").unwrap().len(), 1);
+    }
+}
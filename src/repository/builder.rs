@@ -0,0 +1,311 @@
+use crate::package::archive::read_metadata_file;
+use crate::package::metadata::parse_metadata;
+use crate::repository::{
+    INDEX_FORMAT, PackageEntry, PackageIndex, RepositoryIndex, compare_versions,
+};
+use crate::utils::checksum::calculate_sha256;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use walkdir::WalkDir;
+
+// Inode and change times of the indexed files by URL, see file_stamp(). They
+// describe the build host rather than the packages, so they are kept in
+// STAMPS_FILE next to the index instead of being published in it.
+pub type Stamps = HashMap<String, String>;
+pub const STAMPS_FILE: &str = ".index-stamps.json";
+
+pub fn load_stamps(repo_dir: &Path) -> Stamps {
+    fs::read_to_string(repo_dir.join(STAMPS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+}
+
+// Build an index for every .lpkg file below `repo_dir`. Entries of `previous`
+// whose file still has the same size and stamp are reused instead of reading
+// and hashing the package again, unless they were written in an older format.
+// Returns the stamps of the new index along with it.
+pub fn build_index(
+    repo_dir: &Path,
+    previous: Option<RepositoryIndex>,
+    stamps: &Stamps,
+) -> Result<(RepositoryIndex, Stamps, BuildReport)> {
+    let mut report = BuildReport::default();
+    let (mut known, mirrors) = match previous {
+        Some(index) if index.format == Some(INDEX_FORMAT) => {
+            let known: HashMap<String, PackageIndex> = index
+                .packages
+                .into_values()
+                .flat_map(|entry| entry.versions().to_vec())
+                .map(|package| (package.url.clone(), package))
+                .collect();
+            (known, index.mirrors)
+        }
+        Some(index) => (HashMap::new(), index.mirrors),
+        None => (HashMap::new(), None),
+    };
+
+    let mut by_name: HashMap<String, Vec<PackageIndex>> = HashMap::new();
+    let mut new_stamps = Stamps::new();
+    for entry in WalkDir::new(repo_dir).into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !path.is_file() || path.extension().and_then(|e| e.to_str()) != Some("lpkg") {
+            continue;
+        }
+        // Package URLs are relative to the index so the repository can be
+        // served from any location or mirror
+        let rel_path = path
+            .strip_prefix(repo_dir)
+            .context(format!(
+                "Failed to strip prefix from path {}",
+                path.display()
+            ))?
+            .to_string_lossy()
+            .to_string();
+        let file_meta = fs::metadata(path)?;
+        let stamp = file_stamp(&file_meta);
+
+        let reusable = known.remove(&rel_path).filter(|package| {
+            package.size == Some(file_meta.len())
+                && package.sha256.is_some()
+                && stamps.get(&rel_path) == Some(&stamp)
+        });
+        let package = match reusable {
+            Some(package) => {
+                report.unchanged += 1;
+                package
+            }
+            None => {
+                let package = index_package(path, &rel_path, file_meta.len())?;
+                report
+                    .added
+                    .push(format!("{} {}", package.name, package.version));
+                package
+            }
+        };
+        new_stamps.insert(rel_path, stamp);
+        by_name
+            .entry(package.name.clone())
+            .or_default()
+            .push(package);
+    }
+
+    report.removed = known
+        .into_values()
+        .map(|package| format!("{} {}", package.name, package.version))
+        .collect();
+    report.added.sort();
+    report.removed.sort();
+
+    let packages = by_name
+        .into_iter()
+        .map(|(name, mut versions)| {
+            versions.sort_by(|a, b| compare_versions(&b.version, &a.version));
+            let entry = if versions.len() == 1 {
+                PackageEntry::Single(Box::new(versions.remove(0)))
+            } else {
+                PackageEntry::Multiple(versions)
+            };
+            (name, entry)
+        })
+        .collect();
+
+    let index = RepositoryIndex {
+        format: Some(INDEX_FORMAT),
+        packages,
+        mirrors,
+    };
+    Ok((index, new_stamps, report))
+}
+
+// A replaced file gets a new inode, and rewriting it in place updates its
+// change time, which unlike the modification time cannot be set back.
+fn file_stamp(meta: &Metadata) -> String {
+    format!(
+        "{}:{}.{}:{}.{}",
+        meta.ino(),
+        meta.mtime(),
+        meta.mtime_nsec(),
+        meta.ctime(),
+        meta.ctime_nsec()
+    )
+}
+
+fn index_package(path: &Path, rel_path: &str, size: u64) -> Result<PackageIndex> {
+    let path_str = path.to_str().unwrap_or_default();
+    println!("Indexing {}", path.display());
+
+    let meta_content = read_metadata_file(path_str)?;
+    let meta_file = parse_metadata(&meta_content)
+        .context(format!("Failed to parse metadata of {}", path.display()))?;
+    let sha256 = calculate_sha256(path_str)?;
+
+    let dependencies = meta_file.dependencies.map(|deps| {
+        let mut deps: Vec<String> = deps
+            .into_iter()
+            .map(|(name, constraint)| format!("{} {}", name, constraint))
+            .collect();
+        deps.sort();
+        deps
+    });
+
+    let features = meta_file.optional_dependencies.map(|optional| {
+        optional
+            .into_iter()
+            .map(|(feature, deps)| {
+                let mut deps: Vec<String> = deps
+                    .into_iter()
+                    .map(|(name, constraint)| format!("{} {}", name, constraint))
+                    .collect();
+                deps.sort();
+                (feature, deps)
+            })
+            .collect()
+    });
+
+    let signature_path = format!("{}.sig", path_str);
+    let signature_url = Path::new(&signature_path)
+        .exists()
+        .then(|| format!("{}.sig", rel_path));
+
+    Ok(PackageIndex {
+        name: meta_file.package.name,
+        version: meta_file.package.version,
+        description: meta_file.package.description,
+        url: rel_path.to_string(),
+        signature_url,
+        sha256: Some(sha256),
+        size: Some(size),
+        dependencies,
+        conflicts: None,
+        arch: meta_file.package.arch,
+        libc: meta_file.package.libc,
+        min_kernel: meta_file.package.min_kernel,
+        provides: meta_file.package.provides,
+        replaces: meta_file.package.replaces,
+        features,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::archive::create_archive;
+    use std::fs::File;
+
+    // Pack `name` `version` with an incompressible payload of `size` bytes, so
+    // packages of the same payload size are also of the same archive size
+    fn pack(dest: &Path, name: &str, version: &str, size: usize, seed: u32) {
+        let source = tempfile::tempdir().unwrap();
+        fs::create_dir(source.path().join("files")).unwrap();
+        let mut state = seed;
+        let payload: Vec<u8> = (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        fs::write(source.path().join("files/data"), payload).unwrap();
+        let meta = source.path().join("meta.toml");
+        fs::write(
+            &meta,
+            format!(
+                "[package]\nname = \"{}\"\nversion = \"{}\"\n",
+                name, version
+            ),
+        )
+        .unwrap();
+        create_archive(
+            &source.path().join("files"),
+            None,
+            &meta,
+            dest.to_str().unwrap(),
+        )
+        .unwrap();
+    }
+
+    fn version_of(index: &RepositoryIndex, name: &str) -> String {
+        index.packages[name].versions()[0].version.clone()
+    }
+
+    #[test]
+    fn test_build_index_reuses_only_unchanged_files() {
+        let repo = tempfile::tempdir().unwrap();
+        let foo = repo.path().join("foo.lpkg");
+        pack(&foo, "foo", "1.0.0", 4_000, 1);
+        pack(&repo.path().join("bar.lpkg"), "bar", "1.0.0", 4_000, 2);
+
+        let (index, stamps, report) = build_index(repo.path(), None, &Stamps::new()).unwrap();
+        assert_eq!(report.added, ["bar 1.0.0", "foo 1.0.0"]);
+        assert_eq!(report.unchanged, 0);
+
+        // Nothing changed
+        let (mut index, stamps, report) = build_index(repo.path(), Some(index), &stamps).unwrap();
+        assert!(report.added.is_empty() && report.removed.is_empty());
+        assert_eq!(report.unchanged, 2);
+
+        // Entries written by an older builder may lack fields, so they are
+        // indexed again
+        index.format = None;
+        let (index, stamps, report) = build_index(repo.path(), Some(index), &stamps).unwrap();
+        assert_eq!(report.added, ["bar 1.0.0", "foo 1.0.0"]);
+        assert_eq!(report.unchanged, 0);
+        assert_eq!(index.format, Some(INDEX_FORMAT));
+
+        // A package was added and another one removed
+        pack(&repo.path().join("qux.lpkg"), "qux", "0.1.0", 100, 3);
+        fs::remove_file(repo.path().join("bar.lpkg")).unwrap();
+        let (index, stamps, report) = build_index(repo.path(), Some(index), &stamps).unwrap();
+        assert_eq!(report.added, ["qux 0.1.0"]);
+        assert_eq!(report.removed, ["bar 1.0.0"]);
+        assert_eq!(report.unchanged, 1);
+
+        // Replaced by a file of the same size with the old modification time,
+        // as `cp -p` or rsync would leave it
+        let modified = fs::metadata(&foo).unwrap().modified().unwrap();
+        let replacement = repo.path().join("foo.lpkg.new");
+        pack(&replacement, "foo", "2.0.0", 4_000, 4);
+        assert_eq!(
+            fs::metadata(&replacement).unwrap().len(),
+            fs::metadata(&foo).unwrap().len()
+        );
+        File::options()
+            .write(true)
+            .open(&replacement)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        fs::rename(&replacement, &foo).unwrap();
+        let (index, stamps, report) = build_index(repo.path(), Some(index), &stamps).unwrap();
+        assert_eq!(report.added, ["foo 2.0.0"]);
+        assert_eq!(version_of(&index, "foo"), "2.0.0");
+
+        // Overwritten in place, keeping the inode and the modification time
+        let staged = repo.path().join("staged");
+        pack(&staged, "foo", "3.0.0", 4_000, 5);
+        fs::write(&foo, fs::read(&staged).unwrap()).unwrap();
+        fs::remove_file(&staged).unwrap();
+        File::options()
+            .write(true)
+            .open(&foo)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let (index, _, report) = build_index(repo.path(), Some(index), &stamps).unwrap();
+        assert_eq!(report.added, ["foo 3.0.0"]);
+        assert_eq!(version_of(&index, "foo"), "3.0.0");
+        assert_eq!(report.unchanged, 1);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::repository::http::HttpClient;
+use crate::repository::mirrors::Mirrors;
+use crate::repository::{PackageIndex, compare_versions, download_package};
+use crate::utils::checksum::{calculate_sha256, verify_checksum};
+use crate::utils::root;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Packages are cached as <CACHE_DIR>/<name>/<version>/<sha256>.lpkg. Like
+// the database, the cache belongs to the managed system, see utils::root.
+pub const CACHE_DIR: &str = "/var/lib/lpkg/cache/packages";
+
+#[derive(Debug)]
+pub struct CachedPackage {
+    pub name: String,
+    pub version: String,
+    pub sha256: String,
+    pub size: u64,
+    pub path: PathBuf,
+}
+
+// Path of a cached package on the managed system, as recorded in the database
+pub fn cache_path(name: &str, version: &str, sha256: &str) -> Result<PathBuf> {
+    check_component("name", name)?;
+    check_component("version", version)?;
+    check_component("checksum", sha256)?;
+    Ok(Path::new(CACHE_DIR)
+        .join(name)
+        .join(version)
+        .join(format!("{}.lpkg", sha256)))
+}
+
+// Names and versions of repository packages become directory names in the
+// cache, so each has to be a single path component
+pub fn check_component(kind: &str, value: &str) -> Result<()> {
+    if value.is_empty() || value == "." || value == ".." || value.contains(['/', '\0']) {
+        return Err(anyhow::anyhow!(
+            "Invalid package {} '{}'",
+            kind,
+            value.escape_debug()
+        ));
+    }
+    Ok(())
+}
+
+// Return a verified local copy of a repository package, downloading it into
+// the cache only when no valid cached copy exists. The returned path is on
+// the running system.
+pub fn fetch_package(
+    package: &PackageIndex,
+    mirrors: &Mirrors,
+    client: &HttpClient,
+) -> Result<PathBuf> {
+    if let Some(expected) = &package.sha256 {
+        let cached = root::path(cache_path(&package.name, &package.version, expected)?);
+        if cached.exists() {
+            if verify_checksum(cached.to_str().unwrap_or_default(), expected)? {
+                println!(
+                    "Using cached package {} {} from {}",
+                    package.name,
+                    package.version,
+                    cached.display()
+                );
+                return Ok(cached);
+            }
+            println!(
+                "Cached copy of {} {} is corrupt, downloading it again.",
+                package.name, package.version
+            );
+            fs::remove_file(&cached).context(format!(
+                "Failed to remove corrupt cached package {}",
+                cached.display()
+            ))?;
+        }
+    }
+
+    check_component("name", &package.name)?;
+    check_component("version", &package.version)?;
+    let version_dir = root::path(CACHE_DIR)
+        .join(&package.name)
+        .join(&package.version);
+    fs::create_dir_all(&version_dir).context(format!(
+        "Failed to create cache directory {}",
+        version_dir.display()
+    ))?;
+
+    // A stable name lets an interrupted download resume from its .part file
+    let download_path = version_dir.join("download.lpkg");
+    download_package(
+        package,
+        mirrors,
+        download_path.to_str().unwrap_or_default(),
+        client,
+    )?;
+
+    // download_package() has already checked the hash if the index provides one
+    let actual = calculate_sha256(download_path.to_str().unwrap_or_default())
+        .context("Failed to calculate checksum of downloaded package")?;
+
+    let cached = root::path(cache_path(&package.name, &package.version, &actual)?);
+    fs::rename(&download_path, &cached).context(format!(
+        "Failed to move downloaded package into cache at {}",
+        cached.display()
+    ))?;
+    Ok(cached)
+}
+
+// Keep a copy of an installed package file in the cache, so the version can
+// be restored later without network access. Returns the cached path to
+// record, which is on the managed system.
+pub fn store(file: &str, name: &str, version: &str) -> Result<PathBuf> {
+    let sha256 =
+        calculate_sha256(file).context(format!("Failed to calculate checksum of {}", file))?;
+    let recorded = cache_path(name, version, &sha256)?;
+    let cached = root::path(&recorded);
+    if cached.exists() {
+        return Ok(recorded);
+    }
+    let version_dir = cached.parent().unwrap_or(Path::new(CACHE_DIR));
+    fs::create_dir_all(version_dir).context(format!(
+        "Failed to create cache directory {}",
+        version_dir.display()
+    ))?;
+    let partial = version_dir.join(format!(".{}.lpkg-new", sha256));
+    fs::copy(file, &partial).context(format!(
+        "Failed to copy {} into cache at {}",
+        file,
+        cached.display()
+    ))?;
+    fs::rename(&partial, &cached).context(format!(
+        "Failed to move package into cache at {}",
+        cached.display()
+    ))?;
+    Ok(recorded)
+}
+
+// The cached packages, with their paths on the running system
+pub fn list_cached() -> Result<Vec<CachedPackage>> {
+    list_cached_in(&root::path(CACHE_DIR))
+}
+
+fn list_cached_in(cache_dir: &Path) -> Result<Vec<CachedPackage>> {
+    let mut packages = Vec::new();
+    if !cache_dir.exists() {
+        return Ok(packages);
+    }
+
+    for name_entry in read_dirs(cache_dir)? {
+        for version_entry in read_dirs(&name_entry)? {
+            for entry in fs::read_dir(&version_entry)
+                .context(format!("Failed to read {}", version_entry.display()))?
+            {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("lpkg") {
+                    continue;
+                }
+                packages.push(CachedPackage {
+                    name: file_name(&name_entry),
+                    version: file_name(&version_entry),
+                    sha256: path
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                    size: fs::metadata(&path)?.len(),
+                    path,
+                });
+            }
+        }
+    }
+
+    packages.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then_with(|| compare_versions(&b.version, &a.version))
+    });
+    Ok(packages)
+}
+
+// Remove every cached package except those in `retained` (installed
+// packages and versions kept for rollback, by their paths on the running
+// system). Returns the number of files and bytes freed.
+pub fn clean(retained: &HashSet<PathBuf>) -> Result<(usize, u64)> {
+    let cache_dir = &root::path(CACHE_DIR);
+    let cached = list_cached_in(cache_dir)?;
+    let mut count = 0;
+    let mut freed = 0;
+    for package in cached.iter().filter(|p| !retained.contains(&p.path)) {
+        fs::remove_file(&package.path).context(format!(
+            "Failed to remove cached package {}",
+            package.path.display()
+        ))?;
+        count += 1;
+        freed += package.size;
+    }
+    remove_unused_dirs(cache_dir, retained)?;
+    Ok((count, freed))
+}
+
+// Keep only the newest `keep` versions of each cached package, plus any
+// package in `retained`.
+pub fn prune(keep: usize, retained: &HashSet<PathBuf>) -> Result<(usize, u64)> {
+    prune_in(&root::path(CACHE_DIR), keep, retained)
+}
+
+fn prune_in(cache_dir: &Path, keep: usize, retained: &HashSet<PathBuf>) -> Result<(usize, u64)> {
+    let mut by_name: BTreeMap<String, Vec<CachedPackage>> = BTreeMap::new();
+    for package in list_cached_in(cache_dir)? {
+        by_name
+            .entry(package.name.clone())
+            .or_default()
+            .push(package);
+    }
+
+    let mut count = 0;
+    let mut freed = 0;
+    for packages in by_name.values() {
+        // list_cached() sorts newest first within a package
+        let mut versions: Vec<&str> = packages.iter().map(|p| p.version.as_str()).collect();
+        versions.dedup();
+        for stale in versions.iter().skip(keep) {
+            for package in packages
+                .iter()
+                .filter(|p| p.version == *stale && !retained.contains(&p.path))
+            {
+                fs::remove_file(&package.path).context(format!(
+                    "Failed to remove cached package {}",
+                    package.path.display()
+                ))?;
+                count += 1;
+                freed += package.size;
+            }
+        }
+    }
+    remove_unused_dirs(cache_dir, retained)?;
+    Ok((count, freed))
+}
+
+// Remove version directories (with any partial downloads) that no longer
+// hold a cached package, then empty package directories
+fn remove_unused_dirs(cache_dir: &Path, retained: &HashSet<PathBuf>) -> Result<()> {
+    if !cache_dir.exists() {
+        return Ok(());
+    }
+    let remaining: HashSet<PathBuf> = list_cached_in(cache_dir)?
+        .into_iter()
+        .map(|p| p.path)
+        .chain(retained.iter().cloned())
+        .filter_map(|path| path.parent().map(Path::to_path_buf))
+        .collect();
+    for name_dir in read_dirs(cache_dir)? {
+        for version_dir in read_dirs(&name_dir)? {
+            if !remaining.contains(&version_dir) {
+                fs::remove_dir_all(&version_dir).ok();
+            }
+        }
+        fs::remove_dir(&name_dir).ok();
+    }
+    Ok(())
+}
+
+fn read_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    Ok(dirs)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_path_rejects_names_outside_the_cache() {
+        assert!(cache_path("foo", "1.0.0", "abc").is_ok());
+        for (name, version) in [
+            ("../../etc", "1.0.0"),
+            ("foo", ".."),
+            ("foo", "."),
+            ("", "1.0.0"),
+            ("foo/bar", "1.0.0"),
+            ("foo\0", "1.0.0"),
+        ] {
+            assert!(
+                cache_path(name, version, "abc").is_err(),
+                "{name:?} {version:?}"
+            );
+        }
+        assert!(cache_path("foo", "1.0.0", "../abc").is_err());
+    }
+
+    #[test]
+    fn test_prune_keeps_newest_versions() {
+        let cache = tempfile::tempdir().unwrap();
+        let add = |name: &str, version: &str| {
+            let dir = cache.path().join(name).join(version);
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(format!("{}{}.lpkg", name, version));
+            fs::write(&path, version).unwrap();
+            path
+        };
+        for version in ["1.0.0", "1.10.0", "1.2.0", "0.9.0"] {
+            add("foo", version);
+        }
+        add("bar", "2.0.0");
+        let retained_old = add("bar", "1.0.0");
+        add("bar", "0.5.0");
+        // A partial download in a version directory without a package
+        let partial = cache.path().join("foo/0.1.0");
+        fs::create_dir_all(&partial).unwrap();
+        fs::write(partial.join("download.lpkg.part"), "x").unwrap();
+
+        let retained = HashSet::from([retained_old]);
+        let (count, freed) = prune_in(cache.path(), 2, &retained).unwrap();
+        assert_eq!((count, freed), (3, 15));
+
+        let left: Vec<(String, String)> = list_cached_in(cache.path())
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.name, p.version))
+            .collect();
+        let expected = [
+            ("bar", "2.0.0"),
+            ("bar", "1.0.0"),
+            ("foo", "1.10.0"),
+            ("foo", "1.2.0"),
+        ];
+        assert_eq!(left, expected.map(|(n, v)| (n.to_string(), v.to_string())));
+        assert!(!partial.exists());
+        assert!(!cache.path().join("foo/0.9.0").exists());
+    }
+}
@@ -0,0 +1,238 @@
+use crate::repository::http::HttpClient;
+use crate::utils::format::format_size;
+use anyhow::{Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Download `url` to `destination`, streaming into `<destination>.part` so an
+// interrupted transfer can be resumed with a Range request on the next attempt.
+// `expected_size`, when known, tells whether a left over .part file is complete.
+pub fn download_file(
+    url: &str,
+    destination: &Path,
+    expected_size: Option<u64>,
+    client: &HttpClient,
+) -> Result<()> {
+    if url.starts_with("file://") {
+        let src_path = url.trim_start_matches("file://");
+        fs::copy(src_path, destination).context(format!(
+            "Failed to copy package from {} to {}",
+            src_path,
+            destination.display()
+        ))?;
+        return Ok(());
+    }
+
+    let options = &client.download;
+    let part_path = part_path(destination);
+    let mut attempt = 0;
+    loop {
+        match transfer(url, &part_path, expected_size, client) {
+            Ok(()) => break,
+            Err(e) if attempt < options.retries && is_retryable(&e) => {
+                let delay = options
+                    .retry_backoff_ms
+                    .saturating_mul(1 << attempt.min(16));
+                attempt += 1;
+                eprintln!(
+                    "Warning: download of {} failed ({:#}). Retrying in {} ms (attempt {}/{})",
+                    url, e, delay, attempt, options.retries
+                );
+                thread::sleep(Duration::from_millis(delay));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    fs::rename(&part_path, destination).context(format!(
+        "Failed to move {} to {}",
+        part_path.display(),
+        destination.display()
+    ))?;
+    Ok(())
+}
+
+fn part_path(destination: &Path) -> PathBuf {
+    let mut name = destination.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+fn transfer(
+    url: &str,
+    part_path: &Path,
+    expected_size: Option<u64>,
+    client: &HttpClient,
+) -> Result<()> {
+    let options = &client.download;
+    let resume_from = fs::metadata(part_path).map(|m| m.len()).unwrap_or(0);
+
+    let mut request = client.get(url);
+    if resume_from > 0 {
+        request = request.set("Range", &format!("bytes={}-", resume_from));
+    }
+
+    let response = match request.call() {
+        // The partial file is already complete, or no longer matches the
+        // remote file. The server reports the full size as "bytes */<size>".
+        Err(ureq::Error::Status(416, response)) if resume_from > 0 => {
+            let size = expected_size.or_else(|| {
+                response
+                    .header("Content-Range")?
+                    .strip_prefix("bytes */")?
+                    .parse()
+                    .ok()
+            });
+            if size == Some(resume_from) {
+                return Ok(());
+            }
+            println!("Server rejected resume of {}, restarting", url);
+            fs::remove_file(part_path)
+                .context(format!("Failed to remove {}", part_path.display()))?;
+            return transfer(url, part_path, expected_size, client);
+        }
+        result => result.context(format!("Failed to download {}", url))?,
+    };
+
+    let resumed = response.status() == 206;
+    let offset = if resumed { resume_from } else { 0 };
+    let total = response
+        .header("Content-Length")
+        .and_then(|len| len.parse::<u64>().ok())
+        .map(|len| len + offset);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(part_path)
+        .context(format!("Failed to open {}", part_path.display()))?;
+
+    if resumed {
+        println!("Resuming download of {} at {}", url, format_size(offset));
+    }
+
+    let mut progress = Progress::new(url, offset, total);
+    let mut reader = response.into_reader();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let started = Instant::now();
+    let mut received: u64 = 0;
+    loop {
+        let n = reader
+            .read(&mut buffer)
+            .context(format!("Connection lost while downloading {}", url))?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buffer[..n])
+            .context(format!("Failed to write to {}", part_path.display()))?;
+        received += n as u64;
+        progress.update(offset + received);
+
+        if let Some(max_rate_kib) = options.max_rate_kib.filter(|r| *r > 0) {
+            let expected = Duration::from_secs_f64(received as f64 / (max_rate_kib * 1024) as f64);
+            let elapsed = started.elapsed();
+            if expected > elapsed {
+                thread::sleep(expected - elapsed);
+            }
+        }
+    }
+    progress.finish(offset + received);
+
+    if let Some(total) = total
+        && offset + received < total
+    {
+        return Err(anyhow::anyhow!(
+            "Download of {} ended early ({} of {})",
+            url,
+            format_size(offset + received),
+            format_size(total)
+        ));
+    }
+    Ok(())
+}
+
+// Client errors other than rate limiting will not go away by retrying.
+fn is_retryable(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Status(code, _)) => *code >= 500 || *code == 429,
+        _ => true,
+    }
+}
+
+// Single-line progress bar, only drawn when stderr is a terminal.
+struct Progress {
+    label: String,
+    total: Option<u64>,
+    start_bytes: u64,
+    started: Instant,
+    last_draw: Option<Instant>,
+    enabled: bool,
+}
+
+impl Progress {
+    fn new(url: &str, start_bytes: u64, total: Option<u64>) -> Self {
+        Progress {
+            label: url.rsplit('/').next().unwrap_or(url).to_string(),
+            total,
+            start_bytes,
+            started: Instant::now(),
+            last_draw: None,
+            enabled: std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn update(&mut self, bytes: u64) {
+        if !self.enabled {
+            return;
+        }
+        if let Some(last) = self.last_draw
+            && last.elapsed() < Duration::from_millis(100)
+        {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        self.draw(bytes);
+    }
+
+    fn draw(&self, bytes: u64) {
+        let elapsed = self.started.elapsed().as_secs_f64().max(0.001);
+        let rate = (bytes - self.start_bytes) as f64 / elapsed;
+        let line = match self.total {
+            Some(total) if total > 0 => {
+                let fraction = (bytes as f64 / total as f64).min(1.0);
+                let filled = (fraction * 30.0) as usize;
+                format!(
+                    "{} [{}{}] {:>3}% {}/{} {}/s",
+                    self.label,
+                    "#".repeat(filled),
+                    " ".repeat(30 - filled),
+                    (fraction * 100.0) as u32,
+                    format_size(bytes),
+                    format_size(total),
+                    format_size(rate as u64)
+                )
+            }
+            _ => format!(
+                "{} {} {}/s",
+                self.label,
+                format_size(bytes),
+                format_size(rate as u64)
+            ),
+        };
+        eprint!("\r{}\x1b[K", line);
+    }
+
+    fn finish(&mut self, bytes: u64) {
+        if self.enabled {
+            self.draw(bytes);
+            eprintln!();
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use crate::config::{Config, DownloadConfig, RepositoryAuth, RepositoryConfig};
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rustls_pki_types::CertificateDer;
+use rustls_pki_types::pem::PemObject;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+// HTTP agent carrying the proxy, TLS trust and credentials for one repository,
+// shared by index refreshes and package downloads.
+pub struct HttpClient {
+    agent: ureq::Agent,
+    pub download: DownloadConfig, // Retry and bandwidth settings for downloads
+    authorization: Option<String>,
+    auth_origins: Vec<String>, // Credentials are only sent to these scheme://host:port origins
+}
+
+impl HttpClient {
+    pub fn for_repository(config: &Config, repo: &RepositoryConfig) -> Result<Self> {
+        let authorization = match &repo.auth {
+            Some(auth) => Some(authorization_header(auth, repo).context(format!(
+                "Failed to get credentials for repository {}",
+                repo.name
+            ))?),
+            None => None,
+        };
+        // Mirrors published in the index itself are not trusted with credentials
+        let auth_origins = std::iter::once(&repo.url)
+            .chain(repo.mirrors.iter())
+            .filter_map(|url| origin(url))
+            .map(str::to_string)
+            .collect();
+
+        Ok(HttpClient {
+            agent: build_agent(config, Some(repo))?,
+            download: config.download.clone(),
+            authorization,
+            auth_origins,
+        })
+    }
+
+    pub fn get(&self, url: &str) -> ureq::Request {
+        self.authorize(self.agent.get(url), url)
+    }
+
+    pub fn head(&self, url: &str) -> ureq::Request {
+        self.authorize(self.agent.head(url), url)
+    }
+
+    fn authorize(&self, request: ureq::Request, url: &str) -> ureq::Request {
+        match (&self.authorization, origin(url)) {
+            (Some(header), Some(url_origin))
+                if self.auth_origins.iter().any(|o| o == url_origin) =>
+            {
+                request.set("Authorization", header)
+            }
+            _ => request,
+        }
+    }
+}
+
+fn build_agent(config: &Config, repo: Option<&RepositoryConfig>) -> Result<ureq::Agent> {
+    let timeout = Duration::from_secs(config.download.timeout_secs);
+    let mut builder = ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .user_agent(concat!("lpkg/", env!("CARGO_PKG_VERSION")));
+
+    let proxy = repo
+        .and_then(|r| r.proxy.as_ref())
+        .or(config.network.proxy.as_ref());
+    match proxy {
+        Some(proxy) => {
+            let proxy =
+                ureq::Proxy::new(proxy).context(format!("Invalid proxy setting '{}'", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+        // ALL_PROXY, HTTPS_PROXY or HTTP_PROXY (or their lowercase forms)
+        None => builder = builder.try_proxy_from_env(true),
+    }
+
+    let ca_bundles: Vec<&String> = config
+        .network
+        .ca_bundles
+        .iter()
+        .chain(repo.into_iter().flat_map(|r| r.ca_bundles.iter()))
+        .collect();
+    if !ca_bundles.is_empty() {
+        builder = builder.tls_config(Arc::new(tls_config(&ca_bundles)?));
+    }
+
+    Ok(builder.build())
+}
+
+// TLS configuration trusting the bundled web roots plus the given PEM bundles
+fn tls_config(ca_bundles: &[&String]) -> Result<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    for bundle in ca_bundles {
+        let mut added = 0;
+        for cert in CertificateDer::pem_file_iter(bundle.as_str())
+            .context(format!("Failed to read CA bundle {}", bundle))?
+        {
+            let cert = cert.context(format!("Invalid certificate in CA bundle {}", bundle))?;
+            roots
+                .add(cert)
+                .context(format!("Failed to trust certificate from {}", bundle))?;
+            added += 1;
+        }
+        if added == 0 {
+            return Err(anyhow::anyhow!(
+                "No certificates found in CA bundle {}",
+                bundle
+            ));
+        }
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("Failed to configure TLS")?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(tls)
+}
+
+fn authorization_header(auth: &RepositoryAuth, repo: &RepositoryConfig) -> Result<String> {
+    match auth {
+        RepositoryAuth::Basic { username, password } => Ok(basic(username, password)),
+        RepositoryAuth::Bearer { token } => Ok(format!("Bearer {}", token)),
+        RepositoryAuth::Helper { command } => {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("LPKG_REPO_NAME", &repo.name)
+                .env("LPKG_REPO_URL", &repo.url)
+                .output()
+                .context(format!("Failed to run credential helper '{}'", command))?;
+            if !output.status.success() {
+                return Err(anyhow::anyhow!(
+                    "Credential helper failed with status: {}. Stderr: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+            }
+            parse_helper_output(&String::from_utf8_lossy(&output.stdout))
+        }
+    }
+}
+
+// Credential helpers print `key=value` lines: either `token=...`, or
+// `username=...` and `password=...`.
+fn parse_helper_output(output: &str) -> Result<String> {
+    let value = |key: &str| {
+        output.lines().find_map(|line| {
+            line.split_once('=')
+                .filter(|(k, _)| k.trim() == key)
+                .map(|(_, v)| v.trim().to_string())
+        })
+    };
+    if let Some(token) = value("token") {
+        return Ok(format!("Bearer {}", token));
+    }
+    match (value("username"), value("password")) {
+        (Some(username), Some(password)) => Ok(basic(&username, &password)),
+        _ => Err(anyhow::anyhow!(
+            "Credential helper printed neither a token nor a username and password"
+        )),
+    }
+}
+
+fn basic(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        BASE64.encode(format!("{}:{}", username, password))
+    )
+}
+
+// The scheme://host[:port] part of a URL
+fn origin(url: &str) -> Option<&str> {
+    let scheme_end = url.find("://")? + 3;
+    let end = url[scheme_end..]
+        .find(['/', '?', '#'])
+        .map(|pos| scheme_end + pos)
+        .unwrap_or(url.len());
+    Some(&url[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin() {
+        assert_eq!(
+            origin("https://repo.example.org:8443/lpkg/index.json"),
+            Some("https://repo.example.org:8443")
+        );
+        assert_eq!(
+            origin("https://repo.example.org"),
+            Some("https://repo.example.org")
+        );
+        assert_eq!(origin("index.json"), None);
+    }
+
+    #[test]
+    fn test_parse_helper_output() {
+        assert_eq!(
+            parse_helper_output("username=alice\npassword=secret\n").unwrap(),
+            "Basic YWxpY2U6c2VjcmV0"
+        );
+        assert_eq!(parse_helper_output("token=abc\n").unwrap(), "Bearer abc");
+        assert!(parse_helper_output("nothing useful").is_err());
+    }
+}
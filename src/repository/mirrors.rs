@@ -0,0 +1,136 @@
+use crate::config::{MirrorSelection, RepositoryConfig};
+use crate::repository::RepositoryIndex;
+use crate::repository::http::HttpClient;
+use std::time::{Duration, Instant};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The index URLs a repository can be served from, in the order to try them.
+// Package URLs in an index are written against the primary URL and are
+// rewritten onto whichever mirror is being used.
+#[derive(Debug, Clone)]
+pub struct Mirrors {
+    pub primary: String,
+    pub urls: Vec<String>,
+}
+
+impl Mirrors {
+    // Combine the configured mirrors with any mirrors published in the
+    // repository's last cached index.
+    pub fn for_repository(
+        repo: &RepositoryConfig,
+        cached: Option<&RepositoryIndex>,
+        client: &HttpClient,
+    ) -> Self {
+        let mut urls = vec![repo.url.clone()];
+        let published = cached.and_then(|index| index.mirrors.as_ref());
+        for url in repo.mirrors.iter().chain(published.into_iter().flatten()) {
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+
+        if repo.mirror_selection == MirrorSelection::Fastest && urls.len() > 1 {
+            urls = order_by_latency(urls, client);
+        }
+
+        Mirrors {
+            primary: repo.url.clone(),
+            urls,
+        }
+    }
+
+    // Resolve a package URL from the index against the given mirror.
+    pub fn package_url(&self, mirror: &str, package_url: &str) -> String {
+        if !package_url.contains("://") {
+            return format!(
+                "{}/{}",
+                base_url(mirror),
+                package_url.trim_start_matches('/')
+            );
+        }
+        let primary_base = base_url(&self.primary);
+        match package_url.strip_prefix(primary_base) {
+            Some(rest) if rest.starts_with('/') => format!("{}{}", base_url(mirror), rest),
+            _ => package_url.to_string(),
+        }
+    }
+}
+
+// Directory part of an index URL, without a trailing slash.
+pub fn base_url(index_url: &str) -> &str {
+    match index_url.rfind('/') {
+        Some(pos) if !index_url[..pos].ends_with('/') => &index_url[..pos],
+        _ => index_url.trim_end_matches('/'),
+    }
+}
+
+fn order_by_latency(urls: Vec<String>, client: &HttpClient) -> Vec<String> {
+    let mut timed: Vec<(Option<Duration>, String)> = urls
+        .into_iter()
+        .map(|url| {
+            if url.starts_with("file://") {
+                return (Some(Duration::ZERO), url);
+            }
+            let started = Instant::now();
+            let latency = client
+                .head(&url)
+                .timeout(PROBE_TIMEOUT)
+                .call()
+                .ok()
+                .map(|_| started.elapsed());
+            (latency, url)
+        })
+        .collect();
+    // Unreachable mirrors sort last but are still tried
+    timed.sort_by_key(|(latency, _)| latency.unwrap_or(Duration::MAX));
+    timed.into_iter().map(|(_, url)| url).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirrors() -> Mirrors {
+        Mirrors {
+            primary: "https://repo.example.org/lpkg/index.json".to_string(),
+            urls: vec![
+                "https://repo.example.org/lpkg/index.json".to_string(),
+                "https://mirror.example.net/index.json".to_string(),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_relative_package_url_uses_mirror_base() {
+        assert_eq!(
+            mirrors().package_url(
+                "https://mirror.example.net/index.json",
+                "pkgs/foo-1.0.0.lpkg"
+            ),
+            "https://mirror.example.net/pkgs/foo-1.0.0.lpkg"
+        );
+    }
+
+    #[test]
+    fn test_absolute_package_url_on_primary_is_rewritten() {
+        assert_eq!(
+            mirrors().package_url(
+                "https://mirror.example.net/index.json",
+                "https://repo.example.org/lpkg/foo-1.0.0.lpkg"
+            ),
+            "https://mirror.example.net/foo-1.0.0.lpkg"
+        );
+    }
+
+    #[test]
+    fn test_external_package_url_is_kept() {
+        assert_eq!(
+            mirrors().package_url(
+                "https://mirror.example.net/index.json",
+                "https://cdn.example.com/foo-1.0.0.lpkg"
+            ),
+            "https://cdn.example.com/foo-1.0.0.lpkg"
+        );
+    }
+}
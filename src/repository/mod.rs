@@ -1,51 +1,277 @@
+pub mod advisories;
+pub mod builder;
+pub mod cache;
+pub mod download;
+pub mod http;
+pub mod mirrors;
+pub mod resolve;
+
+use crate::utils::checksum::verify_checksum;
+use crate::utils::platform;
 use anyhow::{Context, Result};
+use http::HttpClient;
+use mirrors::Mirrors;
+use semver::Version;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::Read;
-use std::path::Path;
-use ureq;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
 
 // Define the structure for a package index entry in the repository
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackageIndex {
     pub name: String,
     pub version: String,
     pub description: Option<String>,
     pub url: String,                   // URL to download the .lpkg file
     pub signature_url: Option<String>, // URL to download the signature file if available
+    pub sha256: Option<String>,        // SHA256 of the .lpkg file, used to verify and key the cache
+    pub size: Option<u64>,             // Size of the .lpkg file in bytes
     pub dependencies: Option<Vec<String>>,
     pub conflicts: Option<Vec<String>>,
+    pub arch: Option<String>, // Platform requirements copied from the package metadata
+    pub libc: Option<String>,
+    pub min_kernel: Option<String>,
+    pub provides: Option<Vec<String>>, // Virtual packages provided, as "name" or "name version"
+    pub replaces: Option<Vec<String>>, // Packages this one takes over from
+    pub features: Option<BTreeMap<String, Vec<String>>>, // Optional dependencies by feature
+}
+
+impl PackageIndex {
+    // Whether this build can be installed on the running system
+    pub fn is_supported(&self) -> bool {
+        platform::is_supported(
+            self.arch.as_deref(),
+            self.libc.as_deref(),
+            self.min_kernel.as_deref(),
+        )
+    }
 }
 
+// A package may be published in several versions. A single version can still
+// be written as a plain object, as older indexes do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PackageEntry {
+    Single(Box<PackageIndex>),
+    Multiple(Vec<PackageIndex>),
+}
+
+impl PackageEntry {
+    pub fn versions(&self) -> &[PackageIndex] {
+        match self {
+            PackageEntry::Single(package) => std::slice::from_ref(package.as_ref()),
+            PackageEntry::Multiple(packages) => packages,
+        }
+    }
+}
+
+// Version of the index layout written by `repo build-index`. Bump it whenever
+// entries gain fields read from the package, so older entries are rebuilt.
+pub const INDEX_FORMAT: u32 = 1;
+
 // Define the structure for the repository index file
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RepositoryIndex {
-    pub packages: HashMap<String, PackageIndex>,
+    pub format: Option<u32>, // INDEX_FORMAT of the writer, missing in older indexes
+    pub packages: HashMap<String, PackageEntry>,
+    pub mirrors: Option<Vec<String>>, // Index URLs of mirrors published by the repository
 }
 
-// Function to fetch and parse a remote repository index
-pub fn fetch_index(url: &str) -> Result<RepositoryIndex> {
-    println!("Fetching repository index from {}", url);
-    if url.starts_with("file://") {
-        let path = url.trim_start_matches("file://");
-        let content = fs::read_to_string(path)
-            .context(format!("Failed to read local repository index from {}", path))?;
-        let index: RepositoryIndex = serde_json::from_str(&content)
-            .context("Failed to parse local repository index JSON")?;
-        Ok(index)
+impl RepositoryIndex {
+    // Entries come from the repository and are used in cache paths, see
+    // cache::check_component
+    pub fn check(&self) -> Result<()> {
+        for (name, entry) in &self.packages {
+            cache::check_component("name", name)?;
+            for package in entry.versions() {
+                cache::check_component("name", &package.name)?;
+                cache::check_component("version", &package.version)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Location of the cached index for a named repository
+pub fn index_cache_path(repo_name: &str) -> PathBuf {
+    let cache_dir = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
+    cache_dir.join(format!("lpkg_repo_{}.json", repo_name))
+}
+
+// Cache validators remembered from the last successful index fetch, so an
+// unchanged index is not downloaded again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IndexValidators {
+    pub url: String, // The exact URL (including compression suffix) that served the index
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+pub enum IndexFetch {
+    Modified(RepositoryIndex, IndexValidators),
+    NotModified,
+}
+
+// Location of the validators stored next to a cached index
+pub fn validators_path(repo_name: &str) -> PathBuf {
+    index_cache_path(repo_name).with_extension("meta.json")
+}
+
+pub fn load_validators(repo_name: &str) -> Option<IndexValidators> {
+    let content = fs::read_to_string(validators_path(repo_name)).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+pub fn save_validators(repo_name: &str, validators: &IndexValidators) -> Result<()> {
+    let path = validators_path(repo_name);
+    let content =
+        serde_json::to_string_pretty(validators).context("Failed to serialize index validators")?;
+    fs::write(&path, content).context(format!(
+        "Failed to write index validators to {}",
+        path.display()
+    ))
+}
+
+// Function to fetch a repository index, trying each mirror in turn.
+// Returns the result together with the mirror that served it.
+pub fn fetch_index(
+    mirrors: &Mirrors,
+    previous: Option<&IndexValidators>,
+    client: &HttpClient,
+) -> Result<(IndexFetch, String)> {
+    let mut last_error = None;
+    for mirror in &mirrors.urls {
+        match fetch_index_from(mirror, previous, client) {
+            Ok(fetch) => return Ok((fetch, mirror.clone())),
+            Err(e) => {
+                eprintln!("Warning: mirror {} failed: {:#}", mirror, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| anyhow::anyhow!("No mirrors configured"))
+        .context("No mirror could serve the repository index"))
+}
+
+// Compressed variants of an index URL to try before the plain JSON file
+fn index_variants(url: &str) -> Vec<String> {
+    if url.ends_with(".json") {
+        vec![
+            format!("{}.zst", url),
+            format!("{}.gz", url),
+            url.to_string(),
+        ]
+    } else {
+        vec![url.to_string()]
+    }
+}
+
+fn parse_index<R: Read>(reader: R, url: &str) -> Result<RepositoryIndex> {
+    let reader: Box<dyn Read> = if url.ends_with(".zst") {
+        Box::new(zstd::stream::read::Decoder::new(reader).context("Failed to create zstd decoder")?)
+    } else if url.ends_with(".gz") {
+        Box::new(flate2::read::GzDecoder::new(reader).context("Failed to read gzip header")?)
     } else {
-        let agent = ureq::agent();
-        let response = agent.get(url).call().context("Failed to fetch repository index")?;
-        let index: RepositoryIndex = response.into_json().context("Failed to parse repository index JSON")?;
-        Ok(index)
+        Box::new(reader)
+    };
+    let index: RepositoryIndex = serde_json::from_reader(BufReader::new(reader)).context(
+        format!("Failed to parse repository index JSON from {}", url),
+    )?;
+    index
+        .check()
+        .context(format!("Invalid repository index {}", url))?;
+    Ok(index)
+}
+
+// Function to fetch and parse a single repository index URL, preferring
+// compressed variants and sending conditional request headers when possible
+fn fetch_index_from(
+    url: &str,
+    previous: Option<&IndexValidators>,
+    client: &HttpClient,
+) -> Result<IndexFetch> {
+    let mut variants = index_variants(url);
+    // Try the variant that worked last time first
+    if let Some(previous) = previous
+        && let Some(pos) = variants.iter().position(|v| *v == previous.url)
+    {
+        let known = variants.remove(pos);
+        variants.insert(0, known);
+    }
+
+    if url.starts_with("file://") {
+        for variant in &variants {
+            let path = variant.trim_start_matches("file://");
+            if Path::new(path).exists() {
+                println!("Fetching repository index from {}", variant);
+                let file = fs::File::open(path).context(format!(
+                    "Failed to read local repository index from {}",
+                    path
+                ))?;
+                let index = parse_index(file, variant)?;
+                let validators = IndexValidators {
+                    url: variant.clone(),
+                    ..Default::default()
+                };
+                return Ok(IndexFetch::Modified(index, validators));
+            }
+        }
+        return Err(anyhow::anyhow!("Local repository index {} not found", url));
     }
+
+    let mut last_error = None;
+    for variant in &variants {
+        println!("Fetching repository index from {}", variant);
+        let mut request = client.get(variant);
+        if let Some(previous) = previous.filter(|p| p.url == *variant) {
+            if let Some(etag) = &previous.etag {
+                request = request.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &previous.last_modified {
+                request = request.set("If-Modified-Since", last_modified);
+            }
+        }
+
+        let response = match request.call() {
+            Ok(response) => response,
+            // The repository does not publish this variant, try the next one.
+            // Static hosts answer 403 rather than 404 for missing files.
+            Err(ureq::Error::Status(400..=499, _)) if variant != url => continue,
+            Err(e) => {
+                last_error =
+                    Some(anyhow::Error::new(e).context("Failed to fetch repository index"));
+                break;
+            }
+        };
+
+        if response.status() == 304 {
+            return Ok(IndexFetch::NotModified);
+        }
+
+        let validators = IndexValidators {
+            url: variant.clone(),
+            etag: response.header("ETag").map(str::to_string),
+            last_modified: response.header("Last-Modified").map(str::to_string),
+        };
+        let index = parse_index(response.into_reader(), variant)?;
+        return Ok(IndexFetch::Modified(index, validators));
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Repository index {} not found", url)))
 }
 
 // Function to save a repository index locally for caching purposes
 pub fn save_index(index: &RepositoryIndex, cache_path: &str) -> Result<()> {
     let index_json = serde_json::to_string_pretty(index)
         .context("Failed to serialize repository index to JSON")?;
+    if let Some(parent) = Path::new(cache_path).parent() {
+        fs::create_dir_all(parent).context(format!(
+            "Failed to create index cache directory {}",
+            parent.display()
+        ))?;
+    }
     fs::write(cache_path, index_json).context(format!(
         "Failed to write repository index to {}",
         cache_path
@@ -65,38 +291,175 @@ pub fn load_cached_index(cache_path: &str) -> Result<Option<RepositoryIndex>> {
         .context(format!("Failed to read cached index from {}", cache_path))?;
     let index: RepositoryIndex = serde_json::from_str(&index_json)
         .context("Failed to deserialize cached repository index")?;
+    index
+        .check()
+        .context(format!("Invalid cached index {}", cache_path))?;
     Ok(Some(index))
 }
 
-// Function to search for a package in the repository index
+// Function to search for the newest version of a package in the repository index
 pub fn search_package<'a>(
     index: &'a RepositoryIndex,
     package_name: &str,
 ) -> Option<&'a PackageIndex> {
-    index.packages.get(package_name)
+    package_versions(index, package_name)
+        .into_iter()
+        .max_by(|a, b| compare_versions(&a.version, &b.version))
+}
+
+// All versions of a package published in the repository index that can be
+// installed on this system. Builds for other platforms are left out.
+pub fn package_versions<'a>(
+    index: &'a RepositoryIndex,
+    package_name: &str,
+) -> Vec<&'a PackageIndex> {
+    package_builds(index, package_name)
+        .iter()
+        .filter(|package| package.is_supported())
+        .collect()
 }
 
-// Function to download a package from the repository
-pub fn download_package(package: &PackageIndex, destination: &str) -> Result<()> {
-    println!(
-        "Downloading package {} from {} to {}",
-        package.name, package.url, destination
-    );
+// Every build of a package in the repository index, for any platform
+pub fn package_builds<'a>(index: &'a RepositoryIndex, package_name: &str) -> &'a [PackageIndex] {
+    index
+        .packages
+        .get(package_name)
+        .map(|entry| entry.versions())
+        .unwrap_or_default()
+}
 
-    if package.url.starts_with("file://") {
-        let src_path = package.url.trim_start_matches("file://");
-        fs::copy(src_path, destination).context(format!("Failed to copy package from {} to {}", src_path, destination))?;
-    } else {
-        let agent = ureq::agent();
-        let response = agent.get(&package.url).call().context("Failed to download package")?;
-        let mut bytes = Vec::new();
-        response.into_reader().read_to_end(&mut bytes)?;
-        fs::write(destination, &bytes).context(format!("Failed to write package to {}", destination))?;
+// Order versions by semver, falling back to string order for non-semver versions
+pub fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    match (Version::parse(a), Version::parse(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+// Function to download a package from the repository, moving on to the next
+// mirror on connection errors or a checksum mismatch. Returns the URL used.
+pub fn download_package(
+    package: &PackageIndex,
+    mirrors: &Mirrors,
+    destination: &str,
+    client: &HttpClient,
+) -> Result<String> {
+    let mut urls: Vec<String> = Vec::new();
+    for mirror in &mirrors.urls {
+        let url = mirrors.package_url(mirror, &package.url);
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
     }
 
-    println!("Package {} downloaded to {}", package.name, destination);
+    let mut last_error = None;
+    for url in urls {
+        println!(
+            "Downloading package {} from {} to {}",
+            package.name, url, destination
+        );
+        let result = download::download_file(&url, Path::new(destination), package.size, client)
+            .and_then(|_| verify_download(package, destination));
+        match result {
+            Ok(()) => {
+                println!("Package {} downloaded from {}", package.name, url);
+                return Ok(url);
+            }
+            Err(e) => {
+                eprintln!("Warning: download from {} failed: {:#}", url, e);
+                fs::remove_file(destination).ok();
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| anyhow::anyhow!("No mirrors configured"))
+        .context(format!("Failed to download package {}", package.name)))
+}
+
+fn verify_download(package: &PackageIndex, destination: &str) -> Result<()> {
+    if let Some(expected) = &package.sha256
+        && !verify_checksum(destination, expected)?
+    {
+        return Err(anyhow::anyhow!(
+            "Checksum mismatch for {} {}",
+            package.name,
+            package.version
+        ));
+    }
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    const INDEX_JSON: &str =
+        r#"{"packages": {}, "mirrors": ["https://mirror.example.net/index.json"]}"#;
+
+    #[test]
+    fn test_index_variants_prefer_compressed() {
+        assert_eq!(
+            index_variants("https://repo.example.org/index.json"),
+            vec![
+                "https://repo.example.org/index.json.zst",
+                "https://repo.example.org/index.json.gz",
+                "https://repo.example.org/index.json",
+            ]
+        );
+    }
 
+    #[test]
+    fn test_parse_zstd_index() {
+        let compressed = zstd::stream::encode_all(INDEX_JSON.as_bytes(), 3).unwrap();
+        let index = parse_index(&compressed[..], "index.json.zst").unwrap();
+        assert_eq!(index.mirrors.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_plain_index() {
+        let index = parse_index(INDEX_JSON.as_bytes(), "index.json").unwrap();
+        assert!(index.packages.is_empty());
+    }
+
+    #[test]
+    fn test_fetch_index_skips_variants_refused_by_the_server() {
+        use std::io::{BufRead, Write};
+        use std::net::TcpListener;
+
+        // Like a static bucket, answer 403 for the missing compressed files
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/index.json", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                std::io::BufReader::new(&stream)
+                    .read_line(&mut request)
+                    .unwrap();
+                let response = if request.starts_with("GET /index.json ") {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        INDEX_JSON.len(),
+                        INDEX_JSON
+                    )
+                } else {
+                    "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string()
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let repo: crate::config::RepositoryConfig =
+            toml::from_str(&format!("name = \"test\"\nurl = \"{}\"", url)).unwrap();
+        let client = HttpClient::for_repository(&crate::config::Config::default(), &repo).unwrap();
+        let IndexFetch::Modified(index, validators) =
+            fetch_index_from(&url, None, &client).unwrap()
+        else {
+            panic!("expected an index");
+        };
+        assert_eq!(validators.url, url);
+        assert_eq!(index.mirrors.unwrap().len(), 1);
+    }
+}
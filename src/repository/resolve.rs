@@ -0,0 +1,512 @@
+use crate::config::{self, RepositoryConfig};
+use crate::package::metadata::{parse_provide, provide_satisfies};
+use crate::repository::{
+    PackageIndex, RepositoryIndex, compare_versions, index_cache_path, load_cached_index,
+    package_builds, package_versions,
+};
+use crate::utils::platform;
+use anyhow::{Context, Result};
+use semver::{Version, VersionReq};
+use std::collections::HashMap;
+
+// A configured repository together with its cached index
+pub struct Source {
+    pub repo: RepositoryConfig,
+    pub index: RepositoryIndex,
+}
+
+// A package chosen by the resolver and the source it comes from
+pub struct Resolved<'a> {
+    pub source: &'a Source,
+    pub package: &'a PackageIndex,
+}
+
+// A version range a package is held or pinned to, and why
+pub struct Pin {
+    pub req: VersionReq,
+    pub reason: String,
+}
+
+// Active holds and pins by package name
+pub type Pins = HashMap<String, Pin>;
+
+// Optional features selected by package name
+pub type Features = HashMap<String, Vec<String>>;
+
+// Load the cached index of every configured repository, in priority order.
+// Repositories that were never refreshed are skipped with a warning.
+pub fn load_sources() -> Result<Vec<Source>> {
+    let mut sources = Vec::new();
+    for repo in config::load_repositories()? {
+        let cache_path = index_cache_path(&repo.name);
+        match load_cached_index(cache_path.to_str().unwrap_or_default())? {
+            Some(index) => sources.push(Source { repo, index }),
+            None => eprintln!(
+                "Warning: repository '{}' has no cached index. Run 'lpkg repo refresh' first.",
+                repo.name
+            ),
+        }
+    }
+    Ok(sources)
+}
+
+// Split an index dependency of the form "name constraint" into its parts.
+// A bare name accepts any version.
+pub fn parse_dependency(dependency: &str) -> (&str, Option<&str>) {
+    match dependency.trim().split_once(char::is_whitespace) {
+        Some((name, constraint)) if !constraint.trim().is_empty() => {
+            (name, Some(constraint.trim()))
+        }
+        Some((name, _)) => (name, None),
+        None => (dependency.trim(), None),
+    }
+}
+
+fn matches(package: &PackageIndex, constraint: Option<&VersionReq>) -> bool {
+    match constraint {
+        None => true,
+        Some(req) => Version::parse(&package.version)
+            .map(|version| req.matches(&version))
+            .unwrap_or(false),
+    }
+}
+
+// Newest version of a package satisfying the constraint and any pin on it,
+// taken from the first repository (by priority) that has one.
+pub fn find_candidate<'a>(
+    sources: &'a [Source],
+    name: &str,
+    constraint: Option<&VersionReq>,
+    pins: &Pins,
+) -> Option<Resolved<'a>> {
+    let pin = pins.get(name).map(|pin| &pin.req);
+    sources.iter().find_map(|source| {
+        package_versions(&source.index, name)
+            .into_iter()
+            .filter(|package| matches(package, constraint) && matches(package, pin))
+            .max_by(|a, b| compare_versions(&a.version, &b.version))
+            .map(|package| Resolved { source, package })
+    })
+}
+
+// Every version of a package allowed by any pin on it, newest first within
+// each repository and the repositories in priority order
+pub fn find_candidates<'a>(sources: &'a [Source], name: &str, pins: &Pins) -> Vec<Resolved<'a>> {
+    let pin = pins.get(name).map(|pin| &pin.req);
+    sources
+        .iter()
+        .flat_map(|source| {
+            let mut packages: Vec<&PackageIndex> = package_versions(&source.index, name)
+                .into_iter()
+                .filter(|package| matches(package, pin))
+                .collect();
+            packages.sort_by(|a, b| compare_versions(&b.version, &a.version));
+            packages
+                .into_iter()
+                .map(move |package| Resolved { source, package })
+        })
+        .collect()
+}
+
+// Whether a package satisfies a dependency on `name`: as that package, or by
+// providing or replacing it
+fn provides(package: &PackageIndex, name: &str, constraint: Option<&str>) -> bool {
+    if package.name == name {
+        let req = constraint.and_then(|c| VersionReq::parse(c).ok());
+        return constraint.is_none() || req.is_some_and(|req| matches(package, Some(&req)));
+    }
+    package
+        .replaces
+        .iter()
+        .flatten()
+        .any(|replaced| replaced == name)
+        || package.provides.iter().flatten().any(|entry| {
+            let (provided, version) = parse_provide(entry);
+            provided == name && provide_satisfies(version, constraint)
+        })
+}
+
+// A package providing or replacing `name`, for dependencies on a virtual or
+// renamed package. Providers are tried in name order, and the newest version
+// allowed by the provider's pin is taken, from the first repository that has
+// one.
+pub fn find_provider<'a>(
+    sources: &'a [Source],
+    name: &str,
+    constraint: Option<&str>,
+    pins: &Pins,
+) -> Option<Resolved<'a>> {
+    sources.iter().find_map(|source| {
+        let mut names: Vec<&String> = source.index.packages.keys().collect();
+        names.sort();
+        names.into_iter().find_map(|provider| {
+            let pin = pins.get(provider).map(|pin| &pin.req);
+            package_versions(&source.index, provider)
+                .into_iter()
+                .filter(|package| provides(package, name, constraint) && matches(package, pin))
+                .max_by(|a, b| compare_versions(&a.version, &b.version))
+                .map(|package| Resolved { source, package })
+        })
+    })
+}
+
+// Resolve the requested packages and their dependencies. Requests use the
+// index dependency format, so "foo" or "foo =1.2.0". Packages for which
+// `satisfied(name, constraint)` returns true are left out, and versions
+// outside a package's pin are never picked. The result is in install order:
+// every package comes after its dependencies. The dependencies of the
+// `features` selected for a package are resolved along with its own.
+pub fn resolve<'a>(
+    sources: &'a [Source],
+    requested: &[String],
+    pins: &Pins,
+    features: &Features,
+    satisfied: &dyn Fn(&str, Option<&str>) -> Result<bool>,
+) -> Result<Vec<Resolved<'a>>> {
+    let mut resolver = Resolver {
+        sources,
+        pins,
+        features,
+        satisfied,
+        selected: HashMap::new(),
+        visiting: Vec::new(),
+        order: Vec::new(),
+    };
+    for request in requested {
+        let (name, constraint) = parse_dependency(request);
+        resolver.visit(name, constraint, None)?;
+    }
+    Ok(resolver.order)
+}
+
+struct Resolver<'a, 'f> {
+    sources: &'a [Source],
+    pins: &'f Pins,
+    features: &'f Features,
+    satisfied: &'f dyn Fn(&str, Option<&str>) -> Result<bool>,
+    selected: HashMap<String, &'a PackageIndex>,
+    visiting: Vec<String>,
+    order: Vec<Resolved<'a>>,
+}
+
+impl<'a> Resolver<'a, '_> {
+    fn visit(
+        &mut self,
+        name: &str,
+        constraint: Option<&str>,
+        required_by: Option<&str>,
+    ) -> Result<()> {
+        let req = constraint
+            .map(|c| {
+                VersionReq::parse(c)
+                    .context(format!("Invalid version constraint '{}' for {}", c, name))
+            })
+            .transpose()?;
+
+        // A package already picked (or being picked) must also satisfy this constraint
+        if let Some(package) = self.selected.get(name) {
+            if !provides(package, name, constraint) {
+                return Err(anyhow::anyhow!(
+                    "Conflicting requirements for {}: {} {} was selected, but {} requires {}",
+                    name,
+                    name,
+                    package.version,
+                    required_by.unwrap_or("the request"),
+                    constraint.unwrap_or_default()
+                ));
+            }
+            return Ok(());
+        }
+        if (self.satisfied)(name, constraint)?
+            || self
+                .selected
+                .values()
+                .any(|package| provides(package, name, constraint))
+        {
+            return Ok(());
+        }
+
+        let candidate = find_candidate(self.sources, name, req.as_ref(), self.pins)
+            .or_else(|| find_provider(self.sources, name, constraint, self.pins));
+        let Some(candidate) = candidate else {
+            let required = match required_by {
+                Some(parent) => format!(
+                    "{} {} (required by {})",
+                    name,
+                    constraint.unwrap_or("*"),
+                    parent
+                ),
+                None => format!("{} {}", name, constraint.unwrap_or("*")),
+            };
+            if let Some(pin) = self.pins.get(name)
+                && find_candidate(self.sources, name, req.as_ref(), &Pins::new()).is_some()
+            {
+                return Err(anyhow::anyhow!(
+                    "Cannot install {}: {} is {}",
+                    required,
+                    name,
+                    pin.reason
+                ));
+            }
+            if self
+                .sources
+                .iter()
+                .any(|source| !package_builds(&source.index, name).is_empty())
+            {
+                return Err(anyhow::anyhow!(
+                    "Cannot install {}: no build is available for this system ({})",
+                    required,
+                    platform::host()
+                ));
+            }
+            return Err(match required_by {
+                Some(_) => anyhow::anyhow!("No repository provides {}", required),
+                None => anyhow::anyhow!("Package '{}' not found in any repository", name),
+            });
+        };
+
+        let package = candidate.package;
+        self.selected.insert(name.to_string(), package);
+        self.selected.insert(package.name.clone(), package);
+        self.visiting.push(name.to_string());
+        let parent = format!("{} {}", package.name, package.version);
+        let optional = self
+            .features
+            .get(&package.name)
+            .into_iter()
+            .flatten()
+            .filter_map(|feature| package.features.as_ref()?.get(feature))
+            .flatten();
+        for dependency in package.dependencies.iter().flatten().chain(optional) {
+            let (dep_name, dep_constraint) = parse_dependency(dependency);
+            if self.visiting.iter().any(|n| n == dep_name) {
+                // Dependency cycles are allowed; the cycle is broken here
+                continue;
+            }
+            self.visit(dep_name, dep_constraint, Some(&parent))?;
+        }
+        self.visiting.pop();
+        self.order.push(candidate);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::PackageEntry;
+    use std::collections::BTreeMap;
+
+    fn package(name: &str, version: &str, deps: &[&str]) -> PackageIndex {
+        PackageIndex {
+            name: name.to_string(),
+            version: version.to_string(),
+            description: None,
+            url: format!("{}-{}.lpkg", name, version),
+            signature_url: None,
+            sha256: None,
+            size: None,
+            dependencies: Some(deps.iter().map(|d| d.to_string()).collect()),
+            conflicts: None,
+            arch: None,
+            libc: None,
+            min_kernel: None,
+            provides: None,
+            replaces: None,
+            features: None,
+        }
+    }
+
+    fn source(packages: Vec<PackageIndex>) -> Source {
+        let mut by_name: HashMap<String, Vec<PackageIndex>> = HashMap::new();
+        for package in packages {
+            by_name
+                .entry(package.name.clone())
+                .or_default()
+                .push(package);
+        }
+        Source {
+            repo: toml::from_str("name = \"test\"\nurl = \"file:///repo/index.json\"").unwrap(),
+            index: RepositoryIndex {
+                format: None,
+                packages: by_name
+                    .into_iter()
+                    .map(|(name, versions)| (name, PackageEntry::Multiple(versions)))
+                    .collect(),
+                mirrors: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_dependency() {
+        assert_eq!(
+            parse_dependency("libfoo >=1.0, <2.0"),
+            ("libfoo", Some(">=1.0, <2.0"))
+        );
+        assert_eq!(parse_dependency("libfoo"), ("libfoo", None));
+    }
+
+    #[test]
+    fn test_resolve_orders_dependencies_first() {
+        let sources = vec![source(vec![
+            package("app", "1.0.0", &["libfoo ^1.0"]),
+            package("libfoo", "1.2.0", &["libbar"]),
+            package("libfoo", "2.0.0", &[]),
+            package("libbar", "0.1.0", &[]),
+        ])];
+        let resolved = resolve(
+            &sources,
+            &["app".to_string()],
+            &Pins::new(),
+            &Features::new(),
+            &|_, _| Ok(false),
+        )
+        .unwrap();
+        let names: Vec<String> = resolved
+            .iter()
+            .map(|r| format!("{} {}", r.package.name, r.package.version))
+            .collect();
+        assert_eq!(names, ["libbar 0.1.0", "libfoo 1.2.0", "app 1.0.0"]);
+    }
+
+    #[test]
+    fn test_resolve_skips_satisfied_and_reports_conflicts() {
+        let sources = vec![source(vec![
+            package("app", "1.0.0", &["libfoo ^1.0", "tool"]),
+            package("tool", "1.0.0", &["libfoo ^2.0"]),
+            package("libfoo", "1.0.0", &[]),
+            package("libfoo", "2.0.0", &[]),
+        ])];
+        assert!(
+            resolve(
+                &sources,
+                &["app".to_string()],
+                &Pins::new(),
+                &Features::new(),
+                &|_, _| Ok(false)
+            )
+            .is_err()
+        );
+
+        let resolved = resolve(
+            &sources,
+            &["app".to_string()],
+            &Pins::new(),
+            &Features::new(),
+            &|name, _| Ok(name == "tool"),
+        )
+        .unwrap();
+        assert_eq!(resolved.len(), 2);
+    }
+
+    #[test]
+    fn test_resolve_respects_pins() {
+        let sources = vec![source(vec![
+            package("app", "1.0.0", &["libfoo >=1.0"]),
+            package("libfoo", "1.0.0", &[]),
+            package("libfoo", "1.5.0", &[]),
+            package("libfoo", "2.0.0", &[]),
+        ])];
+        let mut pins = Pins::new();
+        pins.insert(
+            "libfoo".to_string(),
+            Pin {
+                req: VersionReq::parse(">=1.1, <2").unwrap(),
+                reason: "pinned to >=1.1, <2".to_string(),
+            },
+        );
+        let resolved = resolve(
+            &sources,
+            &["app".to_string()],
+            &pins,
+            &Features::new(),
+            &|_, _| Ok(false),
+        )
+        .unwrap();
+        assert_eq!(resolved[0].package.version, "1.5.0");
+
+        let err = match resolve(
+            &sources,
+            &["libfoo =2.0.0".to_string()],
+            &pins,
+            &Features::new(),
+            &|_, _| Ok(false),
+        ) {
+            Ok(_) => panic!("a version outside the pin was selected"),
+            Err(e) => e,
+        };
+        assert!(err.to_string().contains("pinned to >=1.1, <2"));
+    }
+
+    #[test]
+    fn test_resolve_uses_providers() {
+        let mut curl = package("curl-lite", "1.0.0", &[]);
+        curl.provides = Some(vec!["http-client 2.0.0".to_string()]);
+        let mut renamed = package("newtool", "3.0.0", &[]);
+        renamed.replaces = Some(vec!["oldtool".to_string()]);
+        let sources = vec![source(vec![
+            package("app", "1.0.0", &["http-client ^2", "oldtool >=2"]),
+            curl,
+            renamed,
+        ])];
+        let resolved = resolve(
+            &sources,
+            &["app".to_string()],
+            &Pins::new(),
+            &Features::new(),
+            &|_, _| Ok(false),
+        )
+        .unwrap();
+        let names: Vec<&str> = resolved.iter().map(|r| r.package.name.as_str()).collect();
+        assert_eq!(names, ["curl-lite", "newtool", "app"]);
+
+        // A name provided without a version does not satisfy a versioned dependency
+        let mut wget = package("wget-lite", "1.0.0", &[]);
+        wget.provides = Some(vec!["http-client".to_string()]);
+        let unversioned = vec![source(vec![
+            package("app", "1.0.0", &["http-client ^2"]),
+            wget,
+        ])];
+        assert!(
+            resolve(
+                &unversioned,
+                &["app".to_string()],
+                &Pins::new(),
+                &Features::new(),
+                &|_, _| { Ok(false) }
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_resolve_selected_features() {
+        let mut player = package("player", "1.0.0", &["core ^1"]);
+        player.features = Some(BTreeMap::from([(
+            "gpu".to_string(),
+            vec!["vulkan ^1".to_string()],
+        )]));
+        let sources = vec![source(vec![
+            player,
+            package("core", "1.0.0", &[]),
+            package("vulkan", "1.1.0", &[]),
+        ])];
+        let resolve_with = |features: &Features| {
+            resolve(
+                &sources,
+                &["player".to_string()],
+                &Pins::new(),
+                features,
+                &|_, _| Ok(false),
+            )
+            .unwrap()
+            .iter()
+            .map(|r| r.package.name.clone())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(resolve_with(&Features::new()), ["core", "player"]);
+        let gpu = Features::from([("player".to_string(), vec!["gpu".to_string()])]);
+        assert_eq!(resolve_with(&gpu), ["core", "vulkan", "player"]);
+    }
+}
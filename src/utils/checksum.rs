@@ -31,7 +31,6 @@ pub fn calculate_sha256_from_reader<R: Read>(reader: &mut R) -> Result<String> {
     Ok(format!("{:x}", result))
 }
 
-#[allow(dead_code)]
 pub fn verify_checksum(file_path: &str, expected_checksum: &str) -> Result<bool> {
     let calculated =
         calculate_sha256(file_path).context("Failed to calculate checksum for verification")?;
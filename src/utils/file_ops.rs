@@ -26,7 +26,8 @@ pub fn copy_file(source: &str, destination: &str) -> Result<()> {
 
 pub fn remove_file(path: &str) -> Result<()> {
     let file_path = Path::new(path);
-    if file_path.exists() {
+    // Dangling symlinks are removed as well
+    if file_path.symlink_metadata().is_ok() {
         fs::remove_file(file_path).context(format!("Failed to remove file at {}", path))?;
     }
     Ok(())
@@ -47,7 +48,7 @@ pub fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
         if ty.is_dir() {
             copy_dir_all(&entry.path(), &dst.join(entry.file_name()))?;
         } else {
-            fs::copy(&entry.path(), &dst.join(entry.file_name()))?;
+            fs::copy(entry.path(), dst.join(entry.file_name()))?;
         }
     }
     Ok(())
@@ -1,3 +1,6 @@
 // Utility functions for file operations, checksums, etc.
 pub mod checksum;
 pub mod file_ops;
+pub mod format;
+pub mod platform;
+pub mod root;
@@ -0,0 +1,306 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+use std::process::Command;
+use std::sync::OnceLock;
+
+// Architecture names as used in package metadata. Common distribution
+// aliases (amd64, arm64, ...) are accepted and mapped to these.
+pub const ARCHITECTURES: &[&str] = &[
+    "any",
+    "x86_64",
+    "x86",
+    "aarch64",
+    "arm",
+    "riscv64",
+    "powerpc64",
+    "s390x",
+    "loongarch64",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibcKind {
+    Glibc,
+    Musl,
+}
+
+impl fmt::Display for LibcKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LibcKind::Glibc => "glibc",
+            LibcKind::Musl => "musl",
+        })
+    }
+}
+
+// A C library a package needs, written as "glibc", "musl" or with a minimum
+// version, e.g. "glibc >= 2.31"
+#[derive(Debug, Clone, PartialEq)]
+pub struct LibcRequirement {
+    pub kind: LibcKind,
+    pub min_version: Option<String>,
+}
+
+impl LibcRequirement {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (name, min_version) = match value.split_once(">=") {
+            Some((name, version)) => (name.trim(), Some(version.trim())),
+            None => (value.trim(), None),
+        };
+        let kind = match name {
+            "glibc" | "gnu" => LibcKind::Glibc,
+            "musl" => LibcKind::Musl,
+            _ => {
+                return Err(format!(
+                    "unknown C library '{}', expected 'glibc' or 'musl'",
+                    name
+                ));
+            }
+        };
+        if let Some(version) = min_version
+            && parse_version(version).is_none()
+        {
+            return Err(format!("'{}' is not a valid {} version", version, kind));
+        }
+        Ok(LibcRequirement {
+            kind,
+            min_version: min_version.map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for LibcRequirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.min_version {
+            Some(version) => write!(f, "{} >= {}", self.kind, version),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+// The system lpkg is running on
+#[derive(Debug, Clone)]
+pub struct Host {
+    pub arch: String,
+    pub libc: Option<(LibcKind, Option<String>)>,
+    pub kernel: Option<String>,
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.arch)?;
+        match &self.libc {
+            Some((kind, Some(version))) => write!(f, ", {} {}", kind, version)?,
+            Some((kind, None)) => write!(f, ", {}", kind)?,
+            None => {}
+        }
+        if let Some(kernel) = &self.kernel {
+            write!(f, ", Linux {}", kernel)?;
+        }
+        Ok(())
+    }
+}
+
+// The platform of this system, detected once
+pub fn host() -> &'static Host {
+    static HOST: OnceLock<Host> = OnceLock::new();
+    HOST.get_or_init(|| Host {
+        arch: std::env::consts::ARCH.to_string(),
+        libc: detect_libc(),
+        kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+            .ok()
+            .map(|release| release.trim().to_string()),
+    })
+}
+
+fn detect_libc() -> Option<(LibcKind, Option<String>)> {
+    // musl systems have a dynamic loader named after it, which prints its
+    // version when run without arguments
+    let musl_loader = fs::read_dir("/lib")
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .find(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with("ld-musl-"))
+        });
+    if let Some(loader) = musl_loader {
+        let version = Command::new(loader.path())
+            .output()
+            .ok()
+            .and_then(|output| {
+                String::from_utf8_lossy(&output.stderr)
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Version "))
+                    .map(|version| version.trim().to_string())
+            });
+        return Some((LibcKind::Musl, version));
+    }
+    let output = Command::new("getconf")
+        .arg("GNU_LIBC_VERSION")
+        .output()
+        .ok()?;
+    let version = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .strip_prefix("glibc ")
+        .map(str::to_string);
+    output
+        .status
+        .success()
+        .then_some((LibcKind::Glibc, version))
+}
+
+// Map an architecture name or alias to the name used in package metadata
+pub fn normalize_arch(arch: &str) -> Option<&'static str> {
+    let arch = match arch.trim() {
+        "noarch" | "all" => "any",
+        "amd64" | "x64" => "x86_64",
+        "i386" | "i586" | "i686" => "x86",
+        "arm64" => "aarch64",
+        "armhf" | "armv7" | "armv7l" => "arm",
+        "ppc64le" | "ppc64" => "powerpc64",
+        other => other,
+    };
+    ARCHITECTURES.iter().copied().find(|known| *known == arch)
+}
+
+// Check a package's platform requirements against this system. Returns why
+// the package cannot be used here, if it cannot.
+pub fn check(
+    arch: Option<&str>,
+    libc: Option<&str>,
+    min_kernel: Option<&str>,
+) -> Result<(), String> {
+    check_host(host(), arch, libc, min_kernel)
+}
+
+pub fn is_supported(arch: Option<&str>, libc: Option<&str>, min_kernel: Option<&str>) -> bool {
+    check(arch, libc, min_kernel).is_ok()
+}
+
+fn check_host(
+    host: &Host,
+    arch: Option<&str>,
+    libc: Option<&str>,
+    min_kernel: Option<&str>,
+) -> Result<(), String> {
+    if let Some(arch) = arch {
+        match normalize_arch(arch) {
+            Some("any") => {}
+            Some(arch) if arch == host.arch => {}
+            Some(arch) => {
+                return Err(format!(
+                    "it is built for {}, but this system is {}",
+                    arch, host.arch
+                ));
+            }
+            None => {
+                return Err(format!(
+                    "it is built for an unknown architecture '{}'",
+                    arch
+                ));
+            }
+        }
+    }
+
+    if let Some(libc) = libc {
+        let required = LibcRequirement::parse(libc)?;
+        let Some((kind, version)) = &host.libc else {
+            return Err(format!(
+                "it requires {}, but the C library of this system could not be determined",
+                required
+            ));
+        };
+        if *kind != required.kind {
+            return Err(format!(
+                "it requires {}, but this system uses {}",
+                required, kind
+            ));
+        }
+        if let Some(min_version) = &required.min_version {
+            let new_enough = version
+                .as_deref()
+                .is_some_and(|version| compare_versions(version, min_version) != Ordering::Less);
+            if !new_enough {
+                return Err(format!(
+                    "it requires {}, but this system has {} {}",
+                    required,
+                    kind,
+                    version.as_deref().unwrap_or("of an unknown version")
+                ));
+            }
+        }
+    }
+
+    if let Some(min_kernel) = min_kernel {
+        if parse_version(min_kernel).is_none() {
+            return Err(format!("'{}' is not a valid kernel version", min_kernel));
+        }
+        let new_enough = host
+            .kernel
+            .as_deref()
+            .is_some_and(|kernel| compare_versions(kernel, min_kernel) != Ordering::Less);
+        if !new_enough {
+            return Err(format!(
+                "it requires Linux {} or newer, but this system runs {}",
+                min_kernel,
+                host.kernel.as_deref().unwrap_or("an unknown version")
+            ));
+        }
+    }
+    Ok(())
+}
+
+// The leading numeric components of a version such as "2.31" or
+// "6.1.0-13-amd64"
+pub fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let numeric = version
+        .trim()
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()?;
+    let parts: Vec<u64> = numeric
+        .split('.')
+        .map_while(|part| part.parse().ok())
+        .collect();
+    (!parts.is_empty()).then_some(parts)
+}
+
+// Compare dotted versions numerically, treating missing components as zero
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a, b) = (
+        parse_version(a).unwrap_or_default(),
+        parse_version(b).unwrap_or_default(),
+    );
+    for i in 0..a.len().max(b.len()) {
+        let ordering = a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_host() {
+        let host = Host {
+            arch: "x86_64".to_string(),
+            libc: Some((LibcKind::Glibc, Some("2.36".to_string()))),
+            kernel: Some("6.1.0-13-amd64".to_string()),
+        };
+        let check = |arch, libc, kernel| check_host(&host, arch, libc, kernel);
+        assert!(check(None, None, None).is_ok());
+        assert!(check(Some("amd64"), Some("glibc >= 2.31"), Some("5.10")).is_ok());
+        assert!(check(Some("any"), Some("glibc>=2.36"), Some("6.1")).is_ok());
+        assert!(check(Some("aarch64"), None, None).is_err());
+        assert!(check(None, Some("musl"), None).is_err());
+        assert!(check(None, Some("glibc >= 2.38"), None).is_err());
+        assert!(check(None, None, Some("6.2")).is_err());
+        assert!(check(None, Some("uclibc"), None).is_err());
+    }
+}
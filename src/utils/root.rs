@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// The directory the managed system lives in, when it is not the running one.
+// Paths recorded in the database are always those of the managed system.
+// The package cache belongs to the managed system too: it is kept under the
+// root like the database and recorded by its own path there, so rollbacks,
+// generations and undo keep working once the system runs on its own.
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+// Operate on the system installed under `dir`, e.g. an image being built
+pub fn set(dir: &str) -> Result<()> {
+    let root = Path::new(dir)
+        .canonicalize()
+        .context(format!("Root directory {} does not exist", dir))?;
+    if !root.is_dir() {
+        return Err(anyhow::anyhow!("Root {} is not a directory", dir));
+    }
+    ROOT.set(root)
+        .map_err(|_| anyhow::anyhow!("The root directory is already set"))
+}
+
+// Whether lpkg manages another system than the running one. Only files are
+// placed then: scripts, services and desktop caches belong to the running
+// system.
+pub fn is_alternate() -> bool {
+    ROOT.get().is_some_and(|root| root != Path::new("/"))
+}
+
+// Where a path of the managed system is on the running one
+pub fn path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    match ROOT.get() {
+        Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+        None => path.to_path_buf(),
+    }
+}
+
+// Tests install into one shared root under the temporary directory, as the
+// root can only be set once. It is emptied when the first test sets it.
+#[cfg(test)]
+pub fn set_for_tests() {
+    ROOT.get_or_init(|| {
+        let dir = std::env::temp_dir().join("lpkg-tests");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    });
+}